@@ -0,0 +1,30 @@
+use crayfish::at_each;
+use crayfish::finish;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::PlaceGroup;
+
+extern crate crayfish;
+
+#[crayfish::activity(at_each)]
+async fn partial_sum(data: Vec<u64>) -> u64 {
+    // each place sums a stride of the same data
+    let world = world_size();
+    let me = here() as usize;
+    data.iter().skip(me).step_by(world).sum()
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        let data: Vec<u64> = (0..1 << 20).collect();
+        let sums = finish! {
+            // data is serialized once instead of once per place
+            at_each!(PlaceGroup::new(world_size()), partial_sum(data)).await
+        };
+        for (place, sum) in sums.iter().enumerate() {
+            println!("place {}: {}", place, sum);
+        }
+        println!("total: {}", sums.iter().sum::<u64>());
+    }
+}
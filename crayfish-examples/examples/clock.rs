@@ -0,0 +1,33 @@
+use crayfish::clock::Clock;
+use crayfish::ff;
+use crayfish::finish;
+use crayfish::logging::*;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+
+extern crate crayfish;
+
+#[crayfish::activity]
+async fn step(mut clock: Clock, id: usize) {
+    for _ in 0..3 {
+        info!("activity {} at place {} in phase {}", id, here(), clock.phase());
+        // no activity enters the next phase until all of them are done with this one
+        clock.advance().await;
+    }
+}
+
+#[crayfish::main]
+async fn main() {
+    finish! {
+        if here() == 0 {
+            let clock = Clock::new();
+            for i in 0..world_size() {
+                // the clone registers the spawned activity
+                ff!(i as Place, step(clock.clone(), i));
+            }
+            // main does not take part in the phases
+            drop(clock);
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use crayfish::collecting::Reducer;
+use crayfish::collecting::Sum;
+use crayfish::ff;
+use crayfish::finish_collect;
+use crayfish::offer;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+
+extern crate crayfish;
+
+const BINS: usize = 8;
+
+#[crayfish::arg]
+#[derive(Debug, Default)]
+struct Histogram(Vec<usize>);
+
+impl Reducer for Histogram {
+    fn reduce(&mut self, other: Self) {
+        if self.0.len() < other.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        for (a, b) in self.0.iter_mut().zip(other.0) {
+            *a += b;
+        }
+    }
+}
+
+#[crayfish::activity]
+async fn count_sum(n: usize) {
+    for i in 0..n {
+        // reduced at this place, sent with the return of the activity
+        offer!(Sum(i));
+    }
+}
+
+#[crayfish::activity]
+async fn count_histogram(n: usize) {
+    let mut histogram = vec![0; BINS];
+    for i in 0..n {
+        histogram[(i * (here() as usize + 1)) % BINS] += 1;
+    }
+    offer!(Histogram(histogram));
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        let Sum(total) = finish_collect!(Sum<usize>, {
+            for p in 0..world_size() {
+                ff!(p as Place, count_sum(100));
+            }
+        });
+        println!("sum: {}", total);
+
+        let Histogram(bins) = finish_collect!(Histogram, {
+            for p in 0..world_size() {
+                ff!(p as Place, count_histogram(100));
+            }
+        });
+        println!("histogram: {:?}", bins);
+    }
+}
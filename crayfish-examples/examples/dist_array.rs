@@ -0,0 +1,36 @@
+use crayfish::at_each;
+use crayfish::collective;
+use crayfish::dist_array::BlockCyclic;
+use crayfish::dist_array::DistArray;
+use crayfish::dist_array::DistArrayRef;
+use crayfish::finish;
+use crayfish::place::here;
+
+extern crate crayfish;
+
+const LEN: usize = 1000;
+
+#[crayfish::activity(at_each)]
+async fn square(array: DistArrayRef<u64, BlockCyclic>) -> u64 {
+    // only elements of this place are touched
+    array.for_each_local(|_, v| *v *= *v);
+    array.with_local(|values| values.iter().sum())
+}
+
+#[crayfish::main]
+async fn main() {
+    // created at every place in the same order
+    let array = DistArray::new(LEN, BlockCyclic(16), |i| i as u64).await;
+    if here() == 0 {
+        let sums = finish! {
+            at_each!(array.places(), square(array.global_ref())).await
+        };
+        println!("partial sums by place: {:?}", sums);
+        let last = array.get(LEN - 1).await;
+        println!("element {} at place {} is {}", LEN - 1, array.place_of(LEN - 1), last);
+        array.put(0, 42).await;
+        println!("element 0 is {}", array.get(0).await);
+    }
+    // keep the segment alive until all places are done
+    collective::barrier().await;
+}
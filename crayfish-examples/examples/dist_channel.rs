@@ -0,0 +1,40 @@
+use crayfish::collective;
+use crayfish::dist_channel;
+use crayfish::dist_channel::Sender;
+use crayfish::ff;
+use crayfish::finish;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+
+extern crate crayfish;
+
+const ITEMS: usize = 10000;
+
+#[crayfish::activity]
+async fn produce(results: Sender<(Place, usize)>) {
+    for i in 0..ITEMS {
+        results.send((here(), i));
+    }
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        let (tx, mut rx) = dist_channel::channel::<(Place, usize)>();
+        finish! {
+        for place in 0..world_size() {
+            ff!(place as Place, produce(tx.clone()));
+        }
+        }
+        // items of one producer come in the order they are sent
+        let mut next = vec![0; world_size()];
+        for _ in 0..ITEMS * world_size() {
+            let (place, i) = rx.recv().await;
+            assert_eq!(next[place as usize], i);
+            next[place as usize] += 1;
+        }
+        println!("received {} items", ITEMS * world_size());
+    }
+    collective::barrier().await;
+}
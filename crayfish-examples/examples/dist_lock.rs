@@ -0,0 +1,38 @@
+use crayfish::collective;
+use crayfish::dist_lock::DistMutex;
+use crayfish::dist_lock::DistMutexRef;
+use crayfish::dist_lock::DistSemaphore;
+use crayfish::dist_lock::DistSemaphoreRef;
+use crayfish::ff;
+use crayfish::finish;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+use std::time::Duration;
+
+extern crate crayfish;
+
+// at most two places use the licensed tool at a time, and the report is written by one at a time
+#[crayfish::activity]
+async fn run_tool(licenses: DistSemaphoreRef, report: DistMutexRef) {
+    let license = licenses.acquire(1).await;
+    std::thread::sleep(Duration::from_millis(100)); // the tool
+    drop(license);
+
+    let _report = report.lock().await;
+    println!("place {} done", here());
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        let licenses = DistSemaphore::new(2);
+        let report = DistMutex::new();
+        finish! {
+        for place in 0..world_size() {
+            ff!(place as Place, run_tool(licenses.global_ref(), report.global_ref()));
+        }
+        }
+    }
+    collective::barrier().await;
+}
@@ -0,0 +1,35 @@
+use crayfish::collective;
+use crayfish::global_atomic::GlobalAtomicU64;
+use crayfish::place::here;
+
+extern crate crayfish;
+
+const TASKS: u64 = 1000;
+
+fn work(task: u64) -> u64 {
+    (0..task).fold(0, |acc, i| acc ^ (i * i))
+}
+
+#[crayfish::main]
+async fn main() {
+    // a shared work queue: each place takes the next task from the counter at place 0
+    let next = GlobalAtomicU64::new(0, 0).await;
+    let done = GlobalAtomicU64::new(0, 0).await;
+    let mut mine = 0;
+    loop {
+        let task = next.fetch_add(1).await;
+        if task >= TASKS {
+            break;
+        }
+        work(task);
+        mine += 1;
+    }
+    done.fetch_add(mine).await;
+    println!("place {} did {} tasks", here(), mine);
+    collective::barrier().await;
+    if here() == 0 {
+        assert_eq!(done.load().await, TASKS);
+    }
+    next.free().await;
+    done.free().await;
+}
@@ -0,0 +1,43 @@
+use crayfish::collective;
+use crayfish::ff;
+use crayfish::finish;
+use crayfish::global_future::global_promise;
+use crayfish::global_future::GlobalFuture;
+use crayfish::global_future::GlobalPromise;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+
+extern crate crayfish;
+
+// one stage of a pipeline: waits for the value of the previous stage, wherever it was made
+#[crayfish::activity]
+async fn stage(input: GlobalFuture<Vec<u64>>, output: GlobalPromise<Vec<u64>>) {
+    let mut values = input.get().await;
+    for v in values.iter_mut() {
+        *v = *v * 2 + here() as u64;
+    }
+    output.set(values);
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        let (first, mut input) = global_promise::<Vec<u64>>();
+        let mut stages = vec![];
+        for _ in 0..world_size() {
+            let (output, next) = global_promise::<Vec<u64>>();
+            stages.push((input, output));
+            input = next;
+        }
+        finish! {
+        // stages are spawned before any value is there
+        for (place, (input, output)) in stages.into_iter().enumerate() {
+            ff!(place as Place, stage(input, output));
+        }
+        first.set((0..8).collect());
+        }
+        println!("pipeline result: {:?}", input.get().await);
+    }
+    collective::barrier().await;
+}
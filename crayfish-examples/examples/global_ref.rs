@@ -0,0 +1,38 @@
+use crayfish::at_home;
+use crayfish::ff;
+use crayfish::finish;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+use crayfish::shared::GlobalRef;
+use crayfish::shared::PlaceLocal;
+use std::sync::Mutex;
+
+extern crate crayfish;
+
+#[crayfish::activity]
+async fn visit(visitors: GlobalRef<Mutex<Vec<Place>>>) {
+    let me = here();
+    // the closure runs at the home of visitors, so it only gets arguments from the reference
+    let count = at_home!(visitors, |v: &Mutex<Vec<Place>>| -> usize {
+        let mut v = v.lock().unwrap();
+        let count = v.len() + 1;
+        v.push(count as Place);
+        count
+    })
+    .await;
+    println!("place {} is visitor {}", me, count);
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        let visitors = PlaceLocal::new(Mutex::new(vec![]));
+        finish! {
+            for p in 0..world_size() {
+                ff!(p as Place, visit(visitors.global_ref()));
+            }
+        }
+        println!("{} visitors", visitors.lock().unwrap().len());
+    }
+}
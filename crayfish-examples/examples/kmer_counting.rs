@@ -4,16 +4,16 @@ use crayfish::place::world_size;
 use crayfish::inventory;
 use crayfish::logging::*;
 use crayfish::place::Place;
-use crayfish::shared::PlaceLocal;
-use crayfish::shared::PlaceLocalWeak;
-use std::collections::hash_map::DefaultHasher;
+use crayfish::accumulator::Accumulator;
+use crayfish::accumulator::AccumulatorRef;
+use crayfish::accumulator::ReduceOp;
+use crayfish::collecting::Reducer;
+use crayfish::dist_hash_map::DistHashMap;
+use crayfish::dist_hash_map::DistHashMapRef;
 use std::collections::HashMap;
 use std::fs::File;
-use std::hash::Hash;
-use std::hash::Hasher;
 use std::io::BufRead;
 use std::io::BufReader;
-use std::sync::Mutex;
 use crayfish::finish;
 use crayfish::ff;
 
@@ -32,13 +32,34 @@ const BASE_C: u8 = b'C';
 const BASE_G: u8 = b'G';
 
 #[crayfish::arg]
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
 struct KMerData {
     count: CountNumber,
 }
 
+impl Reducer for KMerData {
+    fn reduce(&mut self, other: Self) {
+        self.count += other.count;
+    }
+}
+
 type CountTable = HashMap<KMer, KMerData>;
 
+#[crayfish::arg]
+#[derive(Debug, Clone, Default)]
+struct Histogram(Vec<usize>);
+
+impl Reducer for Histogram {
+    fn reduce(&mut self, other: Self) {
+        if other.0.len() > self.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        for (i, n) in other.0.into_iter().enumerate() {
+            self.0[i] += n;
+        }
+    }
+}
+
 fn get_complement_base(base: u8) -> u8 {
     match base {
         BASE_A => BASE_T,
@@ -82,21 +103,8 @@ fn update_count_table(count_table: &mut CountTable, kmer: KMer, kmerdata: KMerDa
     };
 }
 
-fn get_partition(kmer: &KMer) -> Place {
-    let mut hasher = DefaultHasher::new();
-    kmer.hash(&mut hasher);
-    (hasher.finish() % world_size() as u64) as Place
-}
-
-#[crayfish::activity]
-async fn update_kmer(kmer: KMer, data: KMerData, final_ptr: PlaceLocalWeak<Mutex<CountTable>>) {
-    let ptr = final_ptr.upgrade().unwrap();
-    let mut h = ptr.lock().unwrap();
-    update_count_table(&mut h, kmer, data);
-}
-
 #[crayfish::activity]
-async fn kmer_counting(reads: Reads, final_ptr: PlaceLocalWeak<Mutex<CountTable>>) {
+async fn kmer_counting(reads: Reads, table: DistHashMapRef<KMer, KMerData>) {
     let mut count_table = CountTable::new();
 
     for read in reads {
@@ -122,16 +130,30 @@ async fn kmer_counting(reads: Reads, final_ptr: PlaceLocalWeak<Mutex<CountTable>
         }
     }
 
-    for (k, n) in count_table {
-        crayfish::ff!(get_partition(&k), update_kmer(k, n, final_ptr.clone()));
-    }
+    // merged at the owner places of kmers, one request per owner
+    table.update_all(count_table).await;
+}
+
+#[crayfish::activity]
+async fn local_histogram(
+    table: DistHashMapRef<KMer, KMerData>,
+    hist: AccumulatorRef<Histogram, ReduceOp>,
+) {
+    let mut local = Histogram(vec![0usize; 2048]);
+    table.for_each_local(|_, data| {
+        let count = data.count as usize;
+        if count >= local.0.len() {
+            local.0.resize(count + 1, 0);
+        }
+        local.0[count] += 1;
+    });
+    hist.add(local);
 }
 
 // desugered finish
 #[crayfish::main]
 async fn inner_main() {
-    let count_table_ptr = PlaceLocal::new(Mutex::new(CountTable::new()));
-    collective::barrier().await;
+    let table = DistHashMap::<KMer, KMerData>::new().await;
     if here() == 0 {
         // ctx contains a new finish id now
         let chunk_size = 4096;
@@ -159,7 +181,7 @@ async fn inner_main() {
                     );
                     let mut new_read = vec![];
                     std::mem::swap(&mut new_read, &mut buffer);
-                    ff!(next_place, kmer_counting(new_read, count_table_ptr.downgrade()));
+                    ff!(next_place, kmer_counting(new_read, table.global_ref()));
                     next_place = (next_place + 1) % (world_size as Place);
                 }
                 buffer.push(s.into_bytes());
@@ -169,21 +191,22 @@ async fn inner_main() {
     }
     collective::barrier().await;
 
-    let global_table = count_table_ptr.lock().unwrap();
-
-    let p = global_table.iter().take(100).collect::<Vec<_>>();
-    for (kmer, data) in p {
-        println!("{}: {}", String::from_utf8_lossy(&kmer[..]), data.count);
-    }
+    table.with_local(|local_table| {
+        let p = local_table.iter().take(100).collect::<Vec<_>>();
+        for (kmer, data) in p {
+            println!("{}: {}", String::from_utf8_lossy(&kmer[..]), data.count);
+        }
+    });
 
-    let mut hist = vec![0usize; 2048];
-    for (_, data) in global_table.iter() {
-        if data.count as usize > hist.len() {
-            for _ in 0..data.count {
-                hist.push(0);
-            }
+    if here() == 0 {
+        // each place adds the histogram of its kmers
+        let hist = Accumulator::<Histogram, ReduceOp>::new(Histogram::default());
+        finish! {
+        for place in 0..world_size() as Place {
+            ff!(place, local_histogram(table.global_ref(), hist.global_ref()));
+        }
         }
-        hist[data.count as usize] += 1;
+        println!("{:?}", hist.result().await.0);
     }
-    println!("{:?}", hist);
+    collective::barrier().await;
 }
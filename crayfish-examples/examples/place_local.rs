@@ -20,8 +20,8 @@ async fn change_local(local_num: shared::PlaceLocalWeak<Mutex<usize>>) {
 
 #[crayfish::main]
 async fn main() {
-    let local_num = shared::PlaceLocal::new(Mutex::new(0usize));
-    collective::barrier().await;
+    // the same handle at every place, ready everywhere once it returns
+    let local_num = shared::PlaceLocal::new_everywhere(|| Mutex::new(0usize)).await;
     finish! {
         if here() == 0 {
             for i in 0..world_size() {
@@ -32,4 +32,6 @@ async fn main() {
     collective::barrier().await;
 
     info!("now my local value is {}", local_num.lock().unwrap());
+    // removed everywhere once no activity uses it
+    local_num.destroy().await;
 }
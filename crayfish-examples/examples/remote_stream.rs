@@ -0,0 +1,46 @@
+use crayfish::at_stream;
+use crayfish::collective;
+use crayfish::finish;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+use futures::Stream;
+use futures::StreamExt;
+
+extern crate crayfish;
+extern crate futures;
+
+const PAGE: usize = 100;
+
+// a paginated scan: yields the pages of a table at its place, one at a time
+#[crayfish::activity]
+fn scan(pages: usize) -> impl Stream<Item = Vec<u64>> {
+    let base = here() as u64 * 1_000_000;
+    futures::stream::iter(0..pages).map(move |p| {
+        let start = base + (p * PAGE) as u64;
+        (start..start + PAGE as u64).collect()
+    })
+}
+
+#[crayfish::main]
+async fn main() {
+    if here() == 0 {
+        finish! {
+        for place in 0..world_size() {
+            let mut pages = at_stream!(place as Place, scan(50));
+            let mut sum = 0;
+            while let Some(page) = pages.next().await {
+                sum += page.iter().sum::<u64>();
+            }
+            println!("sum of place {}: {}", place, sum);
+        }
+        // only the first pages are needed, the rest of the scan is cancelled
+        let first = at_stream!(world_size() as Place - 1, scan(1 << 20))
+            .take(3)
+            .collect::<Vec<_>>()
+            .await;
+        println!("took {} pages", first.len());
+        }
+    }
+    collective::barrier().await;
+}
@@ -0,0 +1,38 @@
+use crayfish::collective;
+use crayfish::place::here;
+use crayfish::place::world_size;
+use crayfish::place::Place;
+use crayfish::rma::Strided;
+use crayfish::rma::SymmetricBuffer;
+
+extern crate crayfish;
+
+const LEN: usize = 1 << 16;
+
+#[crayfish::main]
+async fn main() {
+    let world = world_size();
+    let me = here() as usize;
+    let next = ((me + 1) % world) as Place;
+
+    // each place writes its block into the buffer of the next place
+    let buffer = SymmetricBuffer::new(LEN, 0u64).await;
+    let block: Vec<u64> = (0..LEN as u64).map(|i| i * world as u64 + me as u64).collect();
+    buffer.put_bulk(next, 0, &block[..]).await;
+    collective::barrier().await;
+
+    // read every 1024th element of the block written by the previous place
+    let sampled = buffer
+        .get_strided(
+            here(),
+            Strided {
+                start: 0,
+                block: 1,
+                stride: 1024,
+                count: LEN / 1024,
+            },
+        )
+        .await;
+    println!("place {} got {:?}", me, &sampled[..4]);
+    buffer.free().await;
+}
@@ -4,6 +4,7 @@ use quote::quote_spanned;
 use quote::ToTokens;
 use syn::AttributeArgs;
 use syn::Error;
+use syn::Ident;
 use syn::Meta;
 use syn::NestedMeta;
 use syn::Path;
@@ -13,6 +14,8 @@ use syn::Type;
 pub struct Attributes {
     pub crayfish_path: Option<Path>,
     pub ret_type: Option<Type>,
+    pub finish_mode: Option<Ident>,
+    pub at_each: bool, // generate the relay activity of at_each!
 }
 
 const BAD_ATTR: &str = "bad attribute";
@@ -32,10 +35,33 @@ fn str_literal_parse<T: syn::parse::Parse>(lit: &syn::Lit) -> syn::Result<T> {
     }
 }
 
+fn finish_mode_parse(lit: &syn::Lit) -> syn::Result<Ident> {
+    if let syn::Lit::Str(l) = lit {
+        let mode = match l.value().as_str() {
+            "default" => "Default",
+            "local" => "Local",
+            "spmd" => "Spmd",
+            "here" => "Here",
+            "resilient" => "Resilient",
+            _ => {
+                return Err(Error::new_spanned(
+                    lit,
+                    "finish mode must be one of \"default\", \"local\", \"spmd\", \"here\" and \"resilient\"",
+                ))
+            }
+        };
+        Ok(Ident::new(mode, l.span()))
+    } else {
+        Err(Error::new_spanned(lit, BAD_ATTR))
+    }
+}
+
 impl Attributes {
     pub fn new(args: AttributeArgs) -> syn::Result<Self> {
         let mut crayfish_path: Option<Path> = None;
         let mut ret_type: Option<Type> = None;
+        let mut finish_mode: Option<Ident> = None;
+        let mut at_each = false;
 
         for arg in args {
             match arg {
@@ -48,9 +74,15 @@ impl Attributes {
                         "ret" => {
                             ret_type = Some(str_literal_parse::<Type>(&nv.lit)?);
                         }
+                        "mode" => {
+                            finish_mode = Some(finish_mode_parse(&nv.lit)?);
+                        }
                         _ => bad_attr(nv.path)?,
                     }
                 }
+                NestedMeta::Meta(Meta::Path(path)) if path.is_ident("at_each") => {
+                    at_each = true;
+                }
                 _ => bad_attr(arg)?,
             }
         }
@@ -58,6 +90,8 @@ impl Attributes {
         Ok(Attributes {
             crayfish_path,
             ret_type,
+            finish_mode,
+            at_each,
         })
     }
 
@@ -0,0 +1,274 @@
+use crate::func::parse_mode_arg;
+use proc_macro2::Span;
+use proc_macro2::TokenStream;
+use proc_macro2::TokenTree;
+use quote::quote;
+use quote::quote_spanned;
+use syn::parse::ParseStream;
+use syn::parse::Parser;
+use syn::parse_quote;
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::visit_mut;
+use syn::visit_mut::VisitMut;
+use syn::Block;
+use syn::Expr;
+use syn::ExprBlock;
+use syn::ExprForLoop;
+use syn::ExprLoop;
+use syn::ExprWhile;
+use syn::Item;
+use syn::Lifetime;
+use syn::MetaNameValue;
+use syn::Result;
+use syn::Stmt;
+use syn::Token;
+use syn::Type;
+
+// A finish block runs in an async block so that the finish can still wait for spawned
+// activities on an early exit. `return x` in the finish block becomes `return Break(x)` of the
+// async block, and `e?` returns the residual converted to the return type of the function.
+// Closures, async blocks and items have their own `return` and `?`, so they are left alone.
+// `break` and `continue` cannot leave the async block, so the ones targeting a loop outside the
+// finish block are rejected.
+struct EarlyExit<'a> {
+    crayfish_path: &'a TokenStream,
+    targets: Vec<Target>, // loops and labeled blocks in the finish block, innermost last
+    error: Option<syn::Error>,
+    exits: usize, // rewritten `return` and `?`
+}
+
+#[derive(Clone)]
+struct Target {
+    label: Option<String>,
+    is_loop: bool,
+}
+
+// crayfish macros taking expressions, which are expanded inside the async block as well. Other
+// macros are opaque: their tokens are not rewritten, so a `return` or `?` in them is rejected
+// instead of failing later with a type error about the async block.
+const EXPR_MACROS: &[&str] = &["at", "try_at", "ff", "at_each", "at_stream", "offer"];
+
+type FinishTokens<T> = (Option<MetaNameValue>, T, Vec<Stmt>);
+
+fn parse_finish(input: ParseStream) -> Result<FinishTokens<()>> {
+    Ok((parse_mode_arg(input)?, (), Block::parse_within(input)?))
+}
+
+fn parse_collect(input: ParseStream) -> Result<FinishTokens<Type>> {
+    let mode = parse_mode_arg(input)?;
+    let reducer = input.parse::<Type>()?;
+    input.parse::<Token![,]>()?;
+    Ok((mode, reducer, Block::parse_within(input)?))
+}
+
+impl<'a> EarlyExit<'a> {
+    fn visit_stmts(&mut self, stmts: &mut [Stmt]) {
+        for stmt in stmts.iter_mut() {
+            self.visit_stmt_mut(stmt);
+        }
+    }
+
+    // a nested finish block is an async block of its own
+    fn visit_finish_block(&mut self, stmts: &mut [Stmt]) {
+        let targets = std::mem::take(&mut self.targets);
+        self.visit_stmts(stmts);
+        self.targets = targets;
+    }
+
+    fn visit_target(&mut self, label: Option<String>, is_loop: bool, expr: &mut Expr) {
+        self.targets.push(Target { label, is_loop });
+        visit_mut::visit_expr_mut(self, expr);
+        self.targets.pop();
+    }
+
+    fn add_error(&mut self, error: syn::Error) {
+        match self.error.as_mut() {
+            Some(e) => e.combine(error),
+            None => self.error = Some(error),
+        }
+    }
+
+    fn check_target(&mut self, keyword: &str, span: Span, label: Option<&Lifetime>) {
+        let found = match label {
+            Some(label) => {
+                let label = label.ident.to_string();
+                self.targets
+                    .iter()
+                    .any(|t| t.label.as_ref() == Some(&label) && (t.is_loop || keyword == "break"))
+            }
+            None => self.targets.iter().any(|t| t.is_loop),
+        };
+        if !found {
+            let message = format!(
+                "`{}` cannot leave a finish block, which must wait for its activities",
+                keyword
+            );
+            self.add_error(syn::Error::new(span, message));
+        }
+    }
+
+    // the early exits of an opaque macro, found like those of the finish block if its arguments
+    // are expressions, e.g. println! and assert!, or else by any `return` or `?` token
+    fn check_opaque(&mut self, mac: &syn::Macro, name: &str) {
+        if name == "macro_rules" {
+            return;
+        }
+        let exits = match Punctuated::<Expr, Token![,]>::parse_terminated.parse2(mac.tokens.clone())
+        {
+            Ok(exprs) => {
+                let mut finder = EarlyExit {
+                    crayfish_path: self.crayfish_path,
+                    targets: self.targets.clone(),
+                    error: None,
+                    exits: 0,
+                };
+                for mut expr in exprs {
+                    finder.visit_expr_mut(&mut expr);
+                }
+                if let Some(error) = finder.error {
+                    self.add_error(error);
+                }
+                finder.exits > 0
+            }
+            Err(_) => has_exit_token(mac.tokens.clone()),
+        };
+        if exits {
+            let message = format!(
+                "`return` or `?` in `{}!` cannot be rewritten to wait for the activities of the \
+                 finish block, move it out of the macro",
+                name
+            );
+            self.add_error(syn::Error::new(mac.path.span(), message));
+        }
+    }
+
+    // macros nested in a finish block are expanded inside the async block as well
+    fn visit_macro_tokens(&mut self, mac: &mut syn::Macro) {
+        let name = mac.path.segments.last().map(|s| s.ident.to_string());
+        let tokens = mac.tokens.clone();
+        mac.tokens = match name.as_deref() {
+            Some("finish") | Some("finish_async") | Some("try_finish") => {
+                match parse_finish.parse2(tokens) {
+                    Ok((mode, (), mut stmts)) => {
+                        self.visit_finish_block(&mut stmts);
+                        let mode = mode.map(|mode| quote!(#mode,));
+                        quote!(#mode #(#stmts)*)
+                    }
+                    Err(_) => return,
+                }
+            }
+            Some("finish_collect") => match parse_collect.parse2(tokens) {
+                Ok((mode, reducer, mut stmts)) => {
+                    self.visit_finish_block(&mut stmts);
+                    let mode = mode.map(|mode| quote!(#mode,));
+                    quote!(#mode #reducer, #(#stmts)*)
+                }
+                Err(_) => return,
+            },
+            Some(name) if EXPR_MACROS.contains(&name) => {
+                match Punctuated::<Expr, Token![,]>::parse_terminated.parse2(tokens) {
+                    Ok(mut exprs) => {
+                        for expr in exprs.iter_mut() {
+                            self.visit_expr_mut(expr);
+                        }
+                        quote!(#exprs)
+                    }
+                    Err(_) => return,
+                }
+            }
+            Some(name) => return self.check_opaque(mac, name),
+            None => return,
+        };
+    }
+}
+
+impl<'a> VisitMut for EarlyExit<'a> {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Return(ret) => {
+                let value = match ret.expr.as_mut() {
+                    Some(value) => {
+                        self.visit_expr_mut(value);
+                        quote!(#value)
+                    }
+                    None => quote!(()),
+                };
+                *expr = parse_quote!(return ::std::ops::ControlFlow::Break(#value));
+                self.exits += 1;
+            }
+            Expr::Try(try_expr) => {
+                self.visit_expr_mut(&mut try_expr.expr);
+                let path = self.crayfish_path;
+                let value = &try_expr.expr;
+                // report a bad conversion at the `?`
+                let from_residual = quote_spanned!(try_expr.question_token.span=>
+                    #path::runtime::FromFinishResidual::from_residual
+                );
+                *expr = parse_quote!(
+                    match #path::runtime::FinishTry::branch(#value) {
+                        ::std::ops::ControlFlow::Continue(__crayfish_output) => __crayfish_output,
+                        ::std::ops::ControlFlow::Break(__crayfish_residual) => {
+                            return ::std::ops::ControlFlow::Break(#from_residual(
+                                __crayfish_residual,
+                            ))
+                        }
+                    }
+                );
+                self.exits += 1;
+            }
+            Expr::ForLoop(ExprForLoop { label, .. })
+            | Expr::While(ExprWhile { label, .. })
+            | Expr::Loop(ExprLoop { label, .. }) => {
+                let label = label.as_ref().map(|l| l.name.ident.to_string());
+                self.visit_target(label, true, expr);
+            }
+            Expr::Block(ExprBlock { label, .. }) if label.is_some() => {
+                let label = label.as_ref().map(|l| l.name.ident.to_string());
+                self.visit_target(label, false, expr);
+            }
+            Expr::Break(b) => {
+                self.check_target("break", b.break_token.span, b.label.as_ref());
+                visit_mut::visit_expr_mut(self, expr);
+            }
+            Expr::Continue(c) => {
+                self.check_target("continue", c.continue_token.span, c.label.as_ref());
+            }
+            Expr::Closure(_) | Expr::Async(_) => (),
+            Expr::Macro(expr_macro) => self.visit_macro_tokens(&mut expr_macro.mac),
+            _ => visit_mut::visit_expr_mut(self, expr),
+        }
+    }
+
+    fn visit_item_mut(&mut self, item: &mut Item) {
+        // statement macros are parsed as items
+        if let Item::Macro(item_macro) = item {
+            self.visit_macro_tokens(&mut item_macro.mac);
+        }
+    }
+}
+
+fn has_exit_token(tokens: TokenStream) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => ident == "return",
+        TokenTree::Punct(punct) => punct.as_char() == '?',
+        TokenTree::Group(group) => has_exit_token(group.stream()),
+        TokenTree::Literal(_) => false,
+    })
+}
+
+/// rewrite early exits of a finish block, see the comment of EarlyExit
+pub fn rewrite(block: TokenStream, crayfish_path: &TokenStream) -> Result<Vec<Stmt>> {
+    let mut stmts = Block::parse_within.parse2(block)?;
+    let mut early_exit = EarlyExit {
+        crayfish_path,
+        targets: vec![],
+        error: None,
+        exits: 0,
+    };
+    early_exit.visit_stmts(&mut stmts);
+    match early_exit.error {
+        Some(e) => Err(e),
+        None => Ok(stmts),
+    }
+}
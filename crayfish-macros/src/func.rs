@@ -1,9 +1,13 @@
 use crate::attr::Attributes;
+use crate::early_exit;
 use crate::utils::err;
 use proc_macro2::Span;
 use proc_macro2::TokenStream;
-use proc_macro2::TokenTree;
 use quote::quote;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use syn::parse::ParseStream;
+use syn::parse::Parser;
 use syn::punctuated::Punctuated;
 use syn::AttributeArgs;
 use syn::Error;
@@ -36,10 +40,74 @@ fn at_async_fn_name(fn_name: &TokenStream) -> TokenStream {
     prepend_ugly_prefix(&format!("at_async_{}", fn_name))
 }
 
+fn at_try_async_fn_name(fn_name: &TokenStream) -> TokenStream {
+    prepend_ugly_prefix(&format!("at_try_async_{}", fn_name))
+}
+
 fn at_ff_fn_name(fn_name: &TokenStream) -> TokenStream {
     prepend_ugly_prefix(&format!("at_ff_{}", fn_name))
 }
 
+fn at_each_fn_name(fn_name: &TokenStream) -> TokenStream {
+    prepend_ugly_prefix(&format!("at_each_{}", fn_name))
+}
+
+fn at_each_relay_fn_name(fn_name: &TokenStream) -> TokenStream {
+    prepend_ugly_prefix(&format!("at_each_relay_{}", fn_name))
+}
+
+fn at_stream_fn_name(fn_name: &TokenStream) -> TokenStream {
+    prepend_ugly_prefix(&format!("at_stream_{}", fn_name))
+}
+
+fn stream_relay_fn_name(fn_name: &TokenStream) -> TokenStream {
+    prepend_ugly_prefix(&format!("stream_relay_{}", fn_name))
+}
+
+// the item type of a function returning impl Stream<Item = T> or BoxStream<'a, T>
+fn stream_item(function: &ItemFn) -> Option<TokenStream> {
+    if function.sig.asyncness.is_some() {
+        return None;
+    }
+    let ty = match &function.sig.output {
+        syn::ReturnType::Type(_, t) => &**t,
+        syn::ReturnType::Default => return None,
+    };
+    match ty {
+        syn::Type::Path(p) if p.qself.is_none() && p.path.segments.len() == 1 => {
+            let box_stream_t = p.path.segments.last().unwrap();
+            if &format!("{}", box_stream_t.ident) == "BoxStream" {
+                if let syn::PathArguments::AngleBracketed(ref pargs) = box_stream_t.arguments {
+                    if pargs.args.len() == 2 {
+                        if let syn::GenericArgument::Type(t) = pargs.args.last().unwrap() {
+                            return Some(quote!(#t));
+                        }
+                    }
+                }
+            }
+            None
+        }
+        syn::Type::ImplTrait(p) => {
+            for bound in p.bounds.iter() {
+                if let syn::TypeParamBound::Trait(t) = bound {
+                    let output = t.path.segments.last().unwrap();
+                    if &format!("{}", output.ident) != "Stream" {
+                        continue;
+                    }
+                    if let syn::PathArguments::AngleBracketed(ref pargs) = output.arguments {
+                        if let Some(syn::GenericArgument::Binding(b)) = pargs.args.last() {
+                            let ty = &b.ty;
+                            return Some(quote!(#ty));
+                        }
+                    }
+                }
+            }
+            None
+        }
+        _ => None,
+    }
+}
+
 struct HelperFunctionsGenerator {
     crayfish_path: TokenStream,
     fn_id: TokenStream,
@@ -123,26 +191,33 @@ impl HelperFunctionsGenerator {
     }
 
     fn new(function: &ItemFn, crayfish_path: &TokenStream, attrs: &Attributes) -> Result<Self> {
-        let crayfish_path = crayfish_path.clone();
-
-        let ItemFn {
-            sig: syn::Signature { ident, inputs, .. },
-            ..
-        } = function;
-
-        let fn_name: TokenStream = quote!(#ident);
-        let file: TokenStream = file!().parse().unwrap();
-        let line: TokenStream = line!().to_string().parse().unwrap();
-        let path: TokenStream = module_path!().parse().unwrap();
-        let fn_id: TokenStream = fn_hash(&fn_name, &file, &line, &path)
-            .to_string()
-            .parse()
-            .unwrap();
         let ret_type: TokenStream = match &attrs.ret_type {
             Some(t) => quote!(#t),
             None => Self::infer_ret(function)?,
         };
+        Ok(Self::with_ret_type(function, crayfish_path, ret_type))
+    }
+
+    fn with_ret_type(
+        function: &ItemFn,
+        crayfish_path: &TokenStream,
+        ret_type: TokenStream,
+    ) -> Self {
+        let crayfish_path = crayfish_path.clone();
 
+        let ItemFn { sig, .. } = function;
+        let syn::Signature { ident, inputs, .. } = sig;
+
+        let fn_name: TokenStream = quote!(#ident);
+        // evaluated at compile time in user's crate, where module_path!() is the user's module
+        let signature = syn::LitStr::new(&quote!(#sig).to_string(), Span::call_site());
+        let fn_id: TokenStream = quote! {
+            {
+                const FN_ID: #crayfish_path::activity::FunctionLabel =
+                    #crayfish_path::runtime_meta::function_id(::std::module_path!(), #signature);
+                FN_ID
+            }
+        };
         // first param is impl Context
         let params = inputs.clone().into_iter();
         let params: Vec<(String, Type)> = params
@@ -152,13 +227,13 @@ impl HelperFunctionsGenerator {
                 _ => panic!("method not implemented"),
             })
             .collect();
-        Ok(HelperFunctionsGenerator {
+        HelperFunctionsGenerator {
             crayfish_path,
             fn_id,
             fn_name,
             params,
             ret_type,
-        })
+        }
     }
 
     fn punctuated_params(&self) -> TokenStream {
@@ -166,7 +241,7 @@ impl HelperFunctionsGenerator {
             .params
             .iter()
             .map(|(ref ident, ref ty)| {
-                format!("{}:{}", ident, quote!(#ty))
+                format!("{}: {}", ident, quote!(#ty))
                     .parse::<TokenStream>()
                     .unwrap()
             })
@@ -189,6 +264,10 @@ impl HelperFunctionsGenerator {
         prepend_ugly_prefix(&format!("execute_{}", self.fn_name))
     }
 
+    fn execute_local_fn_name(&self) -> TokenStream {
+        prepend_ugly_prefix(&format!("execute_local_{}", self.fn_name))
+    }
+
     fn gen_at_ff(&self) -> TokenStream {
         let crayfish_path = &self.crayfish_path;
         let fn_id = &self.fn_id;
@@ -209,7 +288,8 @@ impl HelperFunctionsGenerator {
             let fn_id = #fn_id; // macro
 
             if dst_place == #crayfish_path::place::here() {
-                #crayfish_path::spawn(#execute_fn_name(a_id, true, #(#param_ident_list),*)); // macro
+                let task_context = #crayfish_path::context::current();
+                #crayfish_path::spawn(#execute_fn_name(a_id, false, task_context, #(#param_ident_list),*)); // macro
             } else {
                 // trace!("spawn activity:{} at place: {}", a_id, dst_place);
                 let mut builder = #crayfish_path::activity::TaskItemBuilder::new(fn_id, dst_place, a_id);
@@ -217,6 +297,7 @@ impl HelperFunctionsGenerator {
                 #crayfish_path::profiling_start!(#profiling_label);
                 #(builder.arg(#param_ident_list);)*
                 #crayfish_path::profiling_stop!();
+                builder.context(#crayfish_path::context::current());
 
                 let item = builder.build_box();
                 use #crayfish_path::runtime::ApgasContext;
@@ -229,10 +310,36 @@ impl HelperFunctionsGenerator {
 
     fn gen_at_async(&self) -> TokenStream {
         let crayfish_path = &self.crayfish_path;
-        let fn_id = &self.fn_id;
         let ret_type = &self.ret_type;
-        let at_async_fn_name = at_async_fn_name(&self.fn_name);
-        let execute_fn_name = self.execute_fn_name();
+        self.gen_at_waited(
+            at_async_fn_name(&self.fn_name),
+            quote!(#ret_type),
+            quote!(#crayfish_path::runtime::wait_single::<#ret_type>),
+            quote!(#crayfish_path::runtime::wait_local::<#ret_type>),
+        )
+    }
+
+    fn gen_at_try_async(&self) -> TokenStream {
+        let crayfish_path = &self.crayfish_path;
+        let ret_type = &self.ret_type;
+        self.gen_at_waited(
+            at_try_async_fn_name(&self.fn_name),
+            quote!(::std::result::Result<#ret_type, #crayfish_path::runtime::RemotePanic>),
+            quote!(#crayfish_path::runtime::try_wait_single::<#ret_type>),
+            quote!(#crayfish_path::runtime::try_wait_local::<#ret_type>),
+        )
+    }
+
+    fn gen_at_waited(
+        &self,
+        helper_fn_name: TokenStream,
+        output_type: TokenStream,
+        wait_fn: TokenStream,
+        wait_local_fn: TokenStream,
+    ) -> TokenStream {
+        let crayfish_path = &self.crayfish_path;
+        let fn_id = &self.fn_id;
+        let execute_local_fn_name = self.execute_local_fn_name();
         let punctuated_params = self.punctuated_params();
         let param_ident_list = self.param_ident_list();
         let profiling_label = syn::LitStr::new(
@@ -242,30 +349,156 @@ impl HelperFunctionsGenerator {
 
         quote! {
 
-        fn #at_async_fn_name(
+        fn #helper_fn_name(
             a_id: #crayfish_path::activity::ActivityId,
             dst_place: #crayfish_path::place::Place,
             #punctuated_params
-        ) -> impl #crayfish_path::re_export::futures::Future<Output = #ret_type > {
+        ) -> impl #crayfish_path::re_export::futures::Future<Output = #output_type > {
             let fn_id = #fn_id; // macro
+            use #crayfish_path::re_export::futures::future::Either;
 
-            let f = #crayfish_path::runtime::wait_single::<#ret_type>(a_id); // macro
             if dst_place == #crayfish_path::place::here() {
-                #crayfish_path::spawn(#execute_fn_name(a_id, true, #(#param_ident_list),*)); // macro
+                // fast path: no serialization, the return value is got from the join handle
+                let task_context = #crayfish_path::context::current();
+                let handle = #crayfish_path::spawn(#execute_local_fn_name(a_id, task_context, #(#param_ident_list),*)); // macro
+                Either::Left(#wait_local_fn(fn_id, a_id, handle))
             } else {
+                let f = #wait_fn(a_id); // macro
                 // trace!("spawn activity:{} at place: {}", a_id, dst_place);
                 let mut builder = #crayfish_path::activity::TaskItemBuilder::new(fn_id, dst_place, a_id);
 
                 #crayfish_path::profiling_start!(#profiling_label);
                 #(builder.arg(#param_ident_list);)*
                 #crayfish_path::profiling_stop!();
+                builder.context(#crayfish_path::context::current());
 
                 builder.waited();
                 let item = builder.build_box();
                 use #crayfish_path::runtime::ApgasContext;
                 #crayfish_path::runtime::ConcreteContext::send(item);
+                Either::Right(f)
+            }
+        }
+
+        }
+    }
+
+    fn gen_at_each(&self) -> TokenStream {
+        let crayfish_path = &self.crayfish_path;
+        let ret_type = &self.ret_type;
+        let at_each_fn_name = at_each_fn_name(&self.fn_name);
+        let relay_at_async_fn_name = at_async_fn_name(&at_each_relay_fn_name(&self.fn_name));
+        let punctuated_params = self.punctuated_params();
+        let param_ident_list = self.param_ident_list();
+
+        quote! {
+
+        fn #at_each_fn_name(
+            a_id: ::std::option::Option<#crayfish_path::activity::ActivityId>,
+            places: ::std::vec::Vec<#crayfish_path::place::Place>,
+            #punctuated_params
+        ) -> impl #crayfish_path::re_export::futures::Future<Output = ::std::vec::Vec<#ret_type>> {
+            use #crayfish_path::re_export::futures::future::Either;
+            match a_id {
+                // an empty group spawns nothing
+                ::std::option::Option::None => {
+                    Either::Left(#crayfish_path::re_export::futures::future::ready(::std::vec::Vec::new()))
+                }
+                ::std::option::Option::Some(a_id) => {
+                    let args = #crayfish_path::activity::serialize_args(&(#(#param_ident_list,)*), &places[..]);
+                    let root = places[0];
+                    Either::Right(#relay_at_async_fn_name(a_id, root, places, args))
+                }
             }
-            f
+        }
+
+        }
+    }
+
+    // an activity running the function at the root of a spawn tree and relaying the serialized
+    // arguments to the subtrees
+    fn gen_at_each_relay(&self) -> Result<ItemFn> {
+        let crayfish_path = &self.crayfish_path;
+        let ret_type = &self.ret_type;
+        let relay_fn_name = at_each_relay_fn_name(&self.fn_name);
+        let relay_at_async_fn_name = at_async_fn_name(&relay_fn_name);
+        let at_async_fn_name = at_async_fn_name(&self.fn_name);
+        let param_ident_list = self.param_ident_list();
+        let param_types: Vec<_> = self.params.iter().map(|(_, ty)| ty).collect();
+        let context_arg_name = context_arg_name();
+        // locals must not collide with the parameters of the function
+        let places = prepend_ugly_prefix("places");
+        let args = prepend_ugly_prefix("args");
+        let subtrees = prepend_ugly_prefix("subtrees");
+        let subtree = prepend_ugly_prefix("subtree");
+        let root = prepend_ugly_prefix("root");
+        let here = prepend_ugly_prefix("here");
+        let rets = prepend_ugly_prefix("rets");
+
+        syn::parse2(quote! {
+        #[allow(clippy::unused_unit)]
+        async fn #relay_fn_name(
+            #places: ::std::vec::Vec<#crayfish_path::place::Place>,
+            #args: ::std::vec::Vec<u8>,
+        ) -> ::std::vec::Vec<#ret_type> {
+            debug_assert_eq!(#places[0], #crayfish_path::place::here());
+            let mut #subtrees = ::std::vec::Vec::new();
+            for #subtree in #crayfish_path::place::spawn_tree_children(&#places[..]) {
+                let #root = #subtree[0];
+                #subtrees.push(#relay_at_async_fn_name(#context_arg_name.spawn_at(#root), #root, #subtree, #args.clone()));
+            }
+            let (#(#param_ident_list,)*): (#(#param_types,)*) =
+                #crayfish_path::activity::deserialize_args(&#args[..]);
+            let #here = #crayfish_path::place::here();
+            let mut #rets = ::std::vec![
+                #at_async_fn_name(#context_arg_name.spawn_at(#here), #here, #(#param_ident_list),*).await
+            ];
+            for #subtree in #subtrees {
+                #rets.extend(#subtree.await);
+            }
+            #rets
+        }
+        })
+    }
+
+    // an activity driving the stream returned by the function and forwarding its items to the
+    // consumer. It is spawned like ff!, so it is counted by the finish of the consumer
+    fn gen_stream_relay(&self, item: &TokenStream) -> Result<ItemFn> {
+        let crayfish_path = &self.crayfish_path;
+        let fn_name = &self.fn_name;
+        let relay_fn_name = stream_relay_fn_name(&self.fn_name);
+        let punctuated_params = self.punctuated_params();
+        let param_ident_list = self.param_ident_list();
+        let context_arg_name = context_arg_name();
+
+        syn::parse2(quote! {
+        async fn #relay_fn_name(
+            sink: #crayfish_path::stream::StreamSink<#item>,
+            #punctuated_params
+        ) {
+            let stream = #fn_name(#context_arg_name, #(#param_ident_list),*);
+            sink.forward(stream).await
+        }
+        })
+    }
+
+    fn gen_at_stream(&self, item: &TokenStream) -> TokenStream {
+        let crayfish_path = &self.crayfish_path;
+        let at_stream_fn_name = at_stream_fn_name(&self.fn_name);
+        let relay_at_ff_fn_name = at_ff_fn_name(&stream_relay_fn_name(&self.fn_name));
+        let punctuated_params = self.punctuated_params();
+        let param_ident_list = self.param_ident_list();
+
+        quote! {
+
+        fn #at_stream_fn_name(
+            a_id: #crayfish_path::activity::ActivityId,
+            dst_place: #crayfish_path::place::Place,
+            #punctuated_params
+        ) -> #crayfish_path::stream::RemoteStream<#item> {
+            let (sink, stream) = #crayfish_path::stream::channel::<#item>(dst_place);
+            #relay_at_ff_fn_name(a_id, dst_place, sink, #(#param_ident_list),*);
+            stream
         }
 
         }
@@ -280,20 +513,53 @@ impl HelperFunctionsGenerator {
         let param_ident_list = self.param_ident_list();
 
         quote! {
-        async fn #execute_fn_name(a_id: #crayfish_path::activity::ActivityId, waited: ::std::primitive::bool, #punctuated_params) {
+        async fn #execute_fn_name(
+            a_id: #crayfish_path::activity::ActivityId,
+            waited: ::std::primitive::bool,
+            task_context: #crayfish_path::context::TaskContext,
+            #punctuated_params
+        ) {
             let fn_id = #fn_id; // macro
             use #crayfish_path::re_export::futures::FutureExt;
             let finish_id = a_id.get_finish_id();
             use #crayfish_path::runtime::ApgasContext;
-            let mut ctx = #crayfish_path::runtime::ConcreteContext::inherit(finish_id);
+            let mut ctx = #crayfish_path::runtime::ConcreteContext::for_activity(finish_id, &task_context);
             // ctx seems to be unwind safe
             let future = ::std::panic::AssertUnwindSafe(#fn_name(&mut ctx, #(#param_ident_list),* )); //macro
-            let result = future.catch_unwind().await;
+            let result = #crayfish_path::context::scope(task_context, future.catch_unwind()).await;
             #crayfish_path::essence::send_activity_result(ctx, a_id, fn_id, waited, result);
         }
         }
     }
 
+    fn gen_execute_local(&self) -> TokenStream {
+        let crayfish_path = &self.crayfish_path;
+        let fn_id = &self.fn_id;
+        let fn_name = &self.fn_name;
+        let ret_type = &self.ret_type;
+        let execute_local_fn_name = self.execute_local_fn_name();
+        let punctuated_params = self.punctuated_params();
+        let param_ident_list = self.param_ident_list();
+
+        quote! {
+        async fn #execute_local_fn_name(
+            a_id: #crayfish_path::activity::ActivityId,
+            task_context: #crayfish_path::context::TaskContext,
+            #punctuated_params
+        ) -> ::std::result::Result<#ret_type, #crayfish_path::activity::PanicPayload> {
+            let fn_id = #fn_id; // macro
+            use #crayfish_path::re_export::futures::FutureExt;
+            let finish_id = a_id.get_finish_id();
+            use #crayfish_path::runtime::ApgasContext;
+            let mut ctx = #crayfish_path::runtime::ConcreteContext::for_activity(finish_id, &task_context);
+            // ctx seems to be unwind safe
+            let future = ::std::panic::AssertUnwindSafe(#fn_name(&mut ctx, #(#param_ident_list),* )); //macro
+            let result = #crayfish_path::context::scope(task_context, future.catch_unwind()).await;
+            #crayfish_path::essence::local_activity_result(ctx, a_id, fn_id, result)
+        }
+        }
+    }
+
     fn gen_handler(&self) -> TokenStream {
         let crayfish_path = &self.crayfish_path;
         let fn_id = &self.fn_id;
@@ -308,6 +574,7 @@ impl HelperFunctionsGenerator {
             &format!("{}_deserialization", self.fn_name),
             Span::call_site(),
         );
+        let fn_name_str = syn::LitStr::new(&self.fn_name.to_string(), Span::call_site());
 
         quote! {
 
@@ -317,6 +584,7 @@ impl HelperFunctionsGenerator {
             let waited = item.is_waited();
             let mut e = #crayfish_path::activity::TaskItemExtracter::new(item);
             let a_id = e.activity_id();
+            let task_context = e.context();
 
             // wait until function return
             // #crayfish_path::logging::trace!(
@@ -325,7 +593,7 @@ impl HelperFunctionsGenerator {
             #crayfish_path::profiling_start!(#profiling_label);
             #(#arg_stmts)*
             #crayfish_path::profiling_stop!();
-            #execute_fn_name(a_id, waited, #(#extract_args),*).await;
+            #execute_fn_name(a_id, waited, task_context, #(#extract_args),*).await;
         }
         .boxed()
         }
@@ -337,7 +605,7 @@ impl HelperFunctionsGenerator {
                 #crayfish_path::runtime_meta::FunctionMetaData::new(
                     #fn_id,
                     #handler_fn_name,
-                    ::std::string::String::from("basic"),
+                    ::std::string::String::from(#fn_name_str),
                     ::std::string::String::from(::std::file!()),
                     ::std::line!(),
                     ::std::string::String::from(::std::module_path!())
@@ -354,12 +622,30 @@ fn _expand_async_func(attrs: Attributes, function: ItemFn) -> Result<TokenStream
     //
 
     let crayfish_path: TokenStream = attrs.get_path();
+    if let Some(item) = stream_item(&function) {
+        return expand_stream_func(attrs, function, item);
+    }
     let gen = HelperFunctionsGenerator::new(&function, &crayfish_path, &attrs)?;
 
     let execute_fn = gen.gen_execute();
+    let execute_local_fn = gen.gen_execute_local();
     let handler_fn = gen.gen_handler();
     let at_async_fn = gen.gen_at_async();
+    let at_try_async_fn = gen.gen_at_try_async();
     let at_ff_fn = gen.gen_at_ff();
+    // the relay of at_each! is an activity itself, but not broadcast
+    let at_each_fn = match attrs.at_each {
+        true => {
+            let relay_attrs = Attributes {
+                crayfish_path: attrs.crayfish_path.clone(),
+                ..Attributes::default()
+            };
+            let relay_fn = _expand_async_func(relay_attrs, gen.gen_at_each_relay()?)?;
+            let at_each_fn = gen.gen_at_each();
+            quote!(#at_each_fn #relay_fn)
+        }
+        false => quote!(),
+    };
 
     let mut function = function;
     // modify fn
@@ -401,11 +687,48 @@ fn _expand_async_func(attrs: Attributes, function: ItemFn) -> Result<TokenStream
 
     #execute_fn
 
+    #execute_local_fn
+
     #handler_fn
 
     #at_async_fn
 
+    #at_try_async_fn
+
     #at_ff_fn
+
+    #at_each_fn
+    ))
+}
+
+// A function returning a stream is called by a relay activity at the producer place, which is
+// spawned by at_stream! instead of the function itself
+fn expand_stream_func(
+    attrs: Attributes,
+    function: ItemFn,
+    item: TokenStream,
+) -> Result<TokenStream> {
+    let crayfish_path: TokenStream = attrs.get_path();
+    let gen = HelperFunctionsGenerator::with_ret_type(&function, &crayfish_path, quote!(()));
+    let relay_attrs = Attributes {
+        crayfish_path: attrs.crayfish_path.clone(),
+        ..Attributes::default()
+    };
+    let relay_fn = _expand_async_func(relay_attrs, gen.gen_stream_relay(&item)?)?;
+    let at_stream_fn = gen.gen_at_stream(&item);
+
+    let mut function = function;
+    let context_arg_name = context_arg_name();
+    let context_arg: syn::FnArg =
+        syn::parse2(quote!(#context_arg_name: &mut impl #crayfish_path::runtime::ApgasContext))?;
+    function.sig.inputs.insert(0, context_arg);
+
+    Ok(quote!(
+    #function
+
+    #relay_fn
+
+    #at_stream_fn
     ))
 }
 
@@ -447,23 +770,12 @@ fn verify_func(func: &ItemFn) -> Result<()> {
     }
 }
 
-fn fn_hash(
-    fn_name: &TokenStream,
-    file: &TokenStream,
-    line: &TokenStream,
-    path: &TokenStream,
-) -> u64 {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::Hasher;
-    let mut hasher = DefaultHasher::new();
-    let s = quote! { #fn_name #file #line #path }.to_string();
-    hasher.write(s.as_bytes());
-    hasher.finish()
-}
-
 pub enum SpawnMethod {
     At,
+    TryAt,
     FireAndForget,
+    AtEach,
+    AtStream,
 }
 
 pub fn expand_at(input: proc_macro::TokenStream, spawn: SpawnMethod) -> Result<TokenStream> {
@@ -505,7 +817,10 @@ pub fn expand_at(input: proc_macro::TokenStream, spawn: SpawnMethod) -> Result<T
                         let last_ident = &last.ident;
                         let last_ident_str = match spawn {
                             SpawnMethod::At => at_async_fn_name,
+                            SpawnMethod::TryAt => at_try_async_fn_name,
                             SpawnMethod::FireAndForget => at_ff_fn_name,
+                            SpawnMethod::AtEach => at_each_fn_name,
+                            SpawnMethod::AtStream => at_stream_fn_name,
                         }(&quote!(#last_ident))
                         .to_string();
                         last.ident = syn::Ident::new(last_ident_str.as_str(), last.ident.span());
@@ -526,48 +841,293 @@ pub fn expand_at(input: proc_macro::TokenStream, spawn: SpawnMethod) -> Result<T
     let place = args.pop();
     let context_arg_name = context_arg_name();
 
-    let ret = quote! {#async_func_name(#context_arg_name.spawn(), #place #call_args)};
+    let ret = match spawn {
+        SpawnMethod::AtEach => {
+            let crayfish_path = Attributes::default().get_path();
+            let group = place.map(|p| p.into_value());
+            quote! {
+                {
+                let places = #crayfish_path::place::spawn_tree_places(#group);
+                let a_id = match places.is_empty() {
+                    true => ::std::option::Option::None,
+                    false => ::std::option::Option::Some(#context_arg_name.spawn_at(places[0])),
+                };
+                #async_func_name(a_id, places, #call_args)
+                }
+            }
+        }
+        // the place is evaluated first, for the context to know where the activity runs
+        _ => {
+            let place = place.map(|p| p.into_value());
+            quote! {
+                {
+                let __crayfish_dst = #place;
+                #async_func_name(#context_arg_name.spawn_at(__crayfish_dst), __crayfish_dst, #call_args)
+                }
+            }
+        }
+    };
     Ok(ret)
 }
 
-pub fn finish(args: Option<AttributeArgs>, input: proc_macro::TokenStream) -> Result<TokenStream> {
-    let attrs = match args {
-        Some(args) => Attributes::new(args)?,
-        None => Attributes::default(),
+// A closure can not be sent to another place, so the closure of at_home! becomes an activity
+// defined in place. Its name is unique for each expansion in a crate, even of the same call
+// site in a macro_rules!, and the same in every place running the binary
+static AT_HOME_EXPANSIONS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn expand_at_home(input: proc_macro::TokenStream) -> Result<TokenStream> {
+    let input = TokenStream::from(input);
+    let parser = |input: ParseStream| -> Result<(Expr, syn::ExprClosure)> {
+        let gref = input.parse::<Expr>()?;
+        input.parse::<Token![,]>()?;
+        Ok((gref, input.parse::<syn::ExprClosure>()?))
     };
+    let (gref, closure) = parser.parse2(input)?;
+    if closure.asyncness.is_some() {
+        err(&closure, "the closure of at_home! can not be async")?;
+    }
+    if closure.inputs.len() != 1 {
+        err(
+            &closure.inputs,
+            "the closure of at_home! takes one argument: |obj: &T|",
+        )?;
+    }
+    let (pat, obj_type) = match closure.inputs.first().unwrap() {
+        syn::Pat::Type(syn::PatType { pat, ty, .. }) => match &**ty {
+            Type::Reference(r) if r.mutability.is_none() => (pat, &r.elem),
+            _ => return err(ty, "the argument must be a shared reference: &T"),
+        },
+        arg => return err(arg, "the argument must be typed: |obj: &T|"),
+    };
+    // the return type of the activity is never inferred
+    let ret_type = match &closure.output {
+        syn::ReturnType::Default => {
+            return err(
+                &closure,
+                "the closure of at_home! must declare its return type: |obj: &T| -> R { .. }",
+            )
+        }
+        syn::ReturnType::Type(_, t) => quote!(#t),
+    };
+    let body = &closure.body;
+
+    let expansion = AT_HOME_EXPANSIONS.fetch_add(1, Ordering::Relaxed);
+    let call_site = proc_macro::Span::call_site();
+    let fn_name = prepend_ugly_prefix(&format!(
+        "at_home_{}_{}_{}",
+        expansion,
+        call_site.line(),
+        call_site.column()
+    ));
+    let crayfish_path = Attributes::default().get_path();
+
+    Ok(quote! {
+        {
+        #[#crayfish_path::activity]
+        #[allow(clippy::let_and_return)]
+        async fn #fn_name(gref: #crayfish_path::shared::GlobalRef<#obj_type>) -> #ret_type {
+            let obj = gref.get();
+            let #pat: &#obj_type = &*obj;
+            // temporaries of the body must not outlive obj
+            let ret: #ret_type = #body;
+            ret
+        }
+        let gref = #crayfish_path::shared::GlobalRef::clone(&#gref);
+        let home = gref.home();
+        #crayfish_path::at!(home, #fn_name(gref))
+        }
+    })
+}
 
-    let block = TokenStream::from(input);
-
-    // error if return inside finish block
-    for tree in block.clone().into_iter() {
-        match tree {
-            TokenTree::Ident(id) => {
-                if &id.to_string() == "return" {
-                    return err(id, "returning from finish blocks is not allowed until the async closure become stable in Rust");
-                }
-            }
-            TokenTree::Punct(p) => {
-                if p.as_char() == '?' {
-                    return err(p, "try expression is not allowed in finish block. TODO: this check might be false positive.");
-                }
+pub fn finish(args: Option<AttributeArgs>, input: proc_macro::TokenStream) -> Result<TokenStream> {
+    let input = TokenStream::from(input);
+    let (attrs, block) = match args {
+        Some(args) => {
+            // the attribute also selects the mode of finish_async! and finish_collect!
+            if let Ok((mac, semi)) = parse_finish_macro.parse2(input.clone()) {
+                let attrs = Attributes::new(args)?;
+                let expanded = match mac.path.get_ident().map(|i| i.to_string()).as_deref() {
+                    Some("finish_async") => expand_finish(attrs, mac.tokens, FinishKind::Async)?,
+                    Some("try_finish") => expand_finish(attrs, mac.tokens, FinishKind::Try)?,
+                    Some("finish_collect") => {
+                        let (reducer, block) = parse_collect.parse2(mac.tokens)?;
+                        expand_finish(attrs, block, FinishKind::Collect(Box::new(reducer)))?
+                    }
+                    _ => {
+                        let expected =
+                            "expect a block, finish_async!, finish_collect! or try_finish!";
+                        return err(mac.path, expected);
+                    }
+                };
+                return Ok(quote!(#expanded #semi));
             }
-            _ => (),
+            (Attributes::new(args)?, input)
         }
+        None => parse_mode.parse2(input)?,
+    };
+    expand_finish(attrs, block, FinishKind::Wait)
+}
+
+fn parse_finish_macro(input: ParseStream) -> Result<(syn::Macro, Option<Token![;]>)> {
+    Ok((input.parse()?, input.parse()?))
+}
+
+// finish macros optionally start with `mode = "..",` for a mode, like #[finish_attr]
+pub fn parse_mode_arg(input: ParseStream) -> Result<Option<syn::MetaNameValue>> {
+    let is_mode = matches!(input.fork().parse::<syn::Ident>(), Ok(i) if i == "mode");
+    if !is_mode || !input.peek2(Token![=]) {
+        return Ok(None);
     }
+    let mode = input.parse()?;
+    input.parse::<Token![,]>()?;
+    Ok(Some(mode))
+}
+
+fn parse_mode(input: ParseStream) -> Result<(Attributes, TokenStream)> {
+    let attrs = match parse_mode_arg(input)? {
+        Some(mode) => Attributes::new(vec![syn::NestedMeta::Meta(syn::Meta::NameValue(mode))])?,
+        None => Attributes::default(),
+    };
+    Ok((attrs, input.parse()?))
+}
+
+fn parse_collect(input: ParseStream) -> Result<(Type, TokenStream)> {
+    let reducer = input.parse::<Type>()?;
+    input.parse::<Token![,]>()?;
+    Ok((reducer, input.parse::<TokenStream>()?))
+}
+
+pub fn finish_async(input: proc_macro::TokenStream) -> Result<TokenStream> {
+    let (attrs, block) = parse_mode.parse(input)?;
+    expand_finish(attrs, block, FinishKind::Async)
+}
+
+pub fn try_finish(input: proc_macro::TokenStream) -> Result<TokenStream> {
+    let (attrs, block) = parse_mode.parse(input)?;
+    expand_finish(attrs, block, FinishKind::Try)
+}
+
+pub fn finish_collect(input: proc_macro::TokenStream) -> Result<TokenStream> {
+    let (attrs, input) = parse_mode.parse(input)?;
+    let (reducer, block) = parse_collect.parse2(input)?;
+    expand_finish(attrs, block, FinishKind::Collect(Box::new(reducer)))
+}
 
+pub fn offer(input: proc_macro::TokenStream) -> Result<TokenStream> {
+    let value = syn::parse::<Expr>(input)?;
+    let crayfish_path = Attributes::default().get_path();
+    let context_arg_name = context_arg_name();
+    Ok(quote!({
+        use #crayfish_path::runtime::ApgasContext as _;
+        #crayfish_path::collecting::offer(#context_arg_name.finish_id(), #value)
+    }))
+}
+
+enum FinishKind {
+    // wait for the activities and resolve to the value of the block
+    Wait,
+    // wait for the activities and resolve to the reduction of offered values
+    Collect(Box<Type>),
+    // resolve to a FinishHandle waiting the activities
+    Async,
+    // wait for the activities and resolve to the value of the block or the error of the finish
+    Try,
+}
+
+fn expand_finish(attrs: Attributes, block: TokenStream, kind: FinishKind) -> Result<TokenStream> {
     let crayfish_path = attrs.get_path();
     let context_arg_name = context_arg_name();
+    let new_frame = match &attrs.finish_mode {
+        Some(mode) => quote!(#crayfish_path::runtime::ConcreteContext::new_frame_with_mode(
+            #crayfish_path::runtime::FinishMode::#mode
+        )),
+        None => quote!(#crayfish_path::runtime::ConcreteContext::new_frame()),
+    };
+    // spawned activities are waited even if the block exits early
+    let stmts = early_exit::rewrite(block, &crayfish_path)?;
+    let block = quote! {
+        async {
+            ::std::ops::ControlFlow::Continue({
+                #(#stmts)*
+            })
+        }
+    };
+    let block_ret = match kind {
+        // activities spawned in the block offer to this finish, even under nested finishes
+        FinishKind::Collect(_) => quote! {
+            let _block_ret = #crayfish_path::collecting::scope(
+                #context_arg_name.finish_id(),
+                #block,
+            )
+            .await;
+        },
+        _ => quote!(let _block_ret = #block.await;),
+    };
 
-    let ret = quote! {
-        {
-        use crayfish::runtime::ApgasContext;
-        let mut #context_arg_name = #crayfish_path::runtime::ConcreteContext::new_frame();
-        let _block_ret = {
-            #block
-        };
-        #crayfish_path::runtime::wait_all(#context_arg_name).await;
-        _block_ret
+    let ret = match kind {
+        FinishKind::Wait => quote! {
+            {
+            use crayfish::runtime::ApgasContext;
+            let mut #context_arg_name = #new_frame;
+            #block_ret
+            #crayfish_path::runtime::wait_all(#context_arg_name).await;
+            match _block_ret {
+                ::std::ops::ControlFlow::Continue(_block_ret) => _block_ret,
+                ::std::ops::ControlFlow::Break(_early_ret) => return _early_ret,
+            }
+            }
+        },
+        FinishKind::Collect(reducer) => {
+            let collecting = prepend_ugly_prefix("collecting");
+            quote! {
+                {
+                use crayfish::runtime::ApgasContext;
+                let mut #context_arg_name = #new_frame;
+                let #collecting = #crayfish_path::collecting::start::<#reducer>(&#context_arg_name);
+                #block_ret
+                #crayfish_path::runtime::wait_all(#context_arg_name).await;
+                match _block_ret {
+                    ::std::ops::ControlFlow::Continue(_) => #collecting.result(),
+                    ::std::ops::ControlFlow::Break(_early_ret) => return _early_ret,
+                }
+                }
+            }
         }
+        // an early exit cannot carry the error, so it re-panics like finish!
+        FinishKind::Try => quote! {
+            {
+            use crayfish::runtime::ApgasContext;
+            let mut #context_arg_name = #new_frame;
+            #block_ret
+            let _finished = #crayfish_path::runtime::try_wait_resilient(#context_arg_name).await;
+            match _block_ret {
+                ::std::ops::ControlFlow::Continue(_block_ret) => _finished.map(|()| _block_ret),
+                ::std::ops::ControlFlow::Break(_early_ret) => {
+                    if let ::std::result::Result::Err(e) = _finished {
+                        panic!("{}", e);
+                    }
+                    return _early_ret;
+                }
+            }
+            }
+        },
+        // an early exit still waits, for the handle never gets out of the block
+        FinishKind::Async => quote! {
+            {
+            use crayfish::runtime::ApgasContext;
+            let mut #context_arg_name = #new_frame;
+            #block_ret
+            match _block_ret {
+                ::std::ops::ControlFlow::Continue(_block_ret) => {
+                    #crayfish_path::runtime::FinishHandle::new(#context_arg_name, _block_ret)
+                }
+                ::std::ops::ControlFlow::Break(_early_ret) => {
+                    #crayfish_path::runtime::wait_all(#context_arg_name).await;
+                    return _early_ret;
+                }
+            }
+            }
+        },
     };
     Ok(ret)
 }
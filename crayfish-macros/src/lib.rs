@@ -6,6 +6,7 @@ use syn::ItemFn;
 
 mod args;
 mod attr;
+mod early_exit;
 mod func;
 mod utils;
 
@@ -60,6 +61,14 @@ pub fn at(input: TokenStream) -> TokenStream {
         .into()
 }
 
+/// try_at!(place, func(a, b, c, d)); resolves to Result<Ret, RemotePanic>
+#[proc_macro]
+pub fn try_at(input: TokenStream) -> TokenStream {
+    func::expand_at(input, func::SpawnMethod::TryAt)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
 /// ff!(place, func(a, b, c, d));
 #[proc_macro]
 pub fn ff(input: TokenStream) -> TokenStream {
@@ -68,6 +77,47 @@ pub fn ff(input: TokenStream) -> TokenStream {
         .into()
 }
 
+/// at_each!(group, func(a, b, c, d)); spawns func at each place of the group. Arguments
+/// are serialized once and relayed along a spawn tree. Resolves to a Vec of return values
+/// ordered by place. The function must be declared with #[activity(at_each)]
+///
+/// Each place gets its own copy of the arguments: a Clock registers a participant at each
+/// place, and a global promise or future, which is used once, panics when sent this way
+#[proc_macro]
+pub fn at_each(input: TokenStream) -> TokenStream {
+    func::expand_at(input, func::SpawnMethod::AtEach)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// at_stream!(place, func(a, b, c, d)); spawns func returning impl Stream<Item = T> at the
+/// place, and resolves at once to a RemoteStream of its items. Dropping the stream cancels it
+#[proc_macro]
+pub fn at_stream(input: TokenStream) -> TokenStream {
+    func::expand_at(input, func::SpawnMethod::AtStream)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// at_home!(gref, |obj: &T| -> Ret { .. }); runs the closure on the value of a GlobalRef at
+/// its home place and resolves to the return value. The closure must not capture variables
+/// and must declare its return type
+#[proc_macro]
+pub fn at_home(input: TokenStream) -> TokenStream {
+    func::expand_at_home(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// #[finish_attr(mode = "spmd")] { .. } selects a cheaper termination detection:
+/// "local" if all activities stay at the finish place, "spmd" if activities only spawn at
+/// their own place, and "here" if activities spawned by the block spawn nothing under it.
+/// "resilient" tracks where activities run instead, so that the finish completes with a
+/// `DeadPlaceError` when places fail, see `runtime::notify_place_failure`. Use it with
+/// `try_finish!` to get the error instead of a panic.
+/// The attribute selects the mode of `finish_async!{ .. }` and `finish_collect!(..)` as well.
+/// Without attributes on expressions, finish macros take the mode as the first argument:
+/// finish_async!(mode = "spmd", { .. }).
 #[proc_macro_attribute]
 pub fn finish_attr(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as AttributeArgs);
@@ -76,6 +126,9 @@ pub fn finish_attr(args: TokenStream, input: TokenStream) -> TokenStream {
         .into()
 }
 
+/// finish!{ .. }; runs the block and waits for the activities spawned under it. `return` and
+/// `?` in the block leave it once the activities are done. They are rejected inside macros
+/// other than those of crayfish, e.g. println! or assert!, whose tokens cannot be rewritten
 #[proc_macro]
 pub fn finish(input: TokenStream) -> TokenStream {
     func::finish(None, input)
@@ -83,6 +136,43 @@ pub fn finish(input: TokenStream) -> TokenStream {
         .into()
 }
 
+/// try_finish!{ .. }; waits like finish!, but resolves to a `Result` of the value of the block
+/// or the `FinishError` of the finish instead of re-panicking, e.g. with
+/// try_finish!(mode = "resilient", { .. }) to recover from failed places
+#[proc_macro]
+pub fn try_finish(input: TokenStream) -> TokenStream {
+    func::try_finish(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// finish_collect!(Reducer, { .. }); waits like finish!, and resolves to the reduction of
+/// the values offered by the activities under it
+#[proc_macro]
+pub fn finish_collect(input: TokenStream) -> TokenStream {
+    func::finish_collect(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// finish_async!{ .. }; runs the block like finish!, but resolves to a FinishHandle at once
+/// instead of waiting. The handle is a future of the value of the block that resolves once
+/// the activities under it are done, and can be stored, joined or raced with other futures.
+#[proc_macro]
+pub fn finish_async(input: TokenStream) -> TokenStream {
+    func::finish_async(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// offer!(value); offers a value to the enclosing finish_collect!
+#[proc_macro]
+pub fn offer(input: TokenStream) -> TokenStream {
+    func::offer(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
 #[proc_macro_attribute]
 pub fn main(args: TokenStream, input: TokenStream) -> TokenStream {
     let args = parse_macro_input!(args as AttributeArgs);
@@ -5,4 +5,6 @@ fn test_macros() {
     t.compile_fail("tests/trybuild/args_err.rs");
     t.compile_fail("tests/trybuild/ret_infer_err.rs");
     t.compile_fail("tests/trybuild/bad_finish.rs");
+    t.compile_fail("tests/trybuild/finish_break.rs");
+    t.compile_fail("tests/trybuild/finish_opaque_macro.rs");
 }
@@ -24,3 +24,148 @@ async fn bar(a: i32, b: i64, c: Vec<usize>, d: String) -> i32 {
         ret.await
     ) + a
 }
+
+#[activity]
+async fn count(n: usize) {
+    offer!(crayfish::collecting::Sum(n));
+}
+
+#[activity]
+async fn collect() -> usize {
+    let crayfish::collecting::Sum(total) = finish_collect!(crayfish::collecting::Sum<usize>, {
+        offer!(crayfish::collecting::Sum(1usize));
+        ff!(crayfish::place::here(), count(2));
+        at!(crayfish::place::here(), count(3)).await;
+    });
+    total
+}
+
+#[activity]
+async fn parse(s: String) -> Result<i32, String> {
+    let a = finish! {
+        let n = s.parse::<i32>().map_err(|e| e.to_string())?;
+        if n < 0 {
+            return Err(format!("negative {}", n));
+        }
+        ff!(crayfish::place::here(), baz());
+        n
+    };
+    let b = finish!(
+        let items: Vec<i32> = vec![1, 2];
+        // return of closures are left alone
+        items
+            .iter()
+            .map(|i| {
+                if *i > 9 {
+                    return 9;
+                }
+                *i
+            })
+            .sum::<i32>()
+    );
+    Ok(a + b)
+}
+
+#[activity]
+async fn first(v: Vec<usize>) -> Option<usize> {
+    finish! {
+        let f = *v.first()?;
+        finish! {
+            if f == 0 {
+                return None;
+            }
+            ff!(crayfish::place::here(), count(f));
+        };
+        Some(f)
+    }
+}
+
+#[activity]
+async fn overlap(v: Vec<usize>) -> Option<usize> {
+    let counting = finish_async! {
+        let f = *v.first()?;
+        ff!(crayfish::place::here(), count(f));
+        f
+    };
+    let outstanding = counting.outstanding();
+    let other = finish_async! {
+        at!(crayfish::place::here(), baz()).await
+    };
+    let (f, b) = futures::join!(counting, other);
+    Some(f + b as usize + outstanding)
+}
+
+#[activity]
+async fn loops(v: Vec<usize>) -> usize {
+    let mut total = 0;
+    for i in v {
+        // loops inside the finish block are left alone
+        total += finish! {
+            let mut sum = 0;
+            'outer: for j in 0..i {
+                let mut k = 0;
+                while k < j {
+                    k += 1;
+                    if k == 3 {
+                        continue 'outer;
+                    }
+                    if k > 5 {
+                        break 'outer;
+                    }
+                }
+                let found = 'block: {
+                    if j % 2 == 0 {
+                        break 'block true;
+                    }
+                    false
+                };
+                if found {
+                    continue;
+                }
+                ff!(crayfish::place::here(), count(j));
+                let mut m = j;
+                sum += loop {
+                    if m % 3 == 0 {
+                        break m;
+                    }
+                    m += 1;
+                };
+            }
+            sum
+        };
+    }
+    total
+}
+
+#[activity]
+async fn overlap_spmd(n: usize) -> Option<usize> {
+    let counting = finish_async!(mode = "spmd", {
+        ff!(crayfish::place::here(), count(n));
+        n
+    });
+    let crayfish::collecting::Sum(total) =
+        finish_collect!(mode = "local", crayfish::collecting::Sum<usize>, {
+            if n == 0 {
+                return None;
+            }
+            offer!(crayfish::collecting::Sum(n));
+        });
+    let waited = finish!(mode = "here", {
+        ff!(crayfish::place::here(), count(n));
+        n
+    });
+    Some(counting.await + total + waited)
+}
+
+#[activity]
+async fn recover(n: usize) -> usize {
+    let done = try_finish!(mode = "resilient", {
+        ff!(crayfish::place::here(), count(n));
+        n
+    });
+    match done {
+        Ok(n) => n,
+        Err(crayfish::runtime::FinishError::DeadPlace(e)) => e.lost().len(),
+        Err(e) => panic!("{}", e),
+    }
+}
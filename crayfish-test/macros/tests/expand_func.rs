@@ -3,7 +3,7 @@ use crayfish_macros::*;
 use futures::Future;
 use futures::FutureExt;
 
-#[activity]
+#[activity(at_each)]
 #[allow(unused_variables)]
 async fn baz() -> i32 {
     123
@@ -24,6 +24,15 @@ async fn foo(a: i32, b: i64, c: Vec<usize>, d: String) -> i32 {
     ret.await
 }
 
+#[activity]
+#[allow(unused_variables)]
+async fn try_foo(a: i32) -> i32 {
+    match try_at!(crayfish::place::here(), baz()).await {
+        Ok(ret) => ret,
+        Err(e) => e.message().len() as i32,
+    }
+}
+
 mod inside{
     extern crate crayfish as duang;
     #[crayfish_macros::activity(crate="duang")]
@@ -33,7 +42,7 @@ mod inside{
     }
 }
 
-#[activity]
+#[activity(at_each)]
 #[allow(unused_variables)]
 async fn bar_box(a: i32, b: i64, c: Vec<usize>, d: String) -> usize {
     if a == 0 {
@@ -59,3 +68,66 @@ fn no_infer_ret(a: i32) -> impl Fut<Output=Vec<usize>>{
         vec![123]
     }
 }
+
+#[activity]
+async fn tagged(depth: usize) -> Option<String> {
+    if depth == 0 {
+        crayfish::context::get::<String>()
+    } else {
+        crayfish::context::set(format!("job-{}", depth));
+        at!(crayfish::place::here(), tagged(depth - 1)).await
+    }
+}
+
+// parameters named like the locals of the relay activity of at_each!
+#[activity(at_each)]
+async fn relay_locals(places: usize, args: usize, subtrees: usize, subtree: usize) -> usize {
+    places + args + subtrees + subtree
+}
+
+#[activity(at_each)]
+async fn relay_locals_rest(root: usize, here: usize, rets: usize) -> usize {
+    root + here + rets
+}
+
+// each place gets a participant of its own
+#[activity(at_each)]
+async fn in_phase(mut clock: crayfish::clock::Clock) -> u64 {
+    clock.advance().await;
+    clock.phase()
+}
+
+#[activity]
+async fn broadcast(n: usize) -> usize {
+    let group = crayfish::place::PlaceGroup::new(crayfish::place::world_size());
+    let sums: Vec<usize> = at_each!(group, bar_box(1, 2, vec![n], String::from("x"))).await;
+    at_each!(vec![crayfish::place::here()], baz()).await;
+    at_each!(vec![crayfish::place::here()], relay_locals(1, 2, 3, 4)).await;
+    at_each!(vec![crayfish::place::here()], relay_locals_rest(5, 6, 7)).await;
+    at_each!(vec![crayfish::place::here()], in_phase(crayfish::clock::Clock::new())).await;
+    sums.into_iter().sum()
+}
+
+#[activity]
+async fn lookup(table: crayfish::shared::GlobalRef<std::sync::Mutex<Vec<usize>>>, i: usize) -> usize {
+    at_home!(table, |t: &std::sync::Mutex<Vec<usize>>| -> () {
+        t.lock().unwrap().push(1)
+    })
+    .await;
+    // every expansion of at_home! is an activity of its own
+    macro_rules! push_at_home {
+        ($table:expr) => {
+            at_home!($table, |t: &std::sync::Mutex<Vec<usize>>| -> () {
+                t.lock().unwrap().push(2)
+            })
+            .await
+        };
+    }
+    push_at_home!(&table);
+    push_at_home!(&table);
+    at_home!(&table, |t: &std::sync::Mutex<Vec<usize>>| -> usize {
+        t.lock().unwrap().len()
+    })
+    .await
+        + i
+}
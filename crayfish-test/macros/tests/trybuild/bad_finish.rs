@@ -5,10 +5,9 @@ pub fn main() {
 
     let _ = async {
         finish!{
-            let a = Some(1);
-            let b = a.unwrap()?;
-            println("{}", b);
+            let a: Result<i32, String> = Ok(1);
+            let b = a?;
+            println!("{}", b);
         }
     };
 }
-
@@ -0,0 +1,19 @@
+use crayfish_macros::*;
+
+
+pub fn main() {
+
+    let _ = async {
+        for i in 0..2 {
+            finish!{
+                if i == 0 {
+                    continue;
+                }
+                for _ in 0..i {
+                    break;
+                }
+                break;
+            }
+        }
+    };
+}
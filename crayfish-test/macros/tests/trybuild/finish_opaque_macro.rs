@@ -0,0 +1,18 @@
+use crayfish_macros::*;
+
+fn parse(s: &str) -> Result<usize, std::num::ParseIntError> {
+    s.parse()
+}
+
+pub fn main() {
+
+    let _ = async {
+        finish!{
+            println!("{}", parse("1")?);
+            assert!(parse("2")? > 1);
+            vec![parse("3")?; 2];
+            println!("{:?}", parse("4").map(|n| n + 1));
+        }
+        Ok::<(), std::num::ParseIntError>(())
+    };
+}
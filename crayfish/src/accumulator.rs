@@ -0,0 +1,368 @@
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::collecting::Reducer;
+use crate::place;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::remote::ReplyTo;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::AddAssign;
+use std::ops::Deref;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+// X10 style accumulators. An add is combined into the partial value of its place without any
+// message. The home place of the accumulator pulls the partial values of all places and
+// combines them on result, so the result covers every add that happens before, e.g. adds of
+// activities under a finish that has completed. Partial values are not combined when a finish
+// completes; use finish_collect! for reductions over the activities of a finish.
+
+/// How values added to an accumulator are combined
+pub trait AccumulateOp<T>: 'static {
+    fn combine(acc: &mut T, value: T);
+}
+
+/// Sum of added values, starting from zero
+pub struct SumOp;
+
+impl<T: AddAssign> AccumulateOp<T> for SumOp {
+    fn combine(acc: &mut T, value: T) {
+        *acc += value;
+    }
+}
+
+/// Maximum of added values, starting from a value no greater than any of them
+pub struct MaxOp;
+
+impl<T: PartialOrd> AccumulateOp<T> for MaxOp {
+    fn combine(acc: &mut T, value: T) {
+        if value > *acc {
+            *acc = value;
+        }
+    }
+}
+
+/// Minimum of added values, starting from a value no less than any of them
+pub struct MinOp;
+
+impl<T: PartialOrd> AccumulateOp<T> for MinOp {
+    fn combine(acc: &mut T, value: T) {
+        if value < *acc {
+            *acc = value;
+        }
+    }
+}
+
+/// Reduce added values by their `Reducer`, starting from the default
+pub struct ReduceOp;
+
+impl<R: Reducer> AccumulateOp<R> for ReduceOp {
+    fn combine(acc: &mut R, value: R) {
+        acc.reduce(value);
+    }
+}
+
+type AccumulatorId = (Place, usize); // home and id at home
+
+// the partial value at this place, with the type erased for the pull handler
+trait Partial: Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_bytes(self: Box<Self>) -> Vec<u8>;
+}
+
+impl<T: RemoteSend> Partial for T {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn into_bytes(self: Box<Self>) -> Vec<u8> {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &*self).expect("Failed to serialize partial value");
+        bytes
+    }
+}
+
+static NEXT_ACCUMULATOR_ID: AtomicUsize = AtomicUsize::new(0);
+static PARTIALS: Lazy<Mutex<FxHashMap<AccumulatorId, Box<dyn Partial>>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn take_partial(id: AccumulatorId) -> Option<Vec<u8>> {
+    PARTIALS.lock().remove(&id).map(|p| p.into_bytes())
+}
+
+/// An accumulator with its home at the place creating it, where its result is. Activities at
+/// any place add to it by its `global_ref`. Values of T are combined by Op, starting from the
+/// zero of Op.
+///
+/// Dropping it frees the partial values of every place, so drop it only after the adding
+/// activities, e.g. after their finish. Adds after that are lost.
+pub struct Accumulator<T, Op = SumOp> {
+    global: AccumulatorRef<T, Op>,
+    places: usize,
+}
+
+/// A reference to an accumulator, which can be sent to any place to add to it
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "T: RemoteSend")]
+pub struct AccumulatorRef<T, Op = SumOp> {
+    id: AccumulatorId,
+    zero: T,
+    _op: PhantomData<fn(Op)>,
+}
+
+impl<T: Clone, Op> Clone for AccumulatorRef<T, Op> {
+    fn clone(&self) -> Self {
+        AccumulatorRef {
+            id: self.id,
+            zero: self.zero.clone(),
+            _op: PhantomData,
+        }
+    }
+}
+
+impl<T, Op> fmt::Debug for AccumulatorRef<T, Op> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccumulatorRef")
+            .field("home", &self.id.0)
+            .field("id", &self.id.1)
+            .finish()
+    }
+}
+
+impl<T: RemoteSend, Op: 'static> RemoteSend for AccumulatorRef<T, Op> {
+    crate::impl_body! {}
+}
+
+impl<T, Op> Accumulator<T, Op>
+where
+    T: RemoteSend + Clone,
+    Op: AccumulateOp<T>,
+{
+    /// an accumulator at this place. Zero is the identity of Op, e.g. 0 for sum
+    pub fn new(zero: T) -> Self {
+        Self::with_places(zero, place::world_size())
+    }
+
+    fn with_places(zero: T, places: usize) -> Self {
+        let id = NEXT_ACCUMULATOR_ID.fetch_add(1, Ordering::Relaxed);
+        Accumulator {
+            global: AccumulatorRef {
+                id: (here(), id),
+                zero,
+                _op: PhantomData,
+            },
+            places,
+        }
+    }
+
+    pub fn global_ref(&self) -> AccumulatorRef<T, Op> {
+        self.global.clone()
+    }
+
+    // the partial values of other places, taken from them
+    fn pull(&self) -> Vec<BoxFuture<'static, Option<Vec<u8>>>> {
+        let id = self.id;
+        (0..self.places as Place)
+            .filter(|p| *p != here())
+            .map(|p| {
+                remote::request(p, PULL_FN_ID, |builder| builder.arg(id))
+                    .map(|mut e| e.arg::<Option<Vec<u8>>>())
+                    .boxed()
+            })
+            .collect()
+    }
+
+    /// Combine the partial values of all places and return the result. Await it after the
+    /// adding activities, e.g. after their finish
+    pub fn result(&self) -> BoxFuture<'static, T> {
+        let pulls = self.pull();
+        let this = self.global_ref();
+        async move {
+            for partial in futures::future::join_all(pulls).await {
+                this.combine_bytes(partial);
+            }
+            let mut partials = PARTIALS.lock();
+            match partials.get_mut(&this.id) {
+                Some(partial) => partial.as_any_mut().downcast_mut::<T>().unwrap().clone(),
+                None => this.zero.clone(),
+            }
+        }
+        .boxed()
+    }
+
+    /// forget the partial values of all places, so the result starts from zero again
+    pub fn reset(&self) -> BoxFuture<'static, ()> {
+        PARTIALS.lock().remove(&self.id);
+        futures::future::join_all(self.pull()).map(|_| ()).boxed()
+    }
+}
+
+impl<T, Op> Deref for Accumulator<T, Op> {
+    type Target = AccumulatorRef<T, Op>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.global
+    }
+}
+
+impl<T, Op> Drop for Accumulator<T, Op> {
+    fn drop(&mut self) {
+        let id = self.global.id;
+        PARTIALS.lock().remove(&id);
+        for place in (0..self.places as Place).filter(|p| *p != here()) {
+            remote::send(place, FREE_FN_ID, |builder| builder.arg(id));
+        }
+    }
+}
+
+impl<T, Op> AccumulatorRef<T, Op>
+where
+    T: RemoteSend + Clone,
+    Op: AccumulateOp<T>,
+{
+    pub fn home(&self) -> Place {
+        self.id.0
+    }
+
+    /// combine the value into the partial value of this place
+    pub fn add(&self, value: T) {
+        let mut partials = PARTIALS.lock();
+        let partial = partials
+            .entry(self.id)
+            .or_insert_with(|| Box::new(self.zero.clone()));
+        Op::combine(partial.as_any_mut().downcast_mut::<T>().unwrap(), value);
+    }
+
+    fn combine_bytes(&self, bytes: Option<Vec<u8>>) {
+        if let Some(bytes) = bytes {
+            let value: T = deserialize_from(&bytes[..]).expect("Failed to deserialize partial");
+            self.add(value);
+        }
+    }
+}
+
+const PULL_FN_ID: FunctionLabel = runtime_meta::function_id(module_path!(), "partial_pull_handler");
+
+fn partial_pull_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let reply_to: ReplyTo = e.arg();
+    let id: AccumulatorId = e.arg();
+    let partial = take_partial(id);
+    remote::reply(reply_to, |builder| builder.arg(partial));
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        PULL_FN_ID,
+        partial_pull_handler,
+        String::from("partial_pull_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+const FREE_FN_ID: FunctionLabel = runtime_meta::function_id(module_path!(), "partial_free_handler");
+
+fn partial_free_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let id: AccumulatorId = e.arg();
+    PARTIALS.lock().remove(&id);
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        FREE_FN_ID,
+        partial_free_handler,
+        String::from("partial_free_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::collecting::Max;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use crate::runtime;
+    use futures::executor;
+
+    #[test]
+    fn test_ops() {
+        let mut v = 1;
+        SumOp::combine(&mut v, 2);
+        assert_eq!(v, 3);
+        MaxOp::combine(&mut v, 2);
+        assert_eq!(v, 3);
+        MinOp::combine(&mut v, 2);
+        assert_eq!(v, 2);
+        let mut m = Max(None);
+        ReduceOp::combine(&mut m, Max(Some(4)));
+        assert_eq!(m, Max(Some(4)));
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let _a = TestGuardForStatic::new();
+        // no other places to pull from
+        let acc = Accumulator::<u64>::with_places(0, 0);
+        assert_eq!(acc.home(), TEST_HERE);
+        assert_eq!(executor::block_on(acc.result()), 0);
+        acc.add(1);
+        acc.global_ref().add(2);
+        // a partial value pulled from another place
+        let other = Accumulator::<u64>::with_places(0, 0);
+        other.add(10);
+        acc.combine_bytes(take_partial(other.id));
+        acc.combine_bytes(take_partial(other.id));
+        assert_eq!(executor::block_on(acc.result()), 13);
+        executor::block_on(acc.reset());
+        assert_eq!(executor::block_on(acc.result()), 0);
+
+        let max = Accumulator::<f64, MaxOp>::with_places(f64::NEG_INFINITY, 0);
+        max.add(-1.5);
+        max.add(-3.0);
+        assert_eq!(executor::block_on(max.result()), -1.5);
+    }
+
+    #[test]
+    fn test_free_on_drop() {
+        let _a = TestGuardForStatic::new();
+        runtime::init_task_item_channels();
+        let sent = runtime::take_task_item_receiver();
+        let acc = Accumulator::<u64>::with_places(0, TEST_HERE as usize + 1);
+        let id = acc.id;
+        acc.add(1);
+        drop(acc);
+        assert!(!PARTIALS.lock().contains_key(&id));
+        // the partial of another place, freed by the message to it
+        PARTIALS.lock().insert(id, Box::new(2u64));
+        let frees: Vec<_> = sent.try_iter().collect();
+        assert_eq!(frees.len(), TEST_HERE as usize);
+        for free in frees {
+            executor::block_on(partial_free_handler(*free));
+        }
+        assert!(!PARTIALS.lock().contains_key(&id));
+    }
+}
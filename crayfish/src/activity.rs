@@ -1,6 +1,12 @@
 use crate::args::RemoteSend;
+use crate::collecting::PartialReduction;
+use crate::context::TaskContext;
 pub use crate::global_id::ActivityId;
+use crate::meta_data;
 use crate::place::Place;
+use crate::place::HERE_STATIC;
+use crate::runtime_meta::get_func_table;
+use crate::runtime_meta::FunctionMetaData;
 use crate::serialization::deserialize_from;
 use crate::serialization::serialize_into;
 use once_cell::sync::Lazy;
@@ -15,7 +21,10 @@ use serde::Serialize;
 use serde::Serializer;
 use std::any::Any;
 use std::any::TypeId;
+use std::backtrace::Backtrace;
+use std::backtrace::BacktraceStatus;
 use std::cell::Cell;
+use std::cell::RefCell;
 use std::fmt;
 use std::marker::PhantomData;
 use std::ops::Deref;
@@ -24,32 +33,163 @@ use std::sync::Mutex;
 
 extern crate serde;
 
-pub type PanicPayload = String;
 pub type FunctionLabel = u64; // function label is line
 pub type ActivityResult = std::result::Result<(), PanicPayload>;
 
-fn cast_panic_payload(payload: Box<dyn Any + Send + 'static>) -> PanicPayload {
+thread_local! {
+    // backtrace of the last panic on this thread, set by the panic hook
+    static PANIC_BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// record backtraces of panicking activities, which would be lost after unwinding
+pub(crate) fn init_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            PANIC_BACKTRACE.with(|b| *b.borrow_mut() = Some(backtrace.to_string()));
+        }
+        default_hook(info);
+    }));
+}
+
+/// What a panicking activity reports to its waiters
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct PanicPayload {
+    message: String,
+    place: Option<Place>, // where the activity was executing
+    hostname: String,
+    backtrace: Option<String>,
+}
+
+impl PanicPayload {
+    pub(crate) fn new(message: String) -> Self {
+        PanicPayload {
+            message,
+            place: HERE_STATIC.get().copied(),
+            hostname: meta_data::HOSTNAME.clone(),
+            backtrace: PANIC_BACKTRACE.with(|b| b.borrow_mut().take()),
+        }
+    }
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+    pub fn place(&self) -> Option<Place> {
+        self.place
+    }
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+    /// only captured if enabled by RUST_BACKTRACE or RUST_LIB_BACKTRACE
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_deref()
+    }
+}
+
+/// An activity on the path from the panic to the waiter
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct ActivityFrame {
+    fn_id: FunctionLabel,
+    spawned_place: Place,
+}
+
+impl ActivityFrame {
+    pub(crate) fn new(fn_id: FunctionLabel, spawned_place: Place) -> Self {
+        ActivityFrame {
+            fn_id,
+            spawned_place,
+        }
+    }
+    pub fn fn_id(&self) -> FunctionLabel {
+        self.fn_id
+    }
+    pub fn spawned_place(&self) -> Place {
+        self.spawned_place
+    }
+    /// name and location of the activity function, if it is registered in this binary
+    pub fn meta_data(&self) -> Option<&'static FunctionMetaData> {
+        get_func_table().get(&self.fn_id)
+    }
+}
+
+impl fmt::Display for ActivityFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.meta_data() {
+            Some(m) => write!(
+                f,
+                "{} at {}:{} in {}",
+                m.name(),
+                m.file(),
+                m.line(),
+                m.module_path()
+            )?,
+            None => write!(f, "<unknown function {}>", self.fn_id)?,
+        }
+        write!(f, ", spawned by place {}", self.spawned_place)
+    }
+}
+
+/// The error returned to the waiting activity when a remote activity panics
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct RemotePanic {
+    payload: PanicPayload,
+    frames: Vec<ActivityFrame>, // start from the panicking activity
+}
+
+impl RemotePanic {
+    pub(crate) fn new(payload: PanicPayload, frames: Vec<ActivityFrame>) -> Self {
+        RemotePanic { payload, frames }
+    }
+    /// the panic message of the remote activity
+    pub fn message(&self) -> &str {
+        self.payload.message()
+    }
+    pub fn payload(&self) -> &PanicPayload {
+        &self.payload
+    }
+    /// the panicking activity, if known
+    pub fn activity(&self) -> Option<&ActivityFrame> {
+        self.frames.first()
+    }
+    /// the chain of activities from the panicking one to the outermost one
+    pub fn frames(&self) -> &[ActivityFrame] {
+        &self.frames[..]
+    }
+}
+
+impl fmt::Display for RemotePanic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "remote activity panicked")?;
+        if let Some(place) = self.payload.place {
+            write!(f, " at place {}", place)?;
+        }
+        write!(f, " ({}): {}", self.payload.hostname, self.payload.message)?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            write!(f, "\n  {}: {}", i, frame)?;
+        }
+        if let Some(backtrace) = self.payload.backtrace() {
+            write!(f, "\nremote backtrace:\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RemotePanic {}
+
+impl RemoteSend for RemotePanic {
+    crate::impl_body! {}
+}
+
+pub(crate) fn cast_panic_payload(payload: Box<dyn Any + Send + 'static>) -> PanicPayload {
     let id = (*payload).type_id();
-    if id == TypeId::of::<String>() {
+    let message = if id == TypeId::of::<String>() {
         *payload.downcast::<String>().unwrap()
     } else if id == TypeId::of::<&str>() {
         String::from(*payload.downcast::<&str>().unwrap())
     } else {
         String::from("Unsupport payload type")
-    }
-}
-
-pub fn copy_panic_payload(
-    payload: &Box<dyn Any + Send + 'static>,
-) -> Box<dyn Any + Send + 'static> {
-    let id = (**payload).type_id();
-    if id == TypeId::of::<String>() {
-        Box::new(payload.downcast_ref::<String>().unwrap().clone())
-    } else if id == TypeId::of::<&str>() {
-        Box::new(String::from(*payload.downcast_ref::<&str>().unwrap()))
-    } else {
-        Box::new(String::from("Unsupport payload type"))
-    }
+    };
+    PanicPayload::new(message)
 }
 
 pub(crate) trait SquashableObject: Any + Send + 'static {
@@ -553,6 +693,8 @@ fn u64_to_type_id(data: u64) -> TypeId {
 pub struct ReturnInfo {
     result: ActivityResult,
     sub_activities: Vec<ActivityId>,
+    destinations: Vec<Place>, // places of sub_activities, for resilient finishes only
+    collected: Vec<PartialReduction>, // partial results of collecting finishes
 }
 
 #[derive(Default, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -562,6 +704,7 @@ pub struct StrippedTaskItem {
     activity_id: ActivityId,
     ret: Option<ReturnInfo>,
     waited: bool, // indicating this activity is waited on the spawned place
+    context: TaskContext,
     args: Vec<u8>,
 }
 
@@ -573,6 +716,7 @@ impl fmt::Debug for StrippedTaskItem {
             .field("activity_id", &self.activity_id)
             .field("ret", &self.ret)
             .field("waited", &self.waited)
+            .field("context", &self.context)
             .finish()
     }
 }
@@ -646,6 +790,17 @@ impl TaskItemExtracter {
     pub fn sub_activities(&mut self) -> Vec<ActivityId> {
         std::mem::take(&mut self.item.inner.ret.as_mut().unwrap().sub_activities)
     }
+    /// should be called before ret_xxx
+    pub fn destinations(&mut self) -> Vec<Place> {
+        std::mem::take(&mut self.item.inner.ret.as_mut().unwrap().destinations)
+    }
+    /// should be called before ret_xxx
+    pub fn collected(&mut self) -> Vec<PartialReduction> {
+        std::mem::take(&mut self.item.inner.ret.as_mut().unwrap().collected)
+    }
+    pub fn context(&mut self) -> TaskContext {
+        std::mem::take(&mut self.item.inner.context)
+    }
     pub fn fn_id(&self) -> FunctionLabel {
         self.item.inner.fn_id
     }
@@ -685,6 +840,10 @@ impl TaskItemBuilder {
         self.item.inner.waited = true;
     }
 
+    pub fn context(&mut self, context: TaskContext) {
+        self.item.inner.context = context;
+    }
+
     pub fn arg<T: RemoteSend>(&mut self, t: T) {
         if T::is_squashable() {
             let label = self.next_label();
@@ -706,15 +865,20 @@ impl TaskItemBuilder {
         self.item.inner.ret = Some(ReturnInfo {
             result,
             sub_activities: vec![],
+            destinations: vec![],
+            collected: vec![],
         });
     }
     pub fn ret<T: RemoteSend>(&mut self, result: std::thread::Result<T>) {
+        self.ret_result(result.map_err(cast_panic_payload));
+    }
+    pub fn ret_result<T: RemoteSend>(&mut self, result: Result<T, PanicPayload>) {
         let result = match result {
             Ok(ret) => {
                 self.arg::<T>(ret);
                 Ok(())
             }
-            Err(payload) => Err(cast_panic_payload(payload)),
+            Err(payload) => Err(payload),
         };
         self.set_result(result);
     }
@@ -730,6 +894,52 @@ impl TaskItemBuilder {
             a_ids,
         );
     }
+    pub fn destinations(&mut self, places: Vec<Place>) {
+        self.item
+            .inner
+            .ret
+            .as_mut()
+            .expect("result must be set before destinations")
+            .destinations = places;
+    }
+    pub fn collected(&mut self, partials: Vec<PartialReduction>) {
+        self.item
+            .inner
+            .ret
+            .as_mut()
+            .expect("result must be set before collected")
+            .collected = partials;
+    }
+}
+
+thread_local! {
+    // places the arguments being serialized are delivered to, empty for a single place
+    static DELIVERIES: RefCell<Vec<Place>> = const { RefCell::new(Vec::new()) };
+}
+
+/// arguments of at_each!, serialized once for all the places. Handles in them get the places
+/// from `delivery_places`, so each place gets its own copy
+pub fn serialize_args<T: Serialize>(args: &T, places: &[Place]) -> Vec<u8> {
+    let mut bytes = vec![];
+    let outer = DELIVERIES.with(|d| d.replace(places.to_vec()));
+    let serialized = serialize_into(&mut bytes, args);
+    DELIVERIES.with(|d| d.replace(outer));
+    serialized.expect("Failed to serialize function argument");
+    bytes
+}
+
+/// places the value being serialized is delivered to, empty if a single place
+pub(crate) fn delivery_places() -> Vec<Place> {
+    DELIVERIES.with(|d| d.borrow().clone())
+}
+
+/// number of places the value being serialized is delivered to
+pub(crate) fn deliveries() -> usize {
+    DELIVERIES.with(|d| d.borrow().len().max(1))
+}
+
+pub fn deserialize_args<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> T {
+    deserialize_from(bytes).expect("Failed to deserialize function argument")
 }
 
 fn squash_one_type(
@@ -852,11 +1062,14 @@ pub mod test {
                     activity_id: ActivityId::from(rng.gen::<usize>()),
                     waited: false,
                     ret: Some(ReturnInfo {
-                        result: Err(s),
+                        result: Err(PanicPayload::new(s)),
                         sub_activities: (0..8)
                             .map(|_| ActivityId::from(rng.gen::<usize>()))
                             .collect(),
+                        destinations: vec![],
+                        collected: vec![],
                     }),
+                    context: TaskContext::default(),
                     args: (0..64).map(|_| rng.gen()).collect(),
                 },
                 squashable: vec![],
@@ -1163,7 +1376,7 @@ pub mod test {
         let mut builder = TaskItemBuilder::new(fn_id, place, activity_id);
         builder.ret(result);
         let mut ex = TaskItemExtracter::new(builder.build());
-        assert_eq!(ex.ret::<usize>().unwrap_err(), msg);
+        assert_eq!(ex.ret::<usize>().unwrap_err().message(), msg);
 
         // ret Err
         let msg = String::from("123125435");
@@ -1172,7 +1385,7 @@ pub mod test {
         let mut builder = TaskItemBuilder::new(fn_id, place, activity_id);
         builder.ret(result);
         let mut ex = TaskItemExtracter::new(builder.build());
-        assert_eq!(ex.ret::<A>().unwrap_err(), msg);
+        assert_eq!(ex.ret::<A>().unwrap_err().message(), msg);
     }
 
     #[test]
@@ -1183,7 +1396,7 @@ pub mod test {
         panic::set_hook(Box::new(|_| {}));
         let result = panic::catch_unwind(|| panic!("12345"));
         let _ = panic::take_hook();
-        assert_eq!(cast_panic_payload(result.unwrap_err()), "12345");
+        assert_eq!(cast_panic_payload(result.unwrap_err()).message(), "12345");
 
         let mut rng = thread_rng();
         let value: usize = rng.gen();
@@ -1195,11 +1408,24 @@ pub mod test {
         });
         let _ = panic::take_hook();
         assert_eq!(
-            cast_panic_payload(result.unwrap_err()),
+            cast_panic_payload(result.unwrap_err()).message(),
             format!("1{}", value)
         );
     }
 
+    #[test]
+    pub fn test_remote_panic_display() {
+        let payload = PanicPayload::new(String::from("panic here"));
+        let frames = vec![ActivityFrame::new(3, 1), ActivityFrame::new(2, 0)];
+        let remote_panic = RemotePanic::new(payload, frames);
+        assert_eq!(remote_panic.activity().unwrap().fn_id(), 3);
+        assert_eq!(remote_panic.payload().hostname(), &*meta_data::HOSTNAME);
+        let rendered = remote_panic.to_string();
+        assert!(rendered.contains("panic here"));
+        assert!(rendered.contains("0: <unknown function 3>, spawned by place 1"));
+        assert!(rendered.contains("1: <unknown function 2>, spawned by place 0"));
+    }
+
     #[test]
     pub fn test_serialzie_squashed_map() {
         let _ = TestGuardForStatic::new();
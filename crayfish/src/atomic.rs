@@ -0,0 +1,147 @@
+use std::ops::Deref;
+use std::ops::DerefMut;
+use tokio::sync::Mutex;
+use tokio::sync::MutexGuard;
+use tokio::sync::Notify;
+
+/// Place-local state for conditional atomic sections, see `atomic!` and `when!`.
+///
+/// Waiting for the lock or a condition suspends the calling activity instead of the worker
+/// thread. Atomicity is per state, not per place as X10.
+#[derive(Debug, Default)]
+pub struct Atomic<T> {
+    value: Mutex<T>,
+    changed: Notify,
+}
+
+/// Exclusive access to the state. Waiters of `when!` are woken on drop if it is modified
+pub struct AtomicGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    changed: &'a Notify,
+    dirty: bool,
+}
+
+impl<T> Atomic<T> {
+    pub fn new(value: T) -> Self {
+        Atomic {
+            value: Mutex::new(value),
+            changed: Notify::new(),
+        }
+    }
+
+    /// start an atomic section
+    pub async fn lock(&self) -> AtomicGuard<'_, T> {
+        AtomicGuard {
+            guard: self.value.lock().await,
+            changed: &self.changed,
+            dirty: false,
+        }
+    }
+
+    /// start an atomic section once the condition holds
+    pub async fn wait_until<F>(&self, mut condition: F) -> AtomicGuard<'_, T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        loop {
+            // created before checking, so no change after the check is missed
+            let changed = self.changed.notified();
+            let guard = self.lock().await;
+            if condition(&guard) {
+                break guard;
+            }
+            drop(guard); // not modified, wake nobody
+            changed.await;
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<'a, T> Deref for AtomicGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for AtomicGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.dirty = true;
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for AtomicGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.dirty {
+            self.changed.notify_waiters();
+        }
+    }
+}
+
+/// atomic!(v in state => body);
+///
+/// Execute body atomically on an `Atomic` state, with v bound to the mutable state.
+#[macro_export]
+macro_rules! atomic {
+    ($v:ident in $state:expr => $body:expr) => {{
+        let mut __crayfish_atomic_guard = $state.lock().await;
+        let $v = &mut *__crayfish_atomic_guard;
+        $body
+    }};
+}
+
+/// when!(v in state, condition => body);
+///
+/// Suspend until the condition on the state holds, then execute body atomically. The
+/// condition is checked again after each atomic section modifying the state.
+#[macro_export]
+macro_rules! when {
+    ($v:ident in $state:expr, $cond:expr => $body:expr) => {{
+        let mut __crayfish_atomic_guard = $state.wait_until(|$v| $cond).await;
+        let $v = &mut *__crayfish_atomic_guard;
+        $body
+    }};
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_atomic_when() {
+        let rt = crate::executor::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let state = Arc::new(Atomic::new(vec![]));
+            let mut waiters = vec![];
+            for i in 0..4usize {
+                let state = state.clone();
+                // each waiter takes its turn
+                waiters.push(crate::spawn(async move {
+                    crate::when!(v in state, v.len() == i => v.push(i));
+                }));
+            }
+            let state_for_last = state.clone();
+            let last = crate::spawn(async move {
+                crate::when!(v in state_for_last, v.len() == 4 => v.iter().sum::<usize>())
+            });
+            for w in waiters {
+                w.await.unwrap();
+            }
+            assert_eq!(last.await.unwrap(), 6);
+            let len = crate::atomic!(v in state => {
+                v.push(4);
+                v.len()
+            });
+            assert_eq!(len, 5);
+        });
+    }
+}
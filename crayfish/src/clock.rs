@@ -0,0 +1,567 @@
+use crate::activity;
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::logging::*;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use rustc_hash::FxHashSet;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use tokio::sync::watch;
+
+// X10 style clock. The phase is tracked at the home place of the clock. Each Clock handle is a
+// registered participant: cloning a handle registers a new one, which is how an activity is
+// registered when spawned with the clone. Dropping a handle resigns it.
+//
+// A participant announces handles it cloned in a phase together with its own advance or resign
+// message. So the home never completes a phase before it knows all the participants of it,
+// whatever order the messages arrive in.
+//
+// A handle sent by at_each! is serialized once and deserialized at each place of the group, so
+// it registers a participant for each place like a clone, numbered by the place.
+
+type Phase = u64;
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+struct ClockId {
+    home: Place,
+    local_id: u64,
+}
+
+#[derive(Copy, Clone, Debug, Hash, Eq, PartialEq, Serialize, Deserialize)]
+struct ParticipantId {
+    place: Place,
+    local_id: u64,
+}
+
+static NEXT_LOCAL_ID: AtomicU64 = AtomicU64::new(0);
+
+fn next_local_id() -> u64 {
+    NEXT_LOCAL_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl ParticipantId {
+    fn new() -> Self {
+        ParticipantId {
+            place: here(),
+            local_id: next_local_id(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Status {
+    Pending,
+    Arrived,
+    Resigned,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ClockMessage {
+    Advance {
+        clock: ClockId,
+        phase: Phase,
+        participant: ParticipantId,
+        registered: Vec<ParticipantId>,
+    },
+    Resign {
+        clock: ClockId,
+        phase: Phase,
+        participant: ParticipantId,
+        registered: Vec<ParticipantId>,
+    },
+    PhaseDone {
+        clock: ClockId,
+        phase: Phase,
+    },
+}
+
+impl RemoteSend for ClockMessage {
+    crate::impl_body! {}
+}
+
+// phase state of a clock, only exists at its home place
+#[derive(Debug, Default)]
+struct ClockHome {
+    phase: Phase,
+    participants: FxHashMap<ParticipantId, Status>,
+    waiting_places: FxHashSet<Place>,
+}
+
+impl ClockHome {
+    fn new(creator: ParticipantId) -> Self {
+        let mut home = ClockHome::default();
+        home.participants.insert(creator, Status::Pending);
+        home
+    }
+
+    fn update(
+        &mut self,
+        phase: Phase,
+        participant: ParticipantId,
+        registered: Vec<ParticipantId>,
+        status: Status,
+    ) {
+        debug_assert_eq!(phase, self.phase);
+        for p in registered {
+            // might already arrive or resign
+            self.participants.entry(p).or_insert(Status::Pending);
+        }
+        self.participants.insert(participant, status);
+        if status == Status::Arrived {
+            self.waiting_places.insert(participant.place);
+        }
+    }
+
+    // return places to notify if current phase is complete
+    fn try_complete(&mut self) -> Option<(Phase, Vec<Place>)> {
+        if self.participants.values().any(|s| *s == Status::Pending) {
+            return None;
+        }
+        let phase = self.phase;
+        self.participants.retain(|_, s| *s != Status::Resigned);
+        for s in self.participants.values_mut() {
+            *s = Status::Pending;
+        }
+        self.phase += 1;
+        let places = self.waiting_places.drain().collect();
+        Some((phase, places))
+    }
+
+    fn is_dead(&self) -> bool {
+        self.participants.is_empty()
+    }
+}
+
+// completed phases seen at this place, shared by all local handles of a clock
+struct ClockLocal {
+    handles: usize,
+    completed: (watch::Sender<Phase>, watch::Receiver<Phase>),
+}
+
+static CLOCK_HOMES: Lazy<Mutex<FxHashMap<ClockId, ClockHome>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+static CLOCK_LOCALS: Lazy<Mutex<FxHashMap<ClockId, ClockLocal>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn local_handle_created(clock: ClockId, phase: Phase) {
+    let mut locals = CLOCK_LOCALS.lock();
+    let local = locals.entry(clock).or_insert_with(|| ClockLocal {
+        handles: 0,
+        completed: watch::channel(phase),
+    });
+    local.handles += 1;
+}
+
+fn local_handle_dropped(clock: ClockId) {
+    let mut locals = CLOCK_LOCALS.lock();
+    let local = locals.get_mut(&clock).unwrap();
+    local.handles -= 1;
+    if local.handles == 0 {
+        locals.remove(&clock);
+    }
+}
+
+const CLOCK_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "clock_message_handler");
+
+fn send_message(dst: Place, message: ClockMessage) {
+    if dst == here() {
+        handle_message(message);
+        return;
+    }
+    remote::send(dst, CLOCK_FN_ID, |builder| builder.arg(message));
+}
+
+fn handle_message(message: ClockMessage) {
+    let (clock, phase, participant, registered, status) = match message {
+        ClockMessage::PhaseDone { clock, phase } => {
+            // all local handles might have been dropped
+            if let Some(local) = CLOCK_LOCALS.lock().get(&clock) {
+                let _ = local.completed.0.send(phase + 1);
+            }
+            return;
+        }
+        ClockMessage::Advance {
+            clock,
+            phase,
+            participant,
+            registered,
+        } => (clock, phase, participant, registered, Status::Arrived),
+        ClockMessage::Resign {
+            clock,
+            phase,
+            participant,
+            registered,
+        } => (clock, phase, participant, registered, Status::Resigned),
+    };
+    debug_assert_eq!(clock.home, here());
+    let mut homes = CLOCK_HOMES.lock();
+    let home = match homes.get_mut(&clock) {
+        Some(home) => home,
+        None => {
+            // a stale message must not take down the dispatch loop of the place
+            warn!(
+                "{:?} is already dead, drop {:?} of {:?}",
+                clock, status, participant
+            );
+            return;
+        }
+    };
+    home.update(phase, participant, registered, status);
+    if let Some((phase, places)) = home.try_complete() {
+        if home.is_dead() {
+            homes.remove(&clock);
+        }
+        drop(homes);
+        for place in places {
+            send_message(place, ClockMessage::PhaseDone { clock, phase });
+        }
+    }
+}
+
+fn clock_message_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    handle_message(e.arg());
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        CLOCK_FN_ID,
+        clock_message_handler,
+        String::from("clock_message_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+/// A clock to synchronize activities in phases, see the clock of X10.
+///
+/// Each handle is a registered participant. Pass a clone to an activity to register it. Every
+/// participant must either call `advance` or be dropped before the phase can complete.
+pub struct Clock {
+    id: ClockId,
+    participant: ParticipantId,
+    phase: Phase,
+    // handles cloned in current phase, announced with the next message
+    registered: RefCell<Vec<ParticipantId>>,
+    // serialized to another place, where the participant lives now
+    transferred: Cell<bool>,
+}
+
+impl Clock {
+    /// create a clock at here, the caller is registered
+    pub fn new() -> Self {
+        let id = ClockId {
+            home: here(),
+            local_id: next_local_id(),
+        };
+        let participant = ParticipantId::new();
+        CLOCK_HOMES.lock().insert(id, ClockHome::new(participant));
+        Self::from_parts(id, participant, 0, vec![])
+    }
+
+    fn from_parts(
+        id: ClockId,
+        participant: ParticipantId,
+        phase: Phase,
+        registered: Vec<ParticipantId>,
+    ) -> Self {
+        local_handle_created(id, phase);
+        Clock {
+            id,
+            participant,
+            phase,
+            registered: RefCell::new(registered),
+            transferred: Cell::new(false),
+        }
+    }
+
+    /// the current phase of this participant, starting from 0
+    pub fn phase(&self) -> u64 {
+        self.phase
+    }
+
+    /// wait until all participants have advanced the current phase
+    pub async fn advance(&mut self) {
+        let phase = self.phase;
+        let mut completed = CLOCK_LOCALS
+            .lock()
+            .get(&self.id)
+            .unwrap()
+            .completed
+            .1
+            .clone();
+        send_message(
+            self.id.home,
+            ClockMessage::Advance {
+                clock: self.id,
+                phase,
+                participant: self.participant,
+                registered: self.registered.take(),
+            },
+        );
+        while *completed.borrow() <= phase {
+            completed.changed().await.unwrap();
+        }
+        self.phase += 1;
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for Clock {
+    /// register a new participant in the current phase
+    fn clone(&self) -> Self {
+        let participant = ParticipantId::new();
+        self.registered.borrow_mut().push(participant);
+        Self::from_parts(self.id, participant, self.phase, vec![])
+    }
+}
+
+impl Drop for Clock {
+    fn drop(&mut self) {
+        local_handle_dropped(self.id);
+        if !self.transferred.get() {
+            send_message(
+                self.id.home,
+                ClockMessage::Resign {
+                    clock: self.id,
+                    phase: self.phase,
+                    participant: self.participant,
+                    registered: self.registered.take(),
+                },
+            );
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ClockWire {
+    id: ClockId,
+    participant: ParticipantId,
+    phase: Phase,
+    registered: Vec<ParticipantId>,
+    // the participant of each place is numbered from participant by the place
+    by_place: bool,
+}
+
+// the participant registered for the copy at place
+fn participant_by_place(base: ParticipantId, place: Place) -> ParticipantId {
+    ParticipantId {
+        local_id: base.local_id + place as u64,
+        ..base
+    }
+}
+
+impl Serialize for Clock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let places = activity::delivery_places();
+        let wire = match places.iter().max() {
+            None => {
+                // the participant moves to the place deserializing it
+                self.transferred.set(true);
+                ClockWire {
+                    id: self.id,
+                    participant: self.participant,
+                    phase: self.phase,
+                    registered: self.registered.take(),
+                    by_place: false,
+                }
+            }
+            Some(max) => {
+                // this participant stays, and resigns when the caller drops it
+                let base = ParticipantId {
+                    place: here(),
+                    local_id: NEXT_LOCAL_ID.fetch_add(*max as u64 + 1, Ordering::Relaxed),
+                };
+                self.registered
+                    .borrow_mut()
+                    .extend(places.iter().map(|p| participant_by_place(base, *p)));
+                ClockWire {
+                    id: self.id,
+                    participant: base,
+                    phase: self.phase,
+                    registered: vec![],
+                    by_place: true,
+                }
+            }
+        };
+        wire.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Clock {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let w = ClockWire::deserialize(deserializer)?;
+        let participant = match w.by_place {
+            true => participant_by_place(w.participant, here()),
+            false => w.participant,
+        };
+        Ok(Self::from_parts(w.id, participant, w.phase, w.registered))
+    }
+}
+
+impl RemoteSend for Clock {
+    crate::impl_body! {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::serialization::deserialize_from;
+    use crate::serialization::serialize_into;
+    use futures::executor;
+    use std::sync::Arc;
+
+    fn pid(local_id: u64) -> ParticipantId {
+        ParticipantId { place: 0, local_id }
+    }
+
+    fn pid_at(place: Place, local_id: u64) -> ParticipantId {
+        ParticipantId { place, local_id }
+    }
+
+    #[test]
+    fn test_clock_home_phases() {
+        let mut home = ClockHome::new(pid(0));
+        home.update(0, pid(0), vec![], Status::Arrived);
+        assert_eq!(home.try_complete(), Some((0, vec![0])));
+        home.update(1, pid(0), vec![], Status::Arrived);
+        assert_eq!(home.try_complete(), Some((1, vec![0])));
+        home.update(2, pid(0), vec![], Status::Resigned);
+        assert_eq!(home.try_complete(), Some((2, vec![])));
+        assert!(home.is_dead());
+    }
+
+    #[test]
+    fn test_clock_home_registered_arrives_first() {
+        let mut home = ClockHome::new(pid(0));
+        // child registered by 0 arrives before 0 announces it
+        home.update(0, pid_at(1, 1), vec![], Status::Arrived);
+        assert_eq!(home.try_complete(), None);
+        home.update(0, pid(0), vec![pid_at(1, 1), pid_at(2, 2)], Status::Arrived);
+        assert_eq!(home.try_complete(), None);
+        home.update(0, pid_at(2, 2), vec![], Status::Resigned);
+        let (phase, mut places) = home.try_complete().unwrap();
+        places.sort_unstable();
+        assert_eq!((phase, places), (0, vec![0, 1]));
+        // two participants left
+        home.update(1, pid_at(1, 1), vec![], Status::Arrived);
+        assert_eq!(home.try_complete(), None);
+        home.update(1, pid(0), vec![], Status::Resigned);
+        assert_eq!(home.try_complete(), Some((1, vec![1])));
+        assert!(!home.is_dead());
+    }
+
+    #[test]
+    fn test_clock_activities() {
+        let _a = TestGuardForStatic::new();
+        let mut clock = Clock::new();
+        let id = clock.id;
+        // one activity registered by a clone, another by a clone sent to it
+        let cloned = clock.clone();
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &clock.clone()).unwrap();
+        let sent: Clock = deserialize_from(&bytes[..]).unwrap();
+        let arrived = Arc::new(AtomicU64::new(0));
+        let activity = |mut clock: Clock, phases: u64| {
+            let arrived = arrived.clone();
+            async move {
+                for phase in 0..phases {
+                    arrived.fetch_add(1, Ordering::SeqCst);
+                    clock.advance().await;
+                    // nobody leaves a phase before all arrive at it
+                    assert!(arrived.load(Ordering::SeqCst) >= 2 * (phase + 1));
+                }
+            }
+        };
+        let mut first = activity(cloned, 2).boxed();
+        assert!((&mut first).now_or_never().is_none());
+        let mut second = activity(sent, 2).boxed();
+        assert!((&mut second).now_or_never().is_none());
+        // the phase completes once the owner advances too
+        let mut owner_advance = clock.advance().boxed();
+        assert!((&mut owner_advance).now_or_never().is_some());
+        drop(owner_advance);
+        assert_eq!(clock.phase(), 1);
+        executor::block_on(async {
+            futures::join!(first, second, clock.advance());
+        });
+        assert_eq!(clock.phase(), 2);
+        // the activities resigned by dropping their handles, and the owner resigns last
+        assert!(CLOCK_HOMES.lock().contains_key(&id));
+        drop(clock);
+        assert!(!CLOCK_HOMES.lock().contains_key(&id));
+        assert!(!CLOCK_LOCALS.lock().contains_key(&id));
+        // a stale message is dropped
+        handle_message(ClockMessage::Resign {
+            clock: id,
+            phase: 2,
+            participant: pid(0),
+            registered: vec![],
+        });
+    }
+
+    #[test]
+    fn test_clock_at_each() {
+        let _a = TestGuardForStatic::new();
+        let clock = Clock::new();
+        let id = clock.id;
+        let other = 3;
+        // the arguments of at_each! over here and another place
+        let bytes = activity::serialize_args(&(clock,), &[other, here()]);
+        let (mut copy,): (Clock,) = activity::deserialize_args(&bytes[..]);
+        // the copy at the other place is registered too
+        let at_other = participant_by_place(
+            ParticipantId {
+                local_id: copy.participant.local_id - here() as u64,
+                ..copy.participant
+            },
+            other,
+        );
+        assert_ne!(copy.participant, at_other);
+        let mut advance = copy.advance().boxed();
+        assert!((&mut advance).now_or_never().is_none());
+        handle_message(ClockMessage::Advance {
+            clock: id,
+            phase: 0,
+            participant: at_other,
+            registered: vec![],
+        });
+        executor::block_on(advance);
+        assert_eq!(copy.phase(), 1);
+        drop(copy);
+        handle_message(ClockMessage::Resign {
+            clock: id,
+            phase: 1,
+            participant: at_other,
+            registered: vec![],
+        });
+        assert!(!CLOCK_HOMES.lock().contains_key(&id));
+    }
+}
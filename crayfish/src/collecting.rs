@@ -0,0 +1,413 @@
+use crate::args::RemoteSend;
+use crate::context;
+use crate::context::TaskContext;
+use crate::global_id::FinishId;
+use crate::logging::*;
+use crate::place;
+use crate::runtime::ApgasContext;
+use crate::runtime::ConcreteContext;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::Any;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::ops::AddAssign;
+
+// X10 style collecting finish. Values offered at a place are reduced into a partial result of
+// the place. The partial result is sent to the finish place along with the next return item
+// of an activity under the finish, so a collecting finish sends no extra message.
+//
+// An offer goes to the nearest collecting finish, which activities know from their task
+// context, even under nested finishes. A partial result of an outer finish climbs the nested
+// finishes: it is sent along with return items to the place of the nested finish, kept there
+// as it is, and sent along with the return item of the activity running the nested finish.
+// Partial results carry the type of their reducer, so places disagreeing on it make the
+// finish panic instead of the runtime.
+
+/// Reduction of a collecting finish, see `finish_collect!`. The reducer is the reduced value
+pub trait Reducer: RemoteSend + Default {
+    fn reduce(&mut self, other: Self);
+}
+
+/// A partial result of a collecting finish, sent along with a return item
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialReduction {
+    finish_id: FinishId,
+    reducer: String,
+    bytes: Vec<u8>,
+}
+
+impl PartialReduction {
+    pub(crate) fn new<R: Reducer>(finish_id: FinishId, value: &R) -> Self {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, value).expect("Failed to serialize partial reduction");
+        PartialReduction {
+            finish_id,
+            reducer: String::from(std::any::type_name::<R>()),
+            bytes,
+        }
+    }
+}
+
+trait Partial: Send {
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+    fn into_partial(self: Box<Self>, finish_id: FinishId) -> PartialReduction;
+    fn absorb(&mut self, partial: &PartialReduction) -> Result<(), String>;
+}
+
+impl<R: Reducer> Partial for R {
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+    fn into_partial(self: Box<Self>, finish_id: FinishId) -> PartialReduction {
+        PartialReduction::new(finish_id, &*self)
+    }
+    fn absorb(&mut self, partial: &PartialReduction) -> Result<(), String> {
+        let reducer = std::any::type_name::<R>();
+        if partial.reducer != reducer {
+            return Err(format!(
+                "{:?} reduces {}, but a place offers {}",
+                partial.finish_id, reducer, partial.reducer
+            ));
+        }
+        let other: R = deserialize_from(&partial.bytes[..])
+            .map_err(|e| format!("Failed to deserialize partial reduction: {}", e))?;
+        self.reduce(other);
+        Ok(())
+    }
+}
+
+enum Slot {
+    Reducer(Box<dyn Partial>),
+    // partial results of an outer finish passing by this place, whose reducer is unknown here
+    Forwarded(Vec<PartialReduction>),
+}
+
+static PARTIALS: Lazy<Mutex<FxHashMap<FinishId, Slot>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+// the nearest collecting finish, in the task context of activities under it
+#[derive(Serialize, Deserialize)]
+struct NearestCollecting(FinishId);
+
+impl RemoteSend for NearestCollecting {
+    crate::impl_body! {}
+}
+
+/// the nearest collecting finish of an activity with the task context
+pub(crate) fn nearest_in(task_context: &TaskContext) -> Option<FinishId> {
+    task_context.get::<NearestCollecting>().map(|n| n.0)
+}
+
+/// Run the block of a collecting finish, so that activities spawned in it, and the ones they
+/// spawn under nested finishes, offer to this finish
+pub async fn scope<F: Future>(finish_id: FinishId, block: F) -> F::Output {
+    let mut inner = context::current();
+    let outer = inner.remove::<NearestCollecting>();
+    inner.insert(NearestCollecting(finish_id));
+    let (ret, mut after) = context::scope(inner, async move {
+        let ret = block.await;
+        (ret, context::current())
+    })
+    .await;
+    // values set by the block stay, like in any other block
+    after.remove::<NearestCollecting>();
+    if let Some(outer) = outer {
+        after.insert(outer);
+    }
+    context::replace(after);
+    ret
+}
+
+/// A collecting finish at its place
+pub struct Collecting<R> {
+    finish_id: FinishId,
+    _reducer: PhantomData<R>,
+}
+
+pub fn start<R: Reducer>(ctx: &ConcreteContext) -> Collecting<R> {
+    let finish_id = ctx.finish_id();
+    PARTIALS
+        .lock()
+        .insert(finish_id, Slot::Reducer(Box::new(R::default())));
+    Collecting {
+        finish_id,
+        _reducer: PhantomData,
+    }
+}
+
+impl<R: Reducer> Collecting<R> {
+    /// the reduced value, only complete after waiting the finish
+    pub fn result(self) -> R {
+        match PARTIALS.lock().remove(&self.finish_id) {
+            Some(Slot::Reducer(partial)) => *partial.into_any().downcast::<R>().unwrap(),
+            _ => unreachable!("the result of a collecting finish is at its place"),
+        }
+    }
+}
+
+impl<R> Drop for Collecting<R> {
+    fn drop(&mut self) {
+        // the finish exits early without taking the result
+        PARTIALS.lock().remove(&self.finish_id);
+    }
+}
+
+/// Offer a value to the nearest collecting finish, see `offer!`. The finish of the offering
+/// block is the nearest if the task context knows no collecting finish
+pub fn offer<R: Reducer>(finish_id: FinishId, value: R) {
+    let finish_id = context::get::<NearestCollecting>().map_or(finish_id, |n| n.0);
+    let mut partials = PARTIALS.lock();
+    let slot = if finish_id.get_place() == place::here() {
+        partials
+            .get_mut(&finish_id)
+            .expect("offer is only allowed under finish_collect!")
+    } else {
+        partials
+            .entry(finish_id)
+            .or_insert_with(|| Slot::Reducer(Box::new(R::default())))
+    };
+    if let Slot::Forwarded(forwarded) = slot {
+        // the reducer is known now
+        let mut partial = R::default();
+        for p in forwarded.iter() {
+            Partial::absorb(&mut partial, p).unwrap_or_else(|e| panic!("{}", e));
+        }
+        *slot = Slot::Reducer(Box::new(partial));
+    }
+    let partial = match slot {
+        Slot::Reducer(partial) => partial,
+        Slot::Forwarded(_) => unreachable!(),
+    };
+    match partial.as_any_mut().downcast_mut::<R>() {
+        Some(partial) => partial.reduce(value),
+        None => panic!(
+            "offered {} is not the reducer of the finish",
+            std::any::type_name::<R>()
+        ),
+    }
+}
+
+/// Take the partial results at this place to send with a return item to the finish, of the
+/// finish and of the nearest collecting finish of the returning activity
+pub(crate) fn take_partials(
+    finish_id: FinishId,
+    nearest: Option<FinishId>,
+) -> Vec<PartialReduction> {
+    let mut taken = vec![];
+    let mut partials = PARTIALS.lock();
+    let ids = std::iter::once(finish_id).chain(nearest.filter(|n| *n != finish_id));
+    for id in ids {
+        if id.get_place() == place::here() {
+            continue; // already reduced into the result
+        }
+        match partials.remove(&id) {
+            Some(Slot::Reducer(partial)) => taken.push(partial.into_partial(id)),
+            Some(Slot::Forwarded(forwarded)) => taken.extend(forwarded),
+            None => (),
+        }
+    }
+    taken
+}
+
+/// Reduce partial results from a return item into the results of finishes at this place, or
+/// keep them to be sent on. An error is a disagreement on the reducer, a panic of the finish
+pub(crate) fn absorb(received: Vec<PartialReduction>) -> Result<(), String> {
+    let mut ret = Ok(());
+    let mut partials = PARTIALS.lock();
+    for partial in received {
+        let finish_id = partial.finish_id;
+        let here = finish_id.get_place() == place::here();
+        match partials.get_mut(&finish_id) {
+            Some(Slot::Reducer(reducer)) => {
+                if let Err(e) = reducer.absorb(&partial) {
+                    ret = ret.and(Err(e));
+                }
+            }
+            Some(Slot::Forwarded(forwarded)) => forwarded.push(partial),
+            None if here => warn!(
+                "{:?} is not a collecting finish, drop offered values",
+                finish_id
+            ),
+            None => {
+                partials.insert(finish_id, Slot::Forwarded(vec![partial]));
+            }
+        }
+    }
+    ret
+}
+
+/// Sum of offered values
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sum<T>(pub T);
+
+impl<T> RemoteSend for Sum<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    crate::impl_body! {}
+}
+
+impl<T> Reducer for Sum<T>
+where
+    T: AddAssign + Default + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn reduce(&mut self, other: Self) {
+        self.0 += other.0;
+    }
+}
+
+/// Maximum of offered values, None if nothing is offered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Max<T>(pub Option<T>);
+
+impl<T> Default for Max<T> {
+    fn default() -> Self {
+        Max(None)
+    }
+}
+
+impl<T> RemoteSend for Max<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    crate::impl_body! {}
+}
+
+impl<T> Reducer for Max<T>
+where
+    T: Ord + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn reduce(&mut self, other: Self) {
+        self.0 = self.0.take().max(other.0);
+    }
+}
+
+/// Minimum of offered values, None if nothing is offered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Min<T>(pub Option<T>);
+
+impl<T> Default for Min<T> {
+    fn default() -> Self {
+        Min(None)
+    }
+}
+
+impl<T> RemoteSend for Min<T>
+where
+    T: Serialize + DeserializeOwned + Send + 'static,
+{
+    crate::impl_body! {}
+}
+
+impl<T> Reducer for Min<T>
+where
+    T: Ord + Serialize + DeserializeOwned + Send + 'static,
+{
+    fn reduce(&mut self, other: Self) {
+        self.0 = match (self.0.take(), other.0) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+
+    #[test]
+    fn test_reducers() {
+        let mut sum = Sum(1usize);
+        sum.reduce(Sum(2));
+        assert_eq!(sum, Sum(3));
+
+        let mut max = Max::default();
+        max.reduce(Max(Some(3)));
+        max.reduce(Max(None));
+        max.reduce(Max(Some(1)));
+        assert_eq!(max, Max(Some(3)));
+
+        let mut min = Min::default();
+        min.reduce(Min(Some(3)));
+        min.reduce(Min(None));
+        min.reduce(Min(Some(1)));
+        assert_eq!(min, Min(Some(1)));
+    }
+
+    #[test]
+    fn test_partial_absorb() {
+        let finish_id = FinishId::from(1);
+        let mut partial: Box<dyn Partial> = Box::new(Sum(5i64));
+        let other: Box<dyn Partial> = Box::new(Sum(-2i64));
+        partial.absorb(&other.into_partial(finish_id)).unwrap();
+        let other = PartialReduction::new(finish_id, &Sum(1u8));
+        assert!(partial.absorb(&other).is_err());
+        assert_eq!(*partial.into_any().downcast::<Sum<i64>>().unwrap(), Sum(3));
+    }
+
+    #[test]
+    fn test_nearest_collecting() {
+        let _a = TestGuardForStatic::new();
+        let collecting = ConcreteContext::new_frame();
+        let outer = start::<Sum<usize>>(&collecting);
+        let outer_id = collecting.finish_id();
+        // an offer of a plain finish nested in the collecting finish
+        let nested = ConcreteContext::new_frame().finish_id();
+        let task_context = executor::block_on(context::scope(
+            TaskContext::default(),
+            scope(outer_id, async {
+                offer(nested, Sum(1usize));
+                context::set(7usize);
+                context::current()
+            }),
+        ));
+        assert_eq!(nearest_in(&task_context), Some(outer_id));
+        assert_eq!(outer.result(), Sum(1));
+    }
+
+    #[test]
+    fn test_forward_partials() {
+        let _a = TestGuardForStatic::new();
+        // an outer finish at another place, and a nested one here
+        let outer = FinishId::from(TEST_HERE as usize + 1);
+        let nested = ConcreteContext::new_frame().finish_id();
+        assert!(take_partials(nested, Some(outer)).is_empty());
+        let received = vec![
+            PartialReduction::new(outer, &Sum(1usize)),
+            PartialReduction::new(outer, &Sum(2usize)),
+        ];
+        absorb(received.clone()).unwrap();
+        // sent on with the return item of the activity running the nested finish
+        assert_eq!(take_partials(nested, Some(outer)), received);
+        // an offer here knows the reducer
+        absorb(received).unwrap();
+        offer(outer, Sum(4usize));
+        assert_eq!(
+            take_partials(nested, Some(outer)),
+            vec![PartialReduction::new(outer, &Sum(7usize))]
+        );
+        // a place disagreeing on the reducer
+        let collecting = ConcreteContext::new_frame();
+        let result = start::<Sum<usize>>(&collecting);
+        let bad = vec![PartialReduction::new(
+            collecting.finish_id(),
+            &Max(Some(1u8)),
+        )];
+        assert!(absorb(bad).is_err());
+        assert_eq!(result.result(), Sum(0));
+    }
+}
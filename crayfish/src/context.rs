@@ -0,0 +1,135 @@
+use crate::args::RemoteSend;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+
+// Request-scoped values of an activity, like a job id or a trace span. An activity spawned by
+// at! or ff! starts with a copy of the context of its spawner, so values flow to remote places
+// and to all descendants. Values are keyed by type and kept serialized, since they are sent
+// along with the task item anyway.
+
+/// Typed values carried by an activity and inherited by the activities it spawns
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskContext {
+    values: BTreeMap<String, Vec<u8>>,
+}
+
+impl fmt::Debug for TaskContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.values.keys()).finish()
+    }
+}
+
+fn key<T>() -> String {
+    String::from(std::any::type_name::<T>())
+}
+
+impl TaskContext {
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn get<T: RemoteSend>(&self) -> Option<T> {
+        self.values.get(&key::<T>()).map(|bytes| {
+            deserialize_from(&bytes[..]).expect("Failed to deserialize context value")
+        })
+    }
+
+    pub fn insert<T: RemoteSend>(&mut self, value: T) {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &value).expect("Failed to serialize context value");
+        self.values.insert(key::<T>(), bytes);
+    }
+
+    pub fn remove<T: RemoteSend>(&mut self) -> Option<T> {
+        self.values.remove(&key::<T>()).map(|bytes| {
+            deserialize_from(&bytes[..]).expect("Failed to deserialize context value")
+        })
+    }
+}
+
+tokio::task_local! {
+    static CONTEXT: RefCell<TaskContext>;
+}
+
+/// run an activity with its context
+pub async fn scope<F: Future>(context: TaskContext, f: F) -> F::Output {
+    CONTEXT.scope(RefCell::new(context), f).await
+}
+
+/// snapshot of the context of the current activity, empty outside activities
+pub fn current() -> TaskContext {
+    CONTEXT
+        .try_with(|c| c.borrow().clone())
+        .unwrap_or_default()
+}
+
+/// value of type T in the context of the current activity
+pub fn get<T: RemoteSend>() -> Option<T> {
+    CONTEXT.try_with(|c| c.borrow().get::<T>()).ok().flatten()
+}
+
+/// set a value of type T for the rest of the current activity and activities spawned after.
+/// Tasks spawned by `crayfish::spawn` instead of at! or ff! do not inherit the context
+pub fn set<T: RemoteSend>(value: T) {
+    CONTEXT
+        .try_with(|c| c.borrow_mut().insert(value))
+        .expect("context can only be set in an activity")
+}
+
+/// replace the context of the current activity, if any
+pub(crate) fn replace(context: TaskContext) {
+    let _ = CONTEXT.try_with(|c| *c.borrow_mut() = context);
+}
+
+/// remove the value of type T from the context of the current activity
+pub fn remove<T: RemoteSend>() -> Option<T> {
+    CONTEXT
+        .try_with(|c| c.borrow_mut().remove::<T>())
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::activity::TaskItemBuilder;
+    use crate::activity::TaskItemExtracter;
+    use crate::global_id::ActivityId;
+    use futures::executor;
+
+    #[test]
+    fn test_context_scope() {
+        assert_eq!(get::<String>(), None);
+        assert!(current().is_empty());
+        let mut context = TaskContext::default();
+        context.insert(String::from("job-1"));
+        let inner = executor::block_on(scope(context, async {
+            assert_eq!(get::<String>(), Some(String::from("job-1")));
+            set(7usize);
+            let inner = current();
+            assert_eq!(remove::<String>(), Some(String::from("job-1")));
+            assert_eq!(get::<String>(), None);
+            inner
+        }));
+        // a snapshot is not affected by later changes
+        assert_eq!(inner.get::<String>(), Some(String::from("job-1")));
+        assert_eq!(inner.get::<usize>(), Some(7));
+        assert_eq!(inner.get::<u32>(), None);
+    }
+
+    #[test]
+    fn test_context_in_task_item() {
+        let mut context = TaskContext::default();
+        context.insert((1u64, String::from("tenant")));
+        let mut builder = TaskItemBuilder::new(1, 0, ActivityId::default());
+        builder.context(context.clone());
+        let mut ex = TaskItemExtracter::new(builder.build());
+        assert_eq!(ex.context(), context);
+    }
+}
@@ -0,0 +1,539 @@
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::place;
+use crate::place::here;
+use crate::place::Place;
+use crate::place::PlaceGroup;
+use crate::remote;
+use crate::remote::ReplyTo;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use crate::shared::PlaceLocal;
+use crate::shared::PlaceLocalWeak;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use parking_lot::RwLock;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+
+// A distributed array is created collectively: every place creates its own segment as a
+// place-local value by new_everywhere, so the handle of the segments agrees everywhere. A
+// DistArrayRef is the handle with the shape of the array, which is sent to other places to
+// address elements. Remote elements are read or written by requests served at the owner place.
+
+/// How elements of a distributed array are assigned to places
+pub trait Dist: Copy + fmt::Debug + Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// owner place and index in the segment of the owner
+    fn locate(&self, len: usize, places: usize, index: usize) -> (Place, usize);
+    /// number of elements owned by the place
+    fn local_len(&self, len: usize, places: usize, place: Place) -> usize;
+    /// global index of an element in the segment of the place
+    fn global_index(&self, len: usize, places: usize, place: Place, local: usize) -> usize;
+    /// panic if the distribution is invalid, checked when an array is created
+    fn check(&self) {}
+}
+
+/// Contiguous blocks of nearly equal size, the first places get one more element
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Block;
+
+/// Element i is at place i mod places
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Cyclic;
+
+/// Blocks of the given size dealt to places round-robin. The size must not be zero
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct BlockCyclic(pub usize);
+
+impl Dist for Block {
+    fn locate(&self, len: usize, places: usize, index: usize) -> (Place, usize) {
+        let (size, rem) = (len / places, len % places);
+        // the first rem places own size + 1 elements
+        let big = rem * (size + 1);
+        if index < big {
+            ((index / (size + 1)) as Place, index % (size + 1))
+        } else {
+            (((index - big) / size + rem) as Place, (index - big) % size)
+        }
+    }
+
+    fn local_len(&self, len: usize, places: usize, place: Place) -> usize {
+        len / places + ((place as usize) < len % places) as usize
+    }
+
+    fn global_index(&self, len: usize, places: usize, place: Place, local: usize) -> usize {
+        let (size, rem) = (len / places, len % places);
+        let place = place as usize;
+        place * size + place.min(rem) + local
+    }
+}
+
+impl Dist for Cyclic {
+    fn locate(&self, _len: usize, places: usize, index: usize) -> (Place, usize) {
+        ((index % places) as Place, index / places)
+    }
+
+    fn local_len(&self, len: usize, places: usize, place: Place) -> usize {
+        len / places + ((place as usize) < len % places) as usize
+    }
+
+    fn global_index(&self, _len: usize, places: usize, place: Place, local: usize) -> usize {
+        local * places + place as usize
+    }
+}
+
+impl Dist for BlockCyclic {
+    fn locate(&self, _len: usize, places: usize, index: usize) -> (Place, usize) {
+        let BlockCyclic(size) = *self;
+        let block = index / size;
+        (
+            (block % places) as Place,
+            block / places * size + index % size,
+        )
+    }
+
+    fn local_len(&self, len: usize, places: usize, place: Place) -> usize {
+        let BlockCyclic(size) = *self;
+        let place = place as usize;
+        let (full_blocks, rem) = (len / size, len % size);
+        let mine = full_blocks / places + (place < full_blocks % places) as usize;
+        // the last partial block
+        let partial = if rem > 0 && full_blocks % places == place {
+            rem
+        } else {
+            0
+        };
+        mine * size + partial
+    }
+
+    fn global_index(&self, _len: usize, places: usize, place: Place, local: usize) -> usize {
+        let BlockCyclic(size) = *self;
+        (local / size * places + place as usize) * size + local % size
+    }
+
+    fn check(&self) {
+        assert!(self.0 > 0, "blocks of BlockCyclic must not be empty");
+    }
+}
+
+// the segment of an array at this place, with the element type erased for request handlers
+trait Segment: Send + Sync + 'static {
+    fn as_any(&self) -> &dyn Any;
+    fn get_bytes(&self, local: usize) -> Vec<u8>;
+    fn put_bytes(&self, local: usize, bytes: &[u8]);
+}
+
+struct LocalSegment<T> {
+    values: RwLock<Vec<T>>,
+}
+
+impl<T> Segment for LocalSegment<T>
+where
+    T: RemoteSend + Clone + Sync,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    fn get_bytes(&self, local: usize) -> Vec<u8> {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &self.values.read()[local])
+            .expect("Failed to serialize array element");
+        bytes
+    }
+    fn put_bytes(&self, local: usize, bytes: &[u8]) {
+        let value: T = deserialize_from(bytes).expect("Failed to deserialize array element");
+        self.values.write()[local] = value;
+    }
+}
+
+type BoxedSegment = Box<dyn Segment>;
+type SegmentRef = PlaceLocalWeak<BoxedSegment>;
+
+fn segment(array: &SegmentRef) -> Result<Arc<BoxedSegment>, String> {
+    array
+        .upgrade()
+        .map_err(|e| format!("distributed array is dropped: {}", e))
+}
+
+#[derive(Serialize, Deserialize)]
+enum ArrayRequest {
+    Get {
+        array: SegmentRef,
+        local: usize,
+    },
+    Put {
+        array: SegmentRef,
+        local: usize,
+        value: Vec<u8>,
+    },
+}
+
+impl RemoteSend for ArrayRequest {
+    crate::impl_body! {}
+}
+
+const ARRAY_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "array_request_handler");
+
+// the element read, or nothing for a write. An error if the array is gone at this place
+fn serve(request: ArrayRequest) -> Result<Vec<u8>, String> {
+    match request {
+        ArrayRequest::Get { array, local } => Ok(segment(&array)?.get_bytes(local)),
+        ArrayRequest::Put {
+            array,
+            local,
+            value,
+        } => {
+            segment(&array)?.put_bytes(local, &value[..]);
+            Ok(vec![])
+        }
+    }
+}
+
+fn array_request_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let reply_to: ReplyTo = e.arg();
+    let reply = serve(e.arg::<ArrayRequest>());
+    remote::reply(reply_to, |builder| builder.arg(reply));
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        ARRAY_FN_ID,
+        array_request_handler,
+        String::from("array_request_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+/// A distributed array owning the segment of this place. Dropping it frees the segment.
+///
+/// Must be created at every place in the same order, like a collective. Use `global_ref` to
+/// send the array to activities.
+pub struct DistArray<T, D: Dist = Block> {
+    _segment: PlaceLocal<BoxedSegment>,
+    global: DistArrayRef<T, D>,
+}
+
+/// A reference to a distributed array, which can be sent to any place of the array
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct DistArrayRef<T, D: Dist = Block> {
+    segment: SegmentRef,
+    len: usize,
+    places: usize,
+    dist: D,
+    _mark: PhantomData<T>,
+}
+
+impl<T, D> DistArray<T, D>
+where
+    T: RemoteSend + Clone + Sync,
+    D: Dist,
+{
+    /// create the array with init(index) as elements of this place. A collective, see
+    /// `PlaceLocal::new_everywhere`
+    pub async fn new<F>(len: usize, dist: D, init: F) -> Self
+    where
+        F: FnMut(usize) -> T,
+    {
+        let places = place::world_size();
+        let segment = PlaceLocal::new_everywhere(|| Self::new_segment(len, places, dist, init));
+        Self::with_segment(len, places, dist, segment.await)
+    }
+
+    fn new_segment<F>(len: usize, places: usize, dist: D, init: F) -> BoxedSegment
+    where
+        F: FnMut(usize) -> T,
+    {
+        dist.check();
+        let values: Vec<T> = local_indices(len, places, dist).map(init).collect();
+        Box::new(LocalSegment {
+            values: RwLock::new(values),
+        })
+    }
+
+    fn with_segment(len: usize, places: usize, dist: D, segment: PlaceLocal<BoxedSegment>) -> Self {
+        let global = DistArrayRef {
+            segment: segment.downgrade(),
+            len,
+            places,
+            dist,
+            _mark: PhantomData,
+        };
+        DistArray {
+            _segment: segment,
+            global,
+        }
+    }
+
+    pub fn global_ref(&self) -> DistArrayRef<T, D> {
+        self.global.clone()
+    }
+}
+
+impl<T, D: Dist> Deref for DistArray<T, D> {
+    type Target = DistArrayRef<T, D>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.global
+    }
+}
+
+impl<T, D: Dist> Clone for DistArrayRef<T, D> {
+    fn clone(&self) -> Self {
+        DistArrayRef {
+            segment: self.segment.clone(),
+            len: self.len,
+            places: self.places,
+            dist: self.dist,
+            _mark: PhantomData,
+        }
+    }
+}
+
+impl<T, D: Dist> fmt::Debug for DistArrayRef<T, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DistArrayRef")
+            .field("segment", &self.segment)
+            .field("len", &self.len)
+            .field("dist", &self.dist)
+            .finish()
+    }
+}
+
+impl<T: Send + 'static, D: Dist> RemoteSend for DistArrayRef<T, D> {
+    crate::impl_body! {}
+}
+
+impl<T, D> DistArrayRef<T, D>
+where
+    T: RemoteSend + Clone + Sync,
+    D: Dist,
+{
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn dist(&self) -> D {
+        self.dist
+    }
+
+    /// places the array is distributed over
+    pub fn places(&self) -> PlaceGroup {
+        PlaceGroup::new(self.places)
+    }
+
+    pub fn place_of(&self, index: usize) -> Place {
+        self.locate(index).0
+    }
+
+    fn locate(&self, index: usize) -> (Place, usize) {
+        assert!(
+            index < self.len,
+            "index {} out of range of distributed array of length {}",
+            index,
+            self.len
+        );
+        self.dist.locate(self.len, self.places, index)
+    }
+
+    /// global indices of elements at this place, in the order of the local view
+    pub fn local_indices(&self) -> impl Iterator<Item = usize> {
+        local_indices(self.len, self.places, self.dist)
+    }
+
+    fn local_segment(&self) -> Arc<BoxedSegment> {
+        segment(&self.segment).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// run f on the elements at this place
+    pub fn with_local<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&[T]) -> R,
+    {
+        let segment = self.local_segment();
+        let segment = segment.as_any().downcast_ref::<LocalSegment<T>>().unwrap();
+        let values = segment.values.read();
+        f(&values[..])
+    }
+
+    /// run f on the elements at this place, exclusively
+    pub fn with_local_mut<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut [T]) -> R,
+    {
+        let segment = self.local_segment();
+        let segment = segment.as_any().downcast_ref::<LocalSegment<T>>().unwrap();
+        let mut values = segment.values.write();
+        f(&mut values[..])
+    }
+
+    /// run f with the global index on each element at this place. To run on all places, call
+    /// it in an activity spawned by at_each! over `places()` under a finish
+    pub fn for_each_local<F>(&self, mut f: F)
+    where
+        F: FnMut(usize, &mut T),
+    {
+        let indices = self.local_indices();
+        self.with_local_mut(|values| {
+            for (index, value) in indices.zip(values.iter_mut()) {
+                f(index, value);
+            }
+        })
+    }
+
+    /// read an element, from its owner place if remote
+    pub fn get(&self, index: usize) -> BoxFuture<'static, T> {
+        let (owner, local) = self.locate(index);
+        if owner == here() {
+            let value = self.with_local(|values| values[local].clone());
+            return futures::future::ready(value).boxed();
+        }
+        let request = ArrayRequest::Get {
+            array: self.segment.clone(),
+            local,
+        };
+        let reply = remote::request(owner, ARRAY_FN_ID, |builder| builder.arg(request));
+        async move {
+            let bytes = reply_of(reply.await.arg(), owner);
+            deserialize_from(&bytes[..]).expect("Failed to deserialize array element")
+        }
+        .boxed()
+    }
+
+    /// write an element, resolves once it is written at its owner place
+    pub fn put(&self, index: usize, value: T) -> BoxFuture<'static, ()> {
+        let (owner, local) = self.locate(index);
+        if owner == here() {
+            self.with_local_mut(|values| values[local] = value);
+            return futures::future::ready(()).boxed();
+        }
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &value).expect("Failed to serialize array element");
+        let request = ArrayRequest::Put {
+            array: self.segment.clone(),
+            local,
+            value: bytes,
+        };
+        let reply = remote::request(owner, ARRAY_FN_ID, |builder| builder.arg(request));
+        reply
+            .map(move |mut reply| {
+                reply_of(reply.arg(), owner);
+            })
+            .boxed()
+    }
+}
+
+fn local_indices<D: Dist>(len: usize, places: usize, dist: D) -> impl Iterator<Item = usize> {
+    let here = here();
+    (0..dist.local_len(len, places, here)).map(move |l| dist.global_index(len, places, here, l))
+}
+
+// an error of the owner is a panic of the requesting activity
+fn reply_of(reply: Result<Vec<u8>, String>, owner: Place) -> Vec<u8> {
+    reply.unwrap_or_else(|e| panic!("{} at place {}", e, owner))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+
+    // an array as if created collectively over the places
+    fn with_places<T, D, F>(len: usize, places: usize, dist: D, init: F) -> DistArray<T, D>
+    where
+        T: RemoteSend + Clone + Sync,
+        D: Dist,
+        F: FnMut(usize) -> T,
+    {
+        let segment = DistArray::<T, D>::new_segment(len, places, dist, init);
+        DistArray::with_segment(len, places, dist, PlaceLocal::new_collective(segment))
+    }
+
+    fn check_dist(dist: impl Dist, len: usize, places: usize) {
+        let mut seen = vec![vec![]; places];
+        for i in 0..len {
+            let (place, local) = dist.locate(len, places, i);
+            assert_eq!(dist.global_index(len, places, place, local), i);
+            seen[place as usize].push(local);
+        }
+        for (place, locals) in seen.into_iter().enumerate() {
+            // local indices of a place are dense and in global order
+            let expected: Vec<usize> = (0..dist.local_len(len, places, place as Place)).collect();
+            assert_eq!(locals, expected, "{:?} {} {}", dist, len, places);
+        }
+    }
+
+    #[test]
+    fn test_dists() {
+        for len in [0, 1, 7, 16, 33] {
+            for places in [1, 3, 4, 8] {
+                check_dist(Block, len, places);
+                check_dist(Cyclic, len, places);
+                check_dist(BlockCyclic(1), len, places);
+                check_dist(BlockCyclic(3), len, places);
+            }
+        }
+        assert_eq!(Block.locate(10, 4, 3), (1, 0));
+        assert_eq!(Cyclic.locate(10, 4, 6), (2, 1));
+        assert_eq!(BlockCyclic(2).locate(10, 4, 9), (0, 3));
+    }
+
+    #[test]
+    fn test_local_view() {
+        let _a = TestGuardForStatic::new();
+        let places = TEST_HERE as usize + 2;
+        let array = with_places(20, places, Cyclic, |i| i * 10);
+        let indices: Vec<usize> = array.local_indices().collect();
+        assert_eq!(indices, vec![TEST_HERE as usize, TEST_HERE as usize + places]);
+        array.with_local(|values| assert_eq!(values, &[70, 160][..]));
+        array.for_each_local(|i, v| *v += i);
+        assert_eq!(executor::block_on(array.get(16)), 176);
+        executor::block_on(array.put(7, 1));
+        array.with_local(|values| assert_eq!(values, &[1, 176][..]));
+        assert_eq!(array.place_of(8), 8);
+
+        // the reference finds the segment of the place
+        let global = array.global_ref();
+        assert_eq!(executor::block_on(global.get(7)), 1);
+        let request = |array: &DistArrayRef<usize, Cyclic>| ArrayRequest::Get {
+            array: array.segment.clone(),
+            local: 0,
+        };
+        let bytes = serve(request(&global)).unwrap();
+        assert_eq!(deserialize_from::<_, usize>(&bytes[..]).unwrap(), 1);
+        drop(array);
+        let local = std::panic::AssertUnwindSafe(|| global.with_local(|_| ()));
+        assert!(std::panic::catch_unwind(local).is_err());
+        // the owner replies an error instead of panicking
+        assert!(serve(request(&global)).unwrap_err().contains("dropped"));
+    }
+
+    #[test]
+    fn test_empty_blocks() {
+        let _a = TestGuardForStatic::new();
+        let create = || with_places(4, 2, BlockCyclic(0), |i| i);
+        assert!(std::panic::catch_unwind(create).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+// Items sent to a channel at another place are task items, so they are aggregated by the
+// squash buffers of the distributor like activities, and can arrive out of order. Every sender
+// handle numbers its items, and the receiver puts them back in order per sender. A clone of a
+// sender, or a sender sent to another place, is a new sender with its own numbering. A dropped
+// sender tells the receiver how many items it sent, so that its ordering state can be freed.
+
+type ChannelId = (Place, u64); // receiver and id at receiver
+type SenderId = (Place, u64); // place of the handle and id there
+
+static NEXT_CHANNEL_ID: AtomicU64 = AtomicU64::new(0);
+static NEXT_SENDER_ID: AtomicU64 = AtomicU64::new(0);
+static INBOXES: Lazy<Mutex<FxHashMap<u64, Arc<Inbox>>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn new_sender_id() -> SenderId {
+    (here(), NEXT_SENDER_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+#[derive(Serialize, Deserialize)]
+enum Packet {
+    Item(u64, Vec<u8>), // sequence number and the serialized item
+    Done(u64),          // the number of items sent
+}
+
+impl RemoteSend for Packet {
+    crate::impl_body! {}
+}
+
+#[derive(Default)]
+struct SenderOrder {
+    next: u64,
+    pending: BTreeMap<u64, Vec<u8>>,
+    done: Option<u64>,
+}
+
+#[derive(Default)]
+struct Queue {
+    items: VecDeque<Vec<u8>>,
+    senders: FxHashMap<SenderId, SenderOrder>,
+}
+
+impl Queue {
+    fn deliver(&mut self, sender: SenderId, packet: Packet) {
+        let order = self.senders.entry(sender).or_default();
+        match packet {
+            Packet::Item(seq, bytes) => {
+                debug_assert!(
+                    seq >= order.next,
+                    "item {} from {:?} is duplicated",
+                    seq,
+                    sender
+                );
+                order.pending.insert(seq, bytes);
+                while let Some(bytes) = order.pending.remove(&order.next) {
+                    self.items.push_back(bytes);
+                    order.next += 1;
+                }
+            }
+            Packet::Done(count) => order.done = Some(count),
+        }
+        if order.done == Some(order.next) {
+            self.senders.remove(&sender);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Inbox {
+    queue: Mutex<Queue>,
+    notify: Notify,
+}
+
+fn send_packet(channel: ChannelId, sender: SenderId, packet: Packet) {
+    if channel.0 == here() {
+        deliver(channel.1, sender, packet);
+    } else {
+        remote::send(channel.0, CHANNEL_FN_ID, |builder| {
+            builder.arg(channel.1);
+            builder.arg(sender);
+            builder.arg(packet);
+        });
+    }
+}
+
+fn deliver(channel: u64, sender: SenderId, packet: Packet) {
+    // the receiver might be gone
+    let inbox = match INBOXES.lock().get(&channel) {
+        Some(inbox) => inbox.clone(),
+        None => return,
+    };
+    inbox.queue.lock().deliver(sender, packet);
+    inbox.notify.notify_one();
+}
+
+/// The sending side of a channel. It can be cloned and sent to any place. Items sent by the
+/// same handle are received in order.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct Sender<T> {
+    channel: ChannelId,
+    #[serde(skip, default = "new_sender_id")]
+    id: SenderId,
+    #[serde(skip)]
+    sent: AtomicU64,
+    _item: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            channel: self.channel,
+            id: new_sender_id(),
+            sent: AtomicU64::new(0),
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("channel", &self.channel)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<T: RemoteSend> RemoteSend for Sender<T> {
+    crate::impl_body! {}
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let sent = *self.sent.get_mut();
+        if sent > 0 {
+            send_packet(self.channel, self.id, Packet::Done(sent));
+        }
+    }
+}
+
+impl<T: RemoteSend> Sender<T> {
+    /// the place of the receiver
+    pub fn receiver(&self) -> Place {
+        self.channel.0
+    }
+
+    /// send the item to the receiver without waiting
+    pub fn send(&self, item: T) {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &item).expect("Failed to serialize channel item");
+        let seq = self.sent.fetch_add(1, Ordering::Relaxed);
+        send_packet(self.channel, self.id, Packet::Item(seq, bytes));
+    }
+}
+
+/// The receiving side of a channel, at the place creating it. Items sent after it is dropped
+/// are discarded.
+pub struct Receiver<T> {
+    id: u64,
+    inbox: Arc<Inbox>,
+    _item: PhantomData<fn() -> T>,
+}
+
+/// a channel with its receiver here
+pub fn channel<T: RemoteSend>() -> (Sender<T>, Receiver<T>) {
+    let id = NEXT_CHANNEL_ID.fetch_add(1, Ordering::Relaxed);
+    let inbox = Arc::new(Inbox::default());
+    INBOXES.lock().insert(id, inbox.clone());
+    let sender = Sender {
+        channel: (here(), id),
+        id: new_sender_id(),
+        sent: AtomicU64::new(0),
+        _item: PhantomData,
+    };
+    let receiver = Receiver {
+        id,
+        inbox,
+        _item: PhantomData,
+    };
+    (sender, receiver)
+}
+
+impl<T: RemoteSend> Receiver<T> {
+    /// a new sender of the channel
+    pub fn sender(&self) -> Sender<T> {
+        Sender {
+            channel: (here(), self.id),
+            id: new_sender_id(),
+            sent: AtomicU64::new(0),
+            _item: PhantomData,
+        }
+    }
+
+    /// the next item, if any has arrived
+    pub fn try_recv(&mut self) -> Option<T> {
+        let bytes = self.inbox.queue.lock().items.pop_front()?;
+        Some(deserialize_from(&bytes[..]).expect("Failed to deserialize channel item"))
+    }
+
+    /// wait for the next item. There is no end of a channel, since senders can be anywhere
+    pub async fn recv(&mut self) -> T {
+        let inbox = self.inbox.clone();
+        loop {
+            let notified = inbox.notify.notified();
+            if let Some(item) = self.try_recv() {
+                return item;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        INBOXES.lock().remove(&self.id);
+    }
+}
+
+const CHANNEL_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "channel_packet_handler");
+
+fn channel_packet_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let channel: u64 = e.arg();
+    let sender: SenderId = e.arg();
+    let packet: Packet = e.arg();
+    deliver(channel, sender, packet);
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        CHANNEL_FN_ID,
+        channel_packet_handler,
+        String::from("channel_packet_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+
+    fn item(seq: u64, value: usize) -> Packet {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &value).unwrap();
+        Packet::Item(seq, bytes)
+    }
+
+    #[test]
+    fn test_local_channel() {
+        let _a = TestGuardForStatic::new();
+        let (tx, mut rx) = channel::<String>();
+        assert_eq!(tx.receiver(), TEST_HERE);
+        assert_eq!(rx.try_recv(), None);
+        tx.send(String::from("a"));
+        let tx2 = tx.clone();
+        assert_ne!(tx.id, tx2.id);
+        tx2.send(String::from("b"));
+        rx.sender().send(String::from("c"));
+        assert_eq!(executor::block_on(rx.recv()), "a");
+        assert_eq!(rx.try_recv().as_deref(), Some("b"));
+        assert_eq!(executor::block_on(rx.recv()), "c");
+        // every dropped sender has been done
+        drop(tx);
+        drop(tx2);
+        assert!(rx.inbox.queue.lock().senders.is_empty());
+        let id = rx.id;
+        drop(rx);
+        assert!(!INBOXES.lock().contains_key(&id));
+    }
+
+    #[test]
+    fn test_order_per_sender() {
+        let mut queue = Queue::default();
+        let (a, b) = ((1, 0), (2, 0));
+        queue.deliver(a, item(1, 11));
+        queue.deliver(b, item(0, 20));
+        queue.deliver(a, Packet::Done(3));
+        queue.deliver(a, item(2, 12));
+        assert_eq!(queue.items.len(), 1);
+        queue.deliver(a, item(0, 10));
+        let values: Vec<usize> = queue
+            .items
+            .iter()
+            .map(|bytes| deserialize_from(&bytes[..]).unwrap())
+            .collect();
+        assert_eq!(values, vec![20, 10, 11, 12]);
+        // a is done, b might send more
+        assert_eq!(queue.senders.len(), 1);
+        assert!(queue.senders.contains_key(&b));
+    }
+
+    #[test]
+    fn test_sender_serde() {
+        let _a = TestGuardForStatic::new();
+        let (tx, _rx) = channel::<usize>();
+        tx.send(1);
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &tx).unwrap();
+        let moved: Sender<usize> = deserialize_from(&bytes[..]).unwrap();
+        assert_eq!(moved.channel, tx.channel);
+        assert_ne!(moved.id, tx.id);
+        assert_eq!(moved.sent.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,651 @@
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemBuilder;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::collecting::Reducer;
+use crate::place;
+use crate::place::here;
+use crate::place::Place;
+use crate::place::PlaceGroup;
+use crate::remote;
+use crate::remote::ReplyTo;
+use crate::runtime::ApgasContext;
+use crate::runtime::ConcreteContext;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use crate::shared::PlaceLocal;
+use crate::shared::PlaceLocalWeak;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use parking_lot::RwLock;
+use rustc_hash::FxHashMap;
+use rustc_hash::FxHasher;
+use serde::Deserialize;
+use serde::Serialize;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+// A distributed hash map is created collectively like a distributed array, with its shards as
+// place-local values by new_everywhere. Each key is owned by the place its hash maps to, and
+// every write is computed at the owner (owner-computes). Keys and updates are separate
+// arguments of request items, so squashable types are squashed with other requests to the same
+// place by the distributor. A reply starts with the status of the request, an error if the map
+// is gone at the owner.
+
+/// An update of a value computed at the owner place of its key
+pub trait Update<V>: RemoteSend {
+    /// the value of a key not in the map yet
+    fn insert(self) -> V;
+    /// update the value of a key in the map
+    fn update(self, value: &mut V);
+}
+
+impl<R: Reducer> Update<R> for R {
+    fn insert(self) -> R {
+        self
+    }
+    fn update(self, value: &mut R) {
+        value.reduce(self);
+    }
+}
+
+type BoxedShard = Box<dyn Shard>;
+type ShardRef = PlaceLocalWeak<BoxedShard>;
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+enum MapOp {
+    Insert,
+    InsertWith,
+    Update,
+    UpdateAll,
+    Get,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MapRequest {
+    map: ShardRef,
+    op: MapOp,
+}
+
+impl RemoteSend for MapRequest {
+    crate::impl_body! {}
+}
+
+// the shard of a map at this place, with key and value types erased for request handlers
+trait Shard: Send + Sync + 'static {
+    fn as_any(&self) -> &dyn Any;
+    fn handle(&self, op: MapOp, e: &mut TaskItemExtracter, reply_to: ReplyTo);
+    fn to_bytes(&self) -> Vec<u8>;
+    fn next_collect(&self) -> u64;
+}
+
+struct LocalShard<K, V, U> {
+    entries: RwLock<HashMap<K, V>>,
+    collects: AtomicU64,
+    _update: PhantomData<fn(U)>,
+}
+
+impl<K, V, U> LocalShard<K, V, U>
+where
+    K: RemoteSend + Hash + Eq + Clone + Sync,
+    V: RemoteSend + Clone + Sync,
+    U: Update<V>,
+{
+    fn insert(&self, key: K, value: V) {
+        self.entries.write().insert(key, value);
+    }
+
+    fn insert_with(&self, key: K, update: U) {
+        self.entries
+            .write()
+            .entry(key)
+            .or_insert_with(|| update.insert());
+    }
+
+    fn update(&self, key: K, update: U) {
+        let mut entries = self.entries.write();
+        match entries.get_mut(&key) {
+            Some(value) => update.update(value),
+            None => {
+                entries.insert(key, update.insert());
+            }
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.entries.read().get(key).cloned()
+    }
+}
+
+impl<K, V, U> Shard for LocalShard<K, V, U>
+where
+    K: RemoteSend + Hash + Eq + Clone + Sync,
+    V: RemoteSend + Clone + Sync,
+    U: Update<V>,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn handle(&self, op: MapOp, e: &mut TaskItemExtracter, reply_to: ReplyTo) {
+        let ok = Ok::<(), String>(());
+        if let MapOp::UpdateAll = op {
+            for (key, update) in e.arg::<Vec<(K, U)>>() {
+                self.update(key, update);
+            }
+            return remote::reply(reply_to, |builder| builder.arg(ok));
+        }
+        let key: K = e.arg();
+        match op {
+            MapOp::Insert => self.insert(key, e.arg()),
+            MapOp::InsertWith => self.insert_with(key, e.arg()),
+            MapOp::Update => self.update(key, e.arg()),
+            MapOp::UpdateAll => unreachable!(),
+            MapOp::Get => {
+                let value = self.get(&key);
+                return remote::reply(reply_to, |builder| {
+                    builder.arg(ok);
+                    builder.arg(value);
+                });
+            }
+        }
+        remote::reply(reply_to, |builder| builder.arg(ok));
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &*self.entries.read()).expect("Failed to serialize shard");
+        bytes
+    }
+
+    fn next_collect(&self) -> u64 {
+        self.collects.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+fn shard(map: &ShardRef) -> Result<Arc<BoxedShard>, String> {
+    map.upgrade()
+        .map_err(|e| format!("distributed hash map is dropped: {}", e))
+}
+
+const MAP_FN_ID: FunctionLabel = runtime_meta::function_id(module_path!(), "map_request_handler");
+
+fn map_request_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let reply_to: ReplyTo = e.arg();
+    let request: MapRequest = e.arg();
+    match shard(&request.map) {
+        Ok(shard) => shard.handle(request.op, &mut e, reply_to),
+        Err(error) => remote::reply(reply_to, |builder| builder.arg(Err::<(), _>(error))),
+    }
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        MAP_FN_ID,
+        map_request_handler,
+        String::from("map_request_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+// shards sent to the root of a collect, which might arrive before the root starts it
+#[derive(Default)]
+struct Collect {
+    shards: Vec<Vec<u8>>,
+    waiter: Option<(usize, oneshot::Sender<Vec<Vec<u8>>>)>,
+}
+
+impl Collect {
+    fn try_complete(&mut self) -> bool {
+        let done = matches!(&self.waiter, Some((expected, _)) if self.shards.len() == *expected);
+        if done {
+            let (_, waiter) = self.waiter.take().unwrap();
+            let _ = waiter.send(std::mem::take(&mut self.shards));
+        }
+        done
+    }
+}
+
+type MapId = usize; // id of the handle of shards
+
+static COLLECTS: Lazy<Mutex<FxHashMap<(MapId, u64), Collect>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn collect_arrived(map: MapId, round: u64, shard: Vec<u8>) {
+    let mut collects = COLLECTS.lock();
+    let collect = collects.entry((map, round)).or_default();
+    collect.shards.push(shard);
+    if collect.try_complete() {
+        collects.remove(&(map, round));
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShardMessage {
+    map: MapId,
+    round: u64,
+    shard: Vec<u8>,
+}
+
+impl RemoteSend for ShardMessage {
+    crate::impl_body! {}
+}
+
+const COLLECT_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "shard_collect_handler");
+
+fn shard_collect_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let message: ShardMessage = e.arg();
+    collect_arrived(message.map, message.round, message.shard);
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        COLLECT_FN_ID,
+        shard_collect_handler,
+        String::from("shard_collect_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+/// A distributed hash map owning the shard of this place. Dropping it frees the shard.
+///
+/// Must be created at every place in the same order, like a collective. Use `global_ref` to
+/// send the map to activities. Values of existing keys are updated by U, see `Update`.
+pub struct DistHashMap<K, V, U = V> {
+    _shard: PlaceLocal<BoxedShard>,
+    global: DistHashMapRef<K, V, U>,
+}
+
+/// A reference to a distributed hash map, which can be sent to any place of the map
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct DistHashMapRef<K, V, U = V> {
+    shard: ShardRef,
+    places: usize,
+    _mark: PhantomData<fn(K, V, U)>,
+}
+
+impl<K, V, U> DistHashMap<K, V, U>
+where
+    K: RemoteSend + Hash + Eq + Clone + Sync,
+    V: RemoteSend + Clone + Sync,
+    U: Update<V>,
+{
+    /// A collective, see `PlaceLocal::new_everywhere`
+    pub async fn new() -> Self {
+        let shard = PlaceLocal::new_everywhere(Self::new_shard).await;
+        Self::with_shard(place::world_size(), shard)
+    }
+
+    fn new_shard() -> BoxedShard {
+        Box::new(LocalShard::<K, V, U> {
+            entries: RwLock::new(HashMap::new()),
+            collects: AtomicU64::new(0),
+            _update: PhantomData,
+        })
+    }
+
+    fn with_shard(places: usize, shard: PlaceLocal<BoxedShard>) -> Self {
+        DistHashMap {
+            global: DistHashMapRef {
+                shard: shard.downgrade(),
+                places,
+                _mark: PhantomData,
+            },
+            _shard: shard,
+        }
+    }
+
+    pub fn global_ref(&self) -> DistHashMapRef<K, V, U> {
+        self.global.clone()
+    }
+}
+
+impl<K, V, U> Deref for DistHashMap<K, V, U> {
+    type Target = DistHashMapRef<K, V, U>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.global
+    }
+}
+
+impl<K, V, U> Clone for DistHashMapRef<K, V, U> {
+    fn clone(&self) -> Self {
+        DistHashMapRef {
+            shard: self.shard.clone(),
+            places: self.places,
+            _mark: PhantomData,
+        }
+    }
+}
+
+impl<K, V, U> fmt::Debug for DistHashMapRef<K, V, U> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DistHashMapRef")
+            .field("shard", &self.shard)
+            .field("places", &self.places)
+            .finish()
+    }
+}
+
+impl<K: 'static, V: 'static, U: 'static> RemoteSend for DistHashMapRef<K, V, U> {
+    crate::impl_body! {}
+}
+
+impl<K, V, U> DistHashMapRef<K, V, U>
+where
+    K: RemoteSend + Hash + Eq + Clone + Sync,
+    V: RemoteSend + Clone + Sync,
+    U: Update<V>,
+{
+    /// places the map is distributed over
+    pub fn places(&self) -> PlaceGroup {
+        PlaceGroup::new(self.places)
+    }
+
+    pub fn owner(&self, key: &K) -> Place {
+        let mut hasher = FxHasher::default();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.places as u64) as Place
+    }
+
+    fn local_shard(&self) -> Arc<BoxedShard> {
+        shard(&self.shard).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn with_local_shard<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&LocalShard<K, V, U>) -> R,
+    {
+        let shard = self.local_shard();
+        f(shard.as_any().downcast_ref().unwrap())
+    }
+
+    // Send an operation to the owner of the key, resolves when it is done. An error of the
+    // owner is a panic of the requesting activity
+    fn request<F>(&self, owner: Place, op: MapOp, build: F) -> BoxFuture<'static, TaskItemExtracter>
+    where
+        F: FnOnce(&mut TaskItemBuilder),
+    {
+        let request = MapRequest {
+            map: self.shard.clone(),
+            op,
+        };
+        let reply = remote::request(owner, MAP_FN_ID, |builder| {
+            builder.arg(request);
+            build(builder);
+        });
+        async move {
+            let mut reply = reply.await;
+            if let Err(e) = reply.arg::<Result<(), String>>() {
+                panic!("{} at place {}", e, owner);
+            }
+            reply
+        }
+        .boxed()
+    }
+
+    /// insert or replace the value of the key
+    pub fn insert(&self, key: K, value: V) -> BoxFuture<'static, ()> {
+        let owner = self.owner(&key);
+        if owner == here() {
+            self.with_local_shard(|s| s.insert(key, value));
+            return futures::future::ready(()).boxed();
+        }
+        self.request(owner, MapOp::Insert, |builder| {
+            builder.arg(key);
+            builder.arg(value);
+        })
+        .map(|_| ())
+        .boxed()
+    }
+
+    /// insert the value computed from the update at the owner if the key is not in the map
+    pub fn insert_with(&self, key: K, update: U) -> BoxFuture<'static, ()> {
+        let owner = self.owner(&key);
+        if owner == here() {
+            self.with_local_shard(|s| s.insert_with(key, update));
+            return futures::future::ready(()).boxed();
+        }
+        self.request(owner, MapOp::InsertWith, |builder| {
+            builder.arg(key);
+            builder.arg(update);
+        })
+        .map(|_| ())
+        .boxed()
+    }
+
+    /// update the value of the key at the owner, or insert it if the key is not in the map.
+    /// The update is sent at once, and the future resolves once it is done
+    pub fn update(&self, key: K, update: U) -> BoxFuture<'static, ()> {
+        let owner = self.owner(&key);
+        if owner == here() {
+            self.with_local_shard(|s| s.update(key, update));
+            return futures::future::ready(()).boxed();
+        }
+        self.request(owner, MapOp::Update, |builder| {
+            builder.arg(key);
+            builder.arg(update);
+        })
+        .map(|_| ())
+        .boxed()
+    }
+
+    /// Update the values of many keys, like `update`. Updates to the same owner are sent in
+    /// one request, and the future resolves once all are done
+    pub fn update_all<I>(&self, updates: I) -> BoxFuture<'static, ()>
+    where
+        I: IntoIterator<Item = (K, U)>,
+    {
+        let mut by_owner: FxHashMap<Place, Vec<(K, U)>> = FxHashMap::default();
+        for (key, update) in updates {
+            let owner = self.owner(&key);
+            if owner == here() {
+                self.with_local_shard(|s| s.update(key, update));
+            } else {
+                by_owner.entry(owner).or_default().push((key, update));
+            }
+        }
+        let requests = by_owner.into_iter().map(|(owner, updates)| {
+            self.request(owner, MapOp::UpdateAll, |builder| builder.arg(updates))
+        });
+        futures::future::join_all(requests).map(|_| ()).boxed()
+    }
+
+    /// the value of the key, from its owner place if remote
+    pub fn get(&self, key: K) -> BoxFuture<'static, Option<V>> {
+        let owner = self.owner(&key);
+        if owner == here() {
+            let value = self.with_local_shard(|s| s.get(&key));
+            return futures::future::ready(value).boxed();
+        }
+        self.request(owner, MapOp::Get, |builder| builder.arg(key))
+            .map(|mut e| e.arg())
+            .boxed()
+    }
+
+    /// run f on the shard of this place
+    pub fn with_local<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&HashMap<K, V>) -> R,
+    {
+        self.with_local_shard(|s| f(&s.entries.read()))
+    }
+
+    /// run f on each entry of the shard of this place
+    pub fn for_each_local<F>(&self, mut f: F)
+    where
+        F: FnMut(&K, &mut V),
+    {
+        self.with_local_shard(|s| {
+            for (key, value) in s.entries.write().iter_mut() {
+                f(key, value);
+            }
+        })
+    }
+
+    pub fn local_len(&self) -> usize {
+        self.with_local(|entries| entries.len())
+    }
+
+    /// Gather all entries to the root. A collective: every place of the map must call it in the
+    /// same order. Resolves to the whole map at the root and None elsewhere
+    pub fn collect_to(&self, root: Place) -> BoxFuture<'static, Option<HashMap<K, V>>> {
+        let shard = self.local_shard();
+        let round = shard.next_collect();
+        if here() != root {
+            let mut builder = TaskItemBuilder::new(COLLECT_FN_ID, root, Default::default());
+            builder.arg(ShardMessage {
+                map: self.shard.id(),
+                round,
+                shard: shard.to_bytes(),
+            });
+            ConcreteContext::send(builder.build_box());
+            return futures::future::ready(None).boxed();
+        }
+        let mut all = self.with_local(|entries| entries.clone());
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut collects = COLLECTS.lock();
+            let id = self.shard.id();
+            let collect = collects.entry((id, round)).or_default();
+            collect.waiter = Some((self.places - 1, tx));
+            if collect.try_complete() {
+                collects.remove(&(id, round));
+            }
+        }
+        async move {
+            for bytes in rx.await.unwrap() {
+                let shard: HashMap<K, V> =
+                    deserialize_from(&bytes[..]).expect("Failed to deserialize shard");
+                all.extend(shard);
+            }
+            Some(all)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::collecting::Sum;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+
+    // a map as if created collectively over the places
+    fn with_places<K, V, U>(places: usize) -> DistHashMap<K, V, U>
+    where
+        K: RemoteSend + Hash + Eq + Clone + Sync,
+        V: RemoteSend + Clone + Sync,
+        U: Update<V>,
+    {
+        let shard = PlaceLocal::new_collective(DistHashMap::<K, V, U>::new_shard());
+        DistHashMap::with_shard(places, shard)
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Append(char);
+
+    impl RemoteSend for Append {
+        crate::impl_body! {}
+    }
+
+    impl Update<String> for Append {
+        fn insert(self) -> String {
+            self.0.to_string()
+        }
+        fn update(self, value: &mut String) {
+            value.push(self.0);
+        }
+    }
+
+    #[test]
+    fn test_local_shard() {
+        let _a = TestGuardForStatic::new();
+        // a single place owns all keys
+        let map = with_places::<String, String, Append>(1);
+        assert_eq!(map.owner(&String::from("k")), 0);
+        let map = with_places::<u32, Sum<u64>, Sum<u64>>(TEST_HERE as usize + 1);
+        let mine: Vec<u32> = (0..64).filter(|k| map.owner(k) == TEST_HERE).collect();
+        assert!(!mine.is_empty());
+        for k in mine.iter() {
+            executor::block_on(map.update(*k, Sum(1)));
+            executor::block_on(map.update(*k, Sum(2)));
+        }
+        executor::block_on(map.insert_with(mine[0], Sum(10)));
+        assert_eq!(executor::block_on(map.get(mine[0])), Some(Sum(3)));
+        assert_eq!(map.local_len(), mine.len());
+        map.for_each_local(|_, v| v.0 *= 2);
+        map.with_local(|entries| assert!(entries.values().all(|v| *v == Sum(6))));
+        executor::block_on(map.update_all(mine.iter().map(|k| (*k, Sum(1)))));
+        assert_eq!(executor::block_on(map.get(mine[0])), Some(Sum(7)));
+    }
+
+    #[test]
+    fn test_dropped_shard() {
+        let _a = TestGuardForStatic::new();
+        let map = with_places::<u32, Sum<u64>, Sum<u64>>(1);
+        let request = MapRequest {
+            map: map.shard.clone(),
+            op: MapOp::Get,
+        };
+        let global = map.global_ref();
+        drop(map);
+        // the owner replies an error instead of panicking
+        assert!(matches!(shard(&request.map), Err(e) if e.contains("dropped")));
+        let local = std::panic::AssertUnwindSafe(|| global.local_len());
+        assert!(std::panic::catch_unwind(local).is_err());
+    }
+
+    #[test]
+    fn test_update() {
+        let mut value = Append('a').insert();
+        Append('b').update(&mut value);
+        assert_eq!(value, "ab");
+        let mut sum = Sum(1).insert();
+        Sum(2).update(&mut sum);
+        assert_eq!(sum, Sum(3));
+    }
+
+    #[test]
+    fn test_collect_before_root() {
+        // shards from other places arrive before the root starts the collect
+        collect_arrived(usize::MAX, 0, vec![1]);
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut collects = COLLECTS.lock();
+            let collect = collects.get_mut(&(usize::MAX, 0)).unwrap();
+            collect.waiter = Some((2, tx));
+            assert!(!collect.try_complete());
+        }
+        collect_arrived(usize::MAX, 0, vec![2]);
+        assert_eq!(executor::block_on(rx).unwrap(), vec![vec![1], vec![2]]);
+        assert!(COLLECTS.lock().get(&(usize::MAX, 0)).is_none());
+    }
+}
@@ -0,0 +1,533 @@
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::remote::ReplyTo;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use futures::future::BoxFuture;
+use futures::Future;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use rustc_hash::FxHashSet;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+use tokio::sync::oneshot;
+
+// The permits of a semaphore are counted at its home place, which grants acquires in the order
+// they arrive. An acquire that can not be granted blocks the ones behind it, so a large acquire
+// is never starved by small ones. Every acquire has a ticket, so that a release or a cancel can
+// find it. An acquire dropped before being granted sends a cancel, which dequeues the ticket,
+// releases it if it was granted meanwhile, or discards it if the cancel overtakes the acquire.
+
+type Ticket = (Place, u64); // place of the acquire and id there
+
+static NEXT_SEMAPHORE_ID: AtomicU64 = AtomicU64::new(0);
+static NEXT_TICKET: AtomicU64 = AtomicU64::new(0);
+static SEMAPHORES: Lazy<Mutex<FxHashMap<u64, SemaphoreState>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+enum Waiter {
+    Local(oneshot::Sender<bool>),
+    Remote(ReplyTo),
+}
+
+struct Request {
+    ticket: Ticket,
+    permits: usize,
+    waiter: Waiter,
+}
+
+#[derive(Default)]
+struct SemaphoreState {
+    available: usize,
+    queue: VecDeque<Request>,
+    held: FxHashMap<Ticket, usize>,
+    cancelled: FxHashSet<Ticket>, // cancels overtaking their acquires
+}
+
+impl SemaphoreState {
+    fn grant(&mut self) -> Vec<Waiter> {
+        let mut granted = vec![];
+        while let Some(front) = self.queue.front() {
+            if front.permits > self.available {
+                break;
+            }
+            let request = self.queue.pop_front().unwrap();
+            self.available -= request.permits;
+            self.held.insert(request.ticket, request.permits);
+            granted.push(request.waiter);
+        }
+        granted
+    }
+
+    // the waiters granted, or the waiter of a cancelled acquire that nobody waits for
+    fn acquire(&mut self, request: Request) -> Result<Vec<Waiter>, Waiter> {
+        if self.cancelled.remove(&request.ticket) {
+            return Err(request.waiter);
+        }
+        self.queue.push_back(request);
+        Ok(self.grant())
+    }
+
+    fn control(&mut self, ticket: Ticket, control: Control) -> Vec<Waiter> {
+        if let Some(permits) = self.held.remove(&ticket) {
+            self.available += permits;
+            return self.grant();
+        }
+        if let Control::Cancel = control {
+            match self.queue.iter().position(|r| r.ticket == ticket) {
+                // the front might have blocked the others
+                Some(i) => drop(self.queue.remove(i)),
+                None => drop(self.cancelled.insert(ticket)),
+            }
+            return self.grant();
+        }
+        vec![]
+    }
+}
+
+fn notify(waiters: Vec<Waiter>, granted: bool) {
+    for waiter in waiters {
+        match waiter {
+            // the acquire might be gone, and its cancel releases the permits
+            Waiter::Local(tx) => drop(tx.send(granted)),
+            Waiter::Remote(to) => remote::reply(to, |builder| builder.arg(granted)),
+        }
+    }
+}
+
+fn acquire_at_home(id: u64, request: Request) {
+    let acquired = match SEMAPHORES.lock().get_mut(&id) {
+        Some(state) => state.acquire(request),
+        None => Err(request.waiter), // dropped at home
+    };
+    match acquired {
+        Ok(granted) => notify(granted, true),
+        Err(waiter) => notify(vec![waiter], false),
+    }
+}
+
+fn control_at_home(id: u64, ticket: Ticket, control: Control) {
+    let granted = match SEMAPHORES.lock().get_mut(&id) {
+        Some(state) => state.control(ticket, control),
+        None => return, // dropped at home
+    };
+    notify(granted, true);
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+enum Control {
+    Release,
+    Cancel,
+}
+
+impl RemoteSend for Control {
+    crate::impl_body! {}
+}
+
+/// A semaphore with its permits at the place creating it. Dropping it fails the acquires
+/// waiting for it. Use `global_ref` to send it to activities.
+pub struct DistSemaphore {
+    global: DistSemaphoreRef,
+}
+
+/// A reference to a semaphore, which can be sent to any place
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct DistSemaphoreRef {
+    home: Place,
+    id: u64,
+    permits: usize,
+}
+
+impl RemoteSend for DistSemaphoreRef {
+    crate::impl_body! {}
+}
+
+impl DistSemaphore {
+    pub fn new(permits: usize) -> Self {
+        let id = NEXT_SEMAPHORE_ID.fetch_add(1, Ordering::Relaxed);
+        let state = SemaphoreState {
+            available: permits,
+            ..SemaphoreState::default()
+        };
+        SEMAPHORES.lock().insert(id, state);
+        DistSemaphore {
+            global: DistSemaphoreRef {
+                home: here(),
+                id,
+                permits,
+            },
+        }
+    }
+
+    pub fn global_ref(&self) -> DistSemaphoreRef {
+        self.global
+    }
+}
+
+impl Deref for DistSemaphore {
+    type Target = DistSemaphoreRef;
+
+    fn deref(&self) -> &Self::Target {
+        &self.global
+    }
+}
+
+impl Drop for DistSemaphore {
+    fn drop(&mut self) {
+        if let Some(state) = SEMAPHORES.lock().remove(&self.global.id) {
+            notify(state.queue.into_iter().map(|r| r.waiter).collect(), false);
+        }
+    }
+}
+
+// resolves to whether the permits are granted, and cancels the acquire if dropped before
+struct Acquiring {
+    semaphore: DistSemaphoreRef,
+    ticket: Ticket,
+    granted: BoxFuture<'static, bool>,
+    done: bool,
+}
+
+impl Future for Acquiring {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<bool> {
+        let this = self.get_mut();
+        let granted = futures::ready!(this.granted.poll_unpin(cx));
+        this.done = true;
+        Poll::Ready(granted)
+    }
+}
+
+impl Drop for Acquiring {
+    fn drop(&mut self) {
+        if !self.done {
+            self.semaphore.control(self.ticket, Control::Cancel);
+        }
+    }
+}
+
+impl DistSemaphoreRef {
+    pub fn home(&self) -> Place {
+        self.home
+    }
+
+    /// the number of permits of the semaphore
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    fn control(&self, ticket: Ticket, control: Control) {
+        if self.home == here() {
+            control_at_home(self.id, ticket, control);
+        } else {
+            remote::send(self.home, CONTROL_FN_ID, |builder| {
+                builder.arg(self.id);
+                builder.arg(ticket);
+                builder.arg(control);
+            });
+        }
+    }
+
+    /// Resolves to a guard holding the permits, once the acquires before it are granted and
+    /// enough permits are released. Panics if the semaphore is dropped at its home place.
+    pub fn acquire(&self, permits: usize) -> BoxFuture<'static, SemaphoreGuard> {
+        assert!(
+            permits <= self.permits,
+            "acquiring {} of {} permits",
+            permits,
+            self.permits
+        );
+        let ticket = (here(), NEXT_TICKET.fetch_add(1, Ordering::Relaxed));
+        let granted = if self.home == here() {
+            let (tx, rx) = oneshot::channel();
+            let request = Request {
+                ticket,
+                permits,
+                waiter: Waiter::Local(tx),
+            };
+            acquire_at_home(self.id, request);
+            rx.map(|granted| granted.unwrap_or(false)).boxed()
+        } else {
+            let id = self.id;
+            remote::request(self.home, ACQUIRE_FN_ID, |builder| {
+                builder.arg(id);
+                builder.arg(ticket);
+                builder.arg(permits);
+            })
+            .map(|mut e| e.arg::<bool>())
+            .boxed()
+        };
+        let acquiring = Acquiring {
+            semaphore: *self,
+            ticket,
+            granted,
+            done: false,
+        };
+        let semaphore = *self;
+        async move {
+            if !acquiring.await {
+                panic!("{:?} is dropped at its home place", semaphore);
+            }
+            SemaphoreGuard {
+                semaphore,
+                ticket,
+                permits,
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Permits held from a semaphore, released on drop, including unwinding of a panic
+#[derive(Debug)]
+pub struct SemaphoreGuard {
+    semaphore: DistSemaphoreRef,
+    ticket: Ticket,
+    permits: usize,
+}
+
+impl SemaphoreGuard {
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+}
+
+impl Drop for SemaphoreGuard {
+    fn drop(&mut self) {
+        self.semaphore.control(self.ticket, Control::Release);
+    }
+}
+
+/// A mutex at the place creating it, for exclusive access to resources outside Crayfish, like
+/// files or devices. Locks are granted in the order they arrive. Use `global_ref` to send it to
+/// activities.
+pub struct DistMutex {
+    semaphore: DistSemaphore,
+}
+
+/// A reference to a mutex, which can be sent to any place
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct DistMutexRef {
+    semaphore: DistSemaphoreRef,
+}
+
+impl RemoteSend for DistMutexRef {
+    crate::impl_body! {}
+}
+
+impl Default for DistMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DistMutex {
+    pub fn new() -> Self {
+        DistMutex {
+            semaphore: DistSemaphore::new(1),
+        }
+    }
+
+    pub fn global_ref(&self) -> DistMutexRef {
+        DistMutexRef {
+            semaphore: self.semaphore.global_ref(),
+        }
+    }
+
+    pub fn home(&self) -> Place {
+        self.semaphore.home()
+    }
+
+    pub fn lock(&self) -> BoxFuture<'static, DistMutexGuard> {
+        self.global_ref().lock()
+    }
+}
+
+impl DistMutexRef {
+    pub fn home(&self) -> Place {
+        self.semaphore.home()
+    }
+
+    /// Resolves to a guard once the lock is granted. Panics if the mutex is dropped at its
+    /// home place.
+    pub fn lock(&self) -> BoxFuture<'static, DistMutexGuard> {
+        self.semaphore
+            .acquire(1)
+            .map(|guard| DistMutexGuard { _guard: guard })
+            .boxed()
+    }
+}
+
+/// The lock of a mutex, released on drop, including unwinding of a panic
+#[derive(Debug)]
+pub struct DistMutexGuard {
+    _guard: SemaphoreGuard,
+}
+
+const ACQUIRE_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "semaphore_acquire_handler");
+const CONTROL_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "semaphore_control_handler");
+
+fn semaphore_acquire_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let reply_to: ReplyTo = e.arg();
+    let id: u64 = e.arg();
+    let ticket: Ticket = e.arg();
+    let permits: usize = e.arg();
+    // replied now or when granted
+    let request = Request {
+        ticket,
+        permits,
+        waiter: Waiter::Remote(reply_to),
+    };
+    acquire_at_home(id, request);
+    futures::future::ready(()).boxed()
+}
+
+fn semaphore_control_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let id: u64 = e.arg();
+    let ticket: Ticket = e.arg();
+    let control: Control = e.arg();
+    control_at_home(id, ticket, control);
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        ACQUIRE_FN_ID,
+        semaphore_acquire_handler,
+        String::from("semaphore_acquire_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        CONTROL_FN_ID,
+        semaphore_control_handler,
+        String::from("semaphore_control_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+    use std::panic::AssertUnwindSafe;
+
+    fn available(semaphore: &DistSemaphore) -> usize {
+        SEMAPHORES.lock().get(&semaphore.id).unwrap().available
+    }
+
+    #[test]
+    fn test_fair_queuing() {
+        let _a = TestGuardForStatic::new();
+        let semaphore = DistSemaphore::new(2);
+        assert_eq!(semaphore.home(), TEST_HERE);
+        let one = executor::block_on(semaphore.acquire(1));
+        let mut two = semaphore.acquire(2);
+        // a permit is left, but not for an acquire behind a blocked one
+        let mut three = semaphore.global_ref().acquire(1);
+        assert!((&mut two).now_or_never().is_none());
+        assert!((&mut three).now_or_never().is_none());
+        assert_eq!(available(&semaphore), 1);
+        drop(one);
+        let two = two.now_or_never().unwrap();
+        assert_eq!(two.permits(), 2);
+        assert!((&mut three).now_or_never().is_none());
+        drop(two);
+        let three = three.now_or_never().unwrap();
+        assert_eq!(available(&semaphore), 1);
+        drop(three);
+        assert_eq!(available(&semaphore), 2);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let _a = TestGuardForStatic::new();
+        let mutex = DistMutex::new();
+        let guard = executor::block_on(mutex.lock());
+        let mut blocked = mutex.lock();
+        let mut behind = mutex.lock();
+        assert!((&mut blocked).now_or_never().is_none());
+        drop(blocked);
+        drop(guard);
+        assert!((&mut behind).now_or_never().is_some());
+        assert_eq!(available(&mutex.semaphore), 1);
+
+        // a cancel overtaking its acquire
+        let mut state = SemaphoreState {
+            available: 1,
+            ..SemaphoreState::default()
+        };
+        let ticket = (3, 0);
+        assert!(state.control(ticket, Control::Cancel).is_empty());
+        let (tx, _rx) = oneshot::channel();
+        let request = Request {
+            ticket,
+            permits: 1,
+            waiter: Waiter::Local(tx),
+        };
+        assert!(state.acquire(request).is_err());
+        assert_eq!(state.available, 1);
+        assert!(state.cancelled.is_empty());
+        assert!(state.held.is_empty());
+    }
+
+    #[test]
+    fn test_release_on_panic() {
+        let _a = TestGuardForStatic::new();
+        let mutex = DistMutex::new();
+        let global = mutex.global_ref();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            executor::block_on(async {
+                let _guard = global.lock().await;
+                panic!("holder failed");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(available(&mutex.semaphore), 1);
+        drop(executor::block_on(mutex.lock()));
+    }
+
+    #[test]
+    fn test_dropped_semaphore() {
+        let _a = TestGuardForStatic::new();
+        let semaphore = DistSemaphore::new(1);
+        let global = semaphore.global_ref();
+        let guard = executor::block_on(global.acquire(1));
+        let mut waiting = global.acquire(1);
+        assert!((&mut waiting).now_or_never().is_none());
+        drop(semaphore);
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| executor::block_on(waiting)));
+        assert!(result.is_err());
+        // released after the semaphore is gone
+        drop(guard);
+        assert!(SEMAPHORES.lock().is_empty());
+    }
+}
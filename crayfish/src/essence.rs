@@ -1,12 +1,17 @@
-use crate::activity::copy_panic_payload;
+use crate::activity;
+use crate::activity::cast_panic_payload;
 use crate::activity::ActivityId;
 use crate::activity::FunctionLabel;
+use crate::activity::PanicPayload;
 use crate::activity::SquashBufferFactory;
 use crate::activity::TaskItem;
 use crate::activity::TaskItemBuilder;
 use crate::args::RemoteSend;
+use crate::collecting;
 use crate::collective;
+use crate::context::TaskContext;
 use crate::executor;
+use crate::finish;
 use crate::logging;
 use crate::logging::*;
 use crate::meta_data;
@@ -14,6 +19,7 @@ use crate::network::context::CommunicationContext;
 use crate::network::MessageHandler;
 use crate::network::Rank;
 use crate::place;
+use crate::rma;
 use crate::runtime::init_task_item_channels;
 use crate::runtime::init_worker_task_queue;
 use crate::runtime::message_recv_callback;
@@ -29,33 +35,55 @@ use futures::future::FutureExt;
 use futures::Future;
 use std::thread;
 
-pub fn send_activity_result<T: RemoteSend>(
-    ctx: impl ApgasContext,
+// report the activity to its finish, return activities it spawned
+fn send_to_finish<T>(
+    mut ctx: impl ApgasContext,
     a_id: ActivityId,
     fn_id: FunctionLabel,
-    waited: bool,
-    result: std::thread::Result<T>,
-) {
+    result: &Result<T, PanicPayload>,
+) -> Vec<ActivityId> {
     let finish_id = a_id.get_finish_id();
-    let stripped_result = match &result {
+    if !finish_id.get_mode().is_tree() {
+        let panic = result.as_ref().err().cloned();
+        finish::count_done(a_id, fn_id, panic, ctx.collecting());
+        return ctx.spawned(); // always empty, nothing is tracked by the context
+    }
+    let stripped_result = match result {
         // copy payload
-        Ok(_) => thread::Result::<()>::Ok(()),
-        Err(e) => thread::Result::<()>::Err(copy_panic_payload(e)),
+        Ok(_) => Ok(()),
+        Err(e) => Err(e.clone()),
     };
 
     // TODO panic all or panic single?
     // should set dst place of return to it's finishid, to construct calling tree
     let mut builder = TaskItemBuilder::new(fn_id, finish_id.get_place(), a_id);
+    let destinations = ctx.destinations();
+    let collecting = ctx.collecting();
     let spawned_activities = ctx.spawned(); // get activity spawned in real_fn
-    builder.ret(stripped_result); // strip return value
+    builder.ret_result(stripped_result); // strip return value
     builder.sub_activities(spawned_activities.clone());
+    builder.destinations(destinations);
+    // piggyback values offered at this place
+    builder.collected(collecting::take_partials(finish_id, collecting));
     let item = builder.build_box();
     ConcreteContext::send(item);
+    spawned_activities
+}
+
+pub fn send_activity_result<T: RemoteSend>(
+    ctx: impl ApgasContext,
+    a_id: ActivityId,
+    fn_id: FunctionLabel,
+    waited: bool,
+    result: std::thread::Result<T>,
+) {
+    let result = result.map_err(cast_panic_payload);
+    let spawned_activities = send_to_finish(ctx, a_id, fn_id, &result);
     // send to the place waited (spawned)
     if waited {
         // two ret must be identical if dst is the same place
         let mut builder = TaskItemBuilder::new(fn_id, a_id.get_spawned_place(), a_id);
-        builder.ret(result); // macro
+        builder.ret_result(result); // macro
         builder.sub_activities(spawned_activities);
         builder.waited();
         let item = builder.build_box();
@@ -63,6 +91,19 @@ pub fn send_activity_result<T: RemoteSend>(
     }
 }
 
+/// For an activity spawned and waited at the same place, only the finish needs a report.
+/// The return value is handed to the waiter directly
+pub fn local_activity_result<T>(
+    ctx: impl ApgasContext,
+    a_id: ActivityId,
+    fn_id: FunctionLabel,
+    result: std::thread::Result<T>,
+) -> Result<T, PanicPayload> {
+    let result = result.map_err(cast_panic_payload);
+    send_to_finish(ctx, a_id, fn_id, &result);
+    result
+}
+
 fn worker_dispatch(item: TaskItem) -> BoxFuture<'static, ()> {
     let fn_id = item.function_id();
     let resovled = runtime_meta::get_func_table().get(&fn_id).unwrap();
@@ -83,6 +124,9 @@ where
     // logger
     logging::setup_logger().unwrap();
 
+    // keep backtraces of panicking activities
+    activity::init_panic_hook();
+
     // print setting
     meta_data::show_data();
 
@@ -112,6 +156,7 @@ where
 
     // init collective operator, which will be used by main
     init_collective_operator(&context);
+    rma::set_rma(Box::new(context.rma_operator()));
 
     // prepare distributor
     let sender = context.single_sender();
@@ -158,8 +203,13 @@ where
 
     // main
     let ret = rt.block_on(async move {
+        // fn_id must mean the same function everywhere before any activity is spawned
+        runtime_meta::check_func_table().await;
+        meta_data::check_am_atomics().await;
         executor::spawn(worker_loop);
-        executor::spawn(main_fut).await.unwrap()
+        executor::spawn(crate::context::scope(TaskContext::default(), main_fut))
+            .await
+            .unwrap()
     });
 
     // should not perform any network before init done
@@ -168,6 +218,7 @@ where
     let mut coll = collective::take_coll();
     rt.block_on(coll.barrier().map(|r| r.unwrap()));
     trigger.stop();
+    drop(rma::take_rma());
     drop(coll); // drop coll to stop network context
 
     hub_thread.join().unwrap();
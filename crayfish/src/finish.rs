@@ -1,18 +1,171 @@
+use crate::activity::ActivityFrame;
 use crate::activity::ActivityId;
 use crate::activity::FunctionLabel;
+use crate::activity::PanicPayload;
+use crate::activity::RemotePanic;
 use crate::activity::TaskItem;
+use crate::activity::TaskItemBuilder;
 use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::collecting;
+use crate::place;
 use crate::place::Place;
+use crate::runtime::ApgasContext;
+use crate::runtime::ConcreteContext;
+use once_cell::sync::Lazy;
 use rustc_hash::FxHashMap;
 use rustc_hash::FxHashSet;
+use serde::Deserialize;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
 
 pub use crate::global_id::FinishId;
 
+/// Termination detection protocol of a finish, selected by `#[finish_attr(mode = "..")]`
+#[derive(
+    Copy, Clone, Debug, Default, Hash, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize,
+)]
+pub enum FinishMode {
+    /// arbitrary spawning, tracked by a calling tree at the finish place
+    #[default]
+    Default,
+    /// all activities run at the finish place, counted there without any return item
+    Local,
+    /// activities only spawn nested ones at their own place. Each place reports a count
+    /// when it runs out of activities of the finish
+    Spmd,
+    /// activities spawned by the finish block spawn nothing under it
+    Here,
+    /// like the default mode, but the calling tree also tracks where activities run. When a
+    /// place fails, activities at it are lost, and the finish completes with a `DeadPlaceError`
+    Resilient,
+}
+
+impl FinishMode {
+    /// whether the finish is tracked by a calling tree, instead of counted
+    pub fn is_tree(&self) -> bool {
+        matches!(self, FinishMode::Default | FinishMode::Resilient)
+    }
+}
+
+/// An activity of a resilient finish, lost with the place it runs at
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LostActivity {
+    id: ActivityId,
+    place: Place,
+}
+
+impl LostActivity {
+    pub fn id(&self) -> ActivityId {
+        self.id
+    }
+    /// the dead place the activity was spawned to
+    pub fn place(&self) -> Place {
+        self.place
+    }
+}
+
+impl fmt::Display for LostActivity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "activity {} at place {}, spawned by place {}",
+            self.id,
+            self.place,
+            self.id.get_spawned_place()
+        )
+    }
+}
+
+/// A resilient finish whose activities are lost with failed places. Activities at surviving
+/// places are all done, except those spawned by lost activities and not reported yet
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeadPlaceError {
+    places: Vec<Place>,
+    lost: Vec<LostActivity>,
+    panic: Option<RemotePanic>,
+}
+
+impl DeadPlaceError {
+    /// the failed places, in the order of their notification
+    pub fn places(&self) -> &[Place] {
+        &self.places[..]
+    }
+    /// activities running at the failed places when they fail
+    pub fn lost(&self) -> &[LostActivity] {
+        &self.lost[..]
+    }
+    /// the first panic among surviving activities, if any
+    pub fn panic(&self) -> Option<&RemotePanic> {
+        self.panic.as_ref()
+    }
+}
+
+impl fmt::Display for DeadPlaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "place(s) {:?} failed, {} activities of the finish are lost",
+            self.places,
+            self.lost.len()
+        )?;
+        for (i, lost) in self.lost.iter().enumerate() {
+            write!(f, "\n  {}: {}", i, lost)?;
+        }
+        if let Some(panic) = self.panic.as_ref() {
+            write!(f, "\nand a surviving {}", panic)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DeadPlaceError {}
+
+impl RemoteSend for DeadPlaceError {
+    crate::impl_body! {}
+}
+
+/// Why a finish completes abnormally
+#[derive(Debug)]
+pub enum FinishError {
+    /// an activity panicked
+    Panicked(RemotePanic),
+    /// places of a resilient finish failed
+    DeadPlace(DeadPlaceError),
+}
+
+impl fmt::Display for FinishError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FinishError::Panicked(e) => e.fmt(f),
+            FinishError::DeadPlace(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for FinishError {}
+
+impl From<RemotePanic> for FinishError {
+    fn from(e: RemotePanic) -> Self {
+        FinishError::Panicked(e)
+    }
+}
+
+impl From<DeadPlaceError> for FinishError {
+    fn from(e: DeadPlaceError) -> Self {
+        FinishError::DeadPlace(e)
+    }
+}
+
 #[derive(Debug, Clone)]
 struct FrameInfo {
     fn_id: FunctionLabel,
     place: Place,
-    panic_payload: Option<String>,
+    panic_payload: Option<PanicPayload>,
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +174,7 @@ struct CallingTreeNode {
     frame: Option<FrameInfo>,
     parent: Option<ActivityId>,
     children: FxHashSet<ActivityId>,
+    place: Option<Place>, // where a spawned activity runs, tracked by resilient finishes
 }
 
 #[derive(Debug)]
@@ -28,6 +182,9 @@ pub struct CallingTree {
     lookup_table: FxHashMap<ActivityId, CallingTreeNode>,
     panic_backtrace: Vec<FrameInfo>,
     panic_backtrace_top: Option<ActivityId>,
+    outstanding: usize, // spawned activities not reported yet
+    dead_places: Vec<Place>,
+    lost: Vec<LostActivity>,
 }
 
 fn root_id() -> ActivityId {
@@ -36,15 +193,29 @@ fn root_id() -> ActivityId {
 
 impl CallingTree {
     pub fn new(initial_call: Vec<ActivityId>) -> Self {
+        Self::new_resilient(initial_call, vec![])
+    }
+    /// a tree also tracking the places of spawned activities, given along with them
+    pub fn new_resilient(initial_call: Vec<ActivityId>, destinations: Vec<Place>) -> Self {
         let mut tree = CallingTree {
             lookup_table: FxHashMap::default(),
             panic_backtrace: Vec::new(),
             panic_backtrace_top: None,
+            outstanding: 0,
+            dead_places: Vec::new(),
+            lost: Vec::new(),
         };
-        tree.new_root(root_id(), &initial_call[..], None);
+        tree.new_root(root_id(), &initial_call[..], &destinations[..], None);
         tree
     }
-    fn new_root(&mut self, id: ActivityId, children: &[ActivityId], frame: Option<FrameInfo>) {
+    fn new_root(
+        &mut self,
+        id: ActivityId,
+        children: &[ActivityId],
+        destinations: &[Place],
+        frame: Option<FrameInfo>,
+    ) {
+        debug_assert!(destinations.is_empty() || destinations.len() == children.len());
         self.lookup_table.insert(
             id,
             CallingTreeNode {
@@ -52,12 +223,15 @@ impl CallingTree {
                 frame,
                 parent: None,
                 children: children.iter().cloned().collect(),
+                place: None,
             },
         ); // could overide virtual node
-        for child in children.iter() {
+        for (i, child) in children.iter().enumerate() {
             if !self.lookup_table.contains_key(child) {
                 // child might exist
-                self.new_root(*child, &[], None);
+                self.new_root(*child, &[], &[], None);
+                self.lookup_table.get_mut(child).unwrap().place = destinations.get(i).cloned();
+                self.outstanding += 1;
             }
             self.link_parent_child(id, *child);
         }
@@ -77,7 +251,9 @@ impl CallingTree {
             debug_assert!(self.lookup_table.contains_key(&root_id()));
             return true;
         }
-        false
+        // Nodes left without any outstanding activity are reported ones, whose parents never
+        // report since they are lost or spawned by lost ones
+        !self.lost.is_empty() && self.outstanding == 0
     }
 
     pub fn activity_done(&mut self, item: TaskItem) {
@@ -88,10 +264,14 @@ impl CallingTree {
         let place = ex.activity_id().get_spawned_place();
         let activity_id = ex.activity_id();
         let sub_activities = ex.sub_activities();
+        let destinations = ex.destinations();
+        let absorbed = collecting::absorb(ex.collected());
         let panic_payload = match ex.ret_panic() {
             Ok(()) => None,
             Err(e) => Some(e),
         };
+        // a disagreement on the reducer is a panic of the finish, not of the runtime
+        let panic_payload = panic_payload.or_else(|| absorbed.err().map(PanicPayload::new));
 
         let frame = FrameInfo {
             fn_id,
@@ -100,12 +280,24 @@ impl CallingTree {
         };
         match self.lookup_table.get_mut(&activity_id) {
             Some(node) => {
+                debug_assert!(node.frame.is_none());
                 let parent_id = node.parent.unwrap();
+                self.outstanding -= 1;
                 // new root will override existing and link
-                self.new_root(activity_id, &sub_activities[..], Some(frame));
+                self.new_root(
+                    activity_id,
+                    &sub_activities[..],
+                    &destinations[..],
+                    Some(frame),
+                );
                 self.link_parent_child(parent_id, activity_id);
             }
-            None => self.new_root(activity_id, &sub_activities[..], Some(frame)),
+            None => self.new_root(
+                activity_id,
+                &sub_activities[..],
+                &destinations[..],
+                Some(frame),
+            ),
         }
 
         if sub_activities.is_empty() {
@@ -120,10 +312,56 @@ impl CallingTree {
                 }
             }
         }
+        if !self.dead_places.is_empty() {
+            // spawned to a place already failed
+            self.reap_lost();
+        }
 
         crayfish_trace_macros::profiling_stop_internal!();
     }
 
+    /// Activities spawned to the failed place and not reported are lost. The tree stops
+    /// waiting for them
+    pub fn place_failed(&mut self, place: Place) {
+        if !self.dead_places.contains(&place) {
+            self.dead_places.push(place);
+            self.reap_lost();
+        }
+    }
+
+    fn reap_lost(&mut self) {
+        let dead_places = &self.dead_places;
+        let lost: Vec<_> = self
+            .lookup_table
+            .values()
+            .filter(|node| node.frame.is_none() && node.id != root_id())
+            .filter_map(|node| match node.place {
+                Some(place) if dead_places.contains(&place) => {
+                    Some(LostActivity { id: node.id, place })
+                }
+                _ => None,
+            })
+            .collect();
+        for lost in lost {
+            // a virtual node always has its parent
+            let node = self.lookup_table.remove(&lost.id).unwrap();
+            debug_assert!(node.children.is_empty());
+            let parent_id = node.parent.unwrap();
+            self.outstanding -= 1;
+            self.lost.push(lost);
+            let parent = self.lookup_table.get_mut(&parent_id).unwrap();
+            parent.children.remove(&lost.id);
+            if parent.children.is_empty() {
+                self.try_purge(parent_id);
+            }
+        }
+    }
+
+    /// activities known spawned but not done
+    pub fn outstanding(&self) -> usize {
+        self.outstanding
+    }
+
     pub fn try_purge(&mut self, mut current_node_id: ActivityId) {
         // purge single branch
         // only remove has parent and children is empty
@@ -154,33 +392,290 @@ impl CallingTree {
         }
     }
 
-    pub fn panic_backtrace(self) -> Option<String> {
-        let format_frame = |frameinfo: FrameInfo| {
-            let mut line = format!(
-                "at function: {} spawned by place {}",
-                frameinfo.fn_id, frameinfo.place,
-            );
-            if let Some(msg) = frameinfo.panic_payload {
-                line.push_str(&format!(" panic: {}", msg));
-            }
-            line
-        };
+    /// the error of a completed tree, if some activities are lost
+    pub fn completion(self) -> Option<FinishError> {
+        if self.lost.is_empty() {
+            return self.panic_backtrace().map(FinishError::Panicked);
+        }
+        let places = self.dead_places.clone();
+        let mut lost = self.lost.clone();
+        lost.sort_by_key(|lost| (lost.place, lost.id));
+        Some(FinishError::DeadPlace(DeadPlaceError {
+            places,
+            lost,
+            panic: self.panic_backtrace(),
+        }))
+    }
 
+    pub fn panic_backtrace(self) -> Option<RemotePanic> {
         debug_assert!(self.all_done());
-        if self.panic_backtrace.is_empty() {
-            None
-        } else {
-            Some(
-                self.panic_backtrace
-                    .into_iter()
-                    .map(format_frame)
-                    .collect::<Vec<_>>()
-                    .join("\n"),
-            )
+        let mut frames = self.panic_backtrace.into_iter();
+        // the first frame is where the panic happens
+        let first = frames.next()?;
+        let payload = first.panic_payload.unwrap();
+        let frames = std::iter::once(ActivityFrame::new(first.fn_id, first.place))
+            .chain(frames.map(|frame| ActivityFrame::new(frame.fn_id, frame.place)))
+            .collect();
+        Some(RemotePanic::new(payload, frames))
+    }
+}
+
+/// The `?` operator in finish blocks, a stable counterpart of `std::ops::Try`
+pub trait FinishTry {
+    type Output;
+    type Residual;
+    fn branch(self) -> ControlFlow<Self::Residual, Self::Output>;
+}
+
+/// Return type of a function, into which `?` in its finish blocks converts a residual
+pub trait FromFinishResidual<R> {
+    fn from_residual(residual: R) -> Self;
+}
+
+impl<T, E> FinishTry for Result<T, E> {
+    type Output = T;
+    type Residual = Result<Infallible, E>;
+    fn branch(self) -> ControlFlow<Self::Residual, Self::Output> {
+        match self {
+            Ok(t) => ControlFlow::Continue(t),
+            Err(e) => ControlFlow::Break(Err(e)),
+        }
+    }
+}
+
+impl<T, E, F: From<E>> FromFinishResidual<Result<Infallible, E>> for Result<T, F> {
+    fn from_residual(residual: Result<Infallible, E>) -> Self {
+        match residual {
+            Ok(never) => match never {},
+            Err(e) => Err(From::from(e)),
         }
     }
 }
 
+impl<T> FinishTry for Option<T> {
+    type Output = T;
+    type Residual = Option<Infallible>;
+    fn branch(self) -> ControlFlow<Self::Residual, Self::Output> {
+        match self {
+            Some(t) => ControlFlow::Continue(t),
+            None => ControlFlow::Break(None),
+        }
+    }
+}
+
+impl<T> FromFinishResidual<Option<Infallible>> for Option<T> {
+    fn from_residual(_residual: Option<Infallible>) -> Self {
+        None
+    }
+}
+
+/// What a place reports to a counting finish
+#[derive(Debug, Serialize, Deserialize)]
+struct FinishReport {
+    delta: isize,
+    panic: Option<RemotePanic>,
+}
+
+impl RemoteSend for FinishReport {
+    crate::impl_body! {}
+}
+
+/// Termination detection of finishes not tracked by a calling tree, at the finish place
+#[derive(Debug)]
+pub struct CountingFinish {
+    pending: isize,
+    panic: Option<RemotePanic>,
+}
+
+impl CountingFinish {
+    pub fn new(finish_id: FinishId) -> Self {
+        // activities spawned by the finish block are counted at the finish place
+        let count = take_place_count(finish_id);
+        CountingFinish {
+            pending: count.delta,
+            panic: count.panic,
+        }
+    }
+
+    pub fn all_done(&self) -> bool {
+        debug_assert!(self.pending >= 0);
+        self.pending == 0
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.pending.max(0) as usize
+    }
+
+    pub fn activity_done(&mut self, item: TaskItem) {
+        let mut ex = TaskItemExtracter::new(item);
+        let absorbed = collecting::absorb(ex.collected());
+        let fn_id = ex.fn_id();
+        let place = ex.activity_id().get_spawned_place();
+        let report = ex
+            .ret::<FinishReport>()
+            .expect("finish report should not carry a panic");
+        self.pending += report.delta;
+        if self.panic.is_none() {
+            self.panic = report.panic;
+        }
+        if let (None, Err(message)) = (&self.panic, absorbed) {
+            let frame = ActivityFrame::new(fn_id, place);
+            self.panic = Some(RemotePanic::new(PanicPayload::new(message), vec![frame]));
+        }
+    }
+
+    pub fn panic_backtrace(self) -> Option<RemotePanic> {
+        debug_assert!(self.all_done());
+        self.panic
+    }
+
+    pub fn completion(self) -> Option<FinishError> {
+        self.panic_backtrace().map(FinishError::Panicked)
+    }
+}
+
+#[derive(Debug, Default)]
+struct PlaceCount {
+    delta: isize, // spawned minus done, since the last report
+    panic: Option<RemotePanic>,
+    waiter: Option<oneshot::Sender<Option<RemotePanic>>>, // local mode only
+}
+
+static PLACE_COUNTS: Lazy<Mutex<FxHashMap<FinishId, PlaceCount>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn take_place_count(finish_id: FinishId) -> PlaceCount {
+    PLACE_COUNTS
+        .lock()
+        .unwrap()
+        .remove(&finish_id)
+        .unwrap_or_default()
+}
+
+fn send_report(
+    finish_id: FinishId,
+    a_id: ActivityId,
+    fn_id: FunctionLabel,
+    count: PlaceCount,
+    collecting: Option<FinishId>,
+) {
+    let report = FinishReport {
+        delta: count.delta,
+        panic: count.panic,
+    };
+    let mut builder = TaskItemBuilder::new(fn_id, finish_id.get_place(), a_id);
+    builder.ret_result(Ok(report));
+    builder.collected(collecting::take_partials(finish_id, collecting));
+    ConcreteContext::send(builder.build_box());
+}
+
+/// Check the destination of an activity spawned under a counting finish, before anything is
+/// counted or sent. A panic here is a panic of the spawning activity, reported to the finish
+/// as usual, instead of a broken count at another place
+pub(crate) fn check_spawn(finish_id: FinishId, from_finish_block: bool, dst: Place) {
+    match finish_id.get_mode() {
+        FinishMode::Local => assert_eq!(
+            dst,
+            finish_id.get_place(),
+            "activities under a local finish must stay at its place"
+        ),
+        FinishMode::Spmd if !from_finish_block => assert_eq!(
+            dst,
+            place::here(),
+            "activities under a finish of mode spmd can only spawn at their own place, use a \
+             nested finish"
+        ),
+        _ => (),
+    }
+}
+
+/// count an activity spawned under a finish not tracked by a calling tree
+pub(crate) fn count_spawn(finish_id: FinishId, from_finish_block: bool) {
+    match finish_id.get_mode() {
+        FinishMode::Default | FinishMode::Resilient => unreachable!(),
+        // destinations are checked by check_spawn
+        FinishMode::Local | FinishMode::Spmd => (),
+        FinishMode::Here => assert!(
+            from_finish_block,
+            "activities under a finish of mode here can not spawn, use a nested finish"
+        ),
+    }
+    let mut counts = PLACE_COUNTS.lock().unwrap();
+    counts.entry(finish_id).or_default().delta += 1;
+}
+
+/// count an activity done under a finish not tracked by a calling tree
+pub(crate) fn count_done(
+    a_id: ActivityId,
+    fn_id: FunctionLabel,
+    panic: Option<PanicPayload>,
+    collecting: Option<FinishId>,
+) {
+    let finish_id = a_id.get_finish_id();
+    let panic = panic.map(|payload| {
+        let frame = ActivityFrame::new(fn_id, a_id.get_spawned_place());
+        RemotePanic::new(payload, vec![frame])
+    });
+    match finish_id.get_mode() {
+        FinishMode::Default | FinishMode::Resilient => unreachable!(),
+        FinishMode::Here => {
+            let count = PlaceCount {
+                delta: -1,
+                panic,
+                waiter: None,
+            };
+            send_report(finish_id, a_id, fn_id, count, collecting);
+        }
+        FinishMode::Local | FinishMode::Spmd => {
+            // guaranteed by check_spawn, never panic with the counts locked
+            debug_assert!(
+                finish_id.get_mode() != FinishMode::Local || finish_id.get_place() == place::here()
+            );
+            let mut counts = PLACE_COUNTS.lock().unwrap();
+            let count = counts.entry(finish_id).or_default();
+            count.delta -= 1;
+            if count.panic.is_none() {
+                count.panic = panic;
+            }
+            if finish_id.get_mode() == FinishMode::Local {
+                if count.delta == 0 && count.waiter.is_some() {
+                    let count = counts.remove(&finish_id).unwrap();
+                    let _ = count.waiter.unwrap().send(count.panic); // the waiter may be dropped
+                }
+            } else if count.delta < 0 {
+                // children spawned here are counted before their parent is done, so any
+                // report is safe. Only report when no child is known alive to save messages
+                let count = counts.remove(&finish_id).unwrap();
+                drop(counts);
+                send_report(finish_id, a_id, fn_id, count, collecting);
+            }
+        }
+    }
+}
+
+/// activities of a finish counted at this place but not reported yet
+pub(crate) fn place_outstanding(finish_id: FinishId) -> usize {
+    match PLACE_COUNTS.lock().unwrap().get(&finish_id) {
+        Some(count) => count.delta.max(0) as usize,
+        None => 0,
+    }
+}
+
+/// wait a finish in the local mode, resolve to the first panic
+pub(crate) async fn wait_local_finish(finish_id: FinishId) -> Option<RemotePanic> {
+    let rx = {
+        let mut counts = PLACE_COUNTS.lock().unwrap();
+        let count = counts.entry(finish_id).or_default();
+        if count.delta == 0 {
+            return counts.remove(&finish_id).unwrap().panic;
+        }
+        let (tx, rx) = oneshot::channel();
+        count.waiter = Some(tx);
+        rx
+    };
+    rx.await.unwrap()
+}
+
 #[cfg(test)]
 mod test {
 
@@ -236,9 +731,11 @@ mod test {
         let mut tree = CallingTree::new(vec![ActivityId::from(1)]);
         for item in items {
             assert!(!tree.all_done());
+            assert_eq!(tree.outstanding(), 1);
             tree.activity_done(*item);
         }
         assert!(tree.all_done());
+        assert_eq!(tree.outstanding(), 0);
         assert!(tree.panic_backtrace().is_none());
 
         // test a chain with panic
@@ -253,7 +750,11 @@ mod test {
         assert_eq!(tree.panic_backtrace.len(), 10);
         let bt = tree.panic_backtrace();
         // println!("{}", bt.as_ref().unwrap());
-        assert!(bt.is_some());
+        let bt = bt.unwrap();
+        assert_eq!(bt.message(), "panic here");
+        assert_eq!(bt.activity().unwrap().fn_id(), 10);
+        let fn_ids: Vec<_> = bt.frames().iter().map(|f| f.fn_id()).collect();
+        assert_eq!(fn_ids, (1..11).rev().collect::<Vec<FunctionLabel>>());
     }
 
     #[test]
@@ -269,6 +770,7 @@ mod test {
             tree.activity_done(*item);
         }
         assert!(tree.all_done());
+        assert_eq!(tree.outstanding(), 0);
         assert!(tree.panic_backtrace().is_none());
 
         // test reorder panic
@@ -350,6 +852,7 @@ mod test {
             tree.activity_done(*item);
         }
         assert!(tree.all_done());
+        assert_eq!(tree.outstanding(), 0);
         assert!(tree.panic_backtrace().is_none());
 
         let items = build_tree(5, 2, Some(vec![31]));
@@ -377,6 +880,7 @@ mod test {
             tree.activity_done(*item);
         }
         assert!(tree.all_done());
+        assert_eq!(tree.outstanding(), 0);
         assert!(tree.panic_backtrace().is_none());
 
         // panic
@@ -429,4 +933,71 @@ mod test {
         // println!("{}", bt.as_ref().unwrap());
         assert!(bt.is_some());
     }
+
+    fn report(a_id: usize, sub_activities: Vec<(usize, Place)>, panic: bool) -> TaskItem {
+        let mut b = TaskItemBuilder::new(a_id as FunctionLabel, 0, ActivityId::from(a_id));
+        if panic {
+            let panic_payload = Box::new(String::from("panic here"));
+            b.ret(Result::<usize>::Err(
+                panic_payload as Box<dyn Any + Send + 'static>,
+            ));
+        } else {
+            b.ret(Result::Ok(1usize));
+        }
+        let (ids, places) = sub_activities
+            .into_iter()
+            .map(|(id, place)| (ActivityId::from(id), place))
+            .unzip();
+        b.sub_activities(ids);
+        b.destinations(places);
+        b.build()
+    }
+
+    #[test]
+    pub fn test_resilient_tree() {
+        // 1 spawns 2 at place 2 and 3 at place 3, 2 spawns 4 at place 4 and 5 at place 2
+        let mut tree = CallingTree::new_resilient(vec![ActivityId::from(1)], vec![1]);
+        tree.activity_done(report(1, vec![(2, 2), (3, 3)], false));
+        tree.activity_done(report(2, vec![(4, 4), (5, 2)], false));
+        // spawned by 5 before place 2 fails
+        tree.activity_done(report(6, vec![], false));
+        tree.place_failed(2);
+        tree.place_failed(2);
+        assert_eq!(tree.outstanding(), 2);
+        assert!(!tree.all_done());
+        tree.activity_done(report(3, vec![], true));
+        assert!(!tree.all_done());
+        // spawned to a place already failed
+        tree.activity_done(report(4, vec![(7, 2)], false));
+        assert!(tree.all_done());
+        assert_eq!(tree.outstanding(), 0);
+        match tree.completion() {
+            Some(FinishError::DeadPlace(e)) => {
+                assert_eq!(e.places(), &[2]);
+                let lost: Vec<_> = e.lost().iter().map(|lost| lost.id()).collect();
+                assert_eq!(lost, vec![ActivityId::from(5), ActivityId::from(7)]);
+                assert!(e.lost().iter().all(|lost| lost.place() == 2));
+                assert_eq!(e.panic().unwrap().message(), "panic here");
+            }
+            e => panic!("unexpected completion {:?}", e),
+        }
+    }
+
+    #[test]
+    pub fn test_resilient_tree_no_loss() {
+        // nothing at the failed place
+        let mut tree = CallingTree::new_resilient(vec![ActivityId::from(1)], vec![1]);
+        tree.activity_done(report(1, vec![(2, 3)], false));
+        tree.place_failed(2);
+        assert!(!tree.all_done());
+        tree.activity_done(report(2, vec![], false));
+        assert!(tree.all_done());
+        assert!(tree.completion().is_none());
+
+        // a tree of the default mode does not know where activities run
+        let mut tree = CallingTree::new(vec![ActivityId::from(1)]);
+        tree.place_failed(1);
+        assert!(!tree.all_done());
+        assert_eq!(tree.outstanding(), 1);
+    }
 }
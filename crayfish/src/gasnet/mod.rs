@@ -1,14 +1,19 @@
 use crate::logging;
 use crate::logging::*;
+use crate::meta_data;
+use crate::network::AtomicOp;
 use crate::network::CollectiveOperator;
 use crate::network::MessageHandler;
 use crate::network::MessageSender;
 use crate::network::Rank;
+use crate::network::RmaOperator;
+use crate::network::Transfers;
 use crate::serialization;
 use bit_vec::BitVec;
 use futures::channel::oneshot;
 use gex_sys::*;
 use rustc_hash::FxHashMap;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::os::raw::*;
 use std::ptr::null_mut;
@@ -42,6 +47,11 @@ enum NetworkOperation {
     Barrier(oneshot::Sender<()>),
     Broadcast(Rank, *mut u8, usize, oneshot::Sender<()>),
     AllGather(Vec<u8>, oneshot::Sender<Vec<Vec<u8>>>),
+    Alloc(usize, usize, oneshot::Sender<Option<(usize, usize)>>),
+    Free(usize),
+    Put(Rank, Transfers, Vec<u8>, oneshot::Sender<Vec<u8>>),
+    Get(Rank, Transfers, usize, oneshot::Sender<Vec<u8>>),
+    Atomic(Rank, usize, AtomicOp, oneshot::Sender<u64>),
 }
 unsafe impl Send for NetworkOperation {} // ptr is not send, but we are playing with unsafe!
 
@@ -206,6 +216,72 @@ impl CollectiveEventTrait for GexEvent {
     }
 }
 
+// transfers of one put or get, done when all gex events are done
+struct RmaEvent {
+    gex_events: Vec<gex_Event_t>,
+    buffer: Vec<u8>, // source of put or destination of get, must live until done
+    notifier: oneshot::Sender<Vec<u8>>,
+}
+
+impl RmaEvent {
+    fn test(&mut self) -> bool {
+        self.gex_events.retain(|e| !gex_event_done(*e));
+        self.gex_events.is_empty()
+    }
+
+    fn notify(self) {
+        // the requester might be gone
+        let _ = self.notifier.send(self.buffer);
+    }
+}
+
+struct AtomicEvent {
+    gex_event: gex_Event_t,
+    result: Box<u64>, // written by gasnet, must live until done
+    notifier: oneshot::Sender<u64>,
+}
+
+// First fit allocator of symmetric buffers in the segment. Every place allocates and frees
+// buffers in the same order, so a buffer has the same offset at every place.
+#[derive(Default)]
+struct SegmentAllocator {
+    len: usize,
+    used: BTreeMap<usize, usize>, // offset -> len
+}
+
+impl SegmentAllocator {
+    fn new(len: usize) -> Self {
+        SegmentAllocator {
+            len,
+            used: BTreeMap::new(),
+        }
+    }
+
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let size = usize::max(size, 1); // buffers never share an offset
+        let round_up = |offset: usize| offset + (align - offset % align) % align;
+        let mut start = 0;
+        for (&offset, &len) in self.used.iter() {
+            if round_up(start) + size <= offset {
+                break;
+            }
+            start = offset + len;
+        }
+        let start = round_up(start);
+        if start + size > self.len {
+            return None;
+        }
+        self.used.insert(start, size);
+        Some(start)
+    }
+
+    fn free(&mut self, offset: usize) {
+        self.used
+            .remove(&offset)
+            .expect("free a buffer not allocated in the segment");
+    }
+}
+
 type MessageType = gex_AM_Arg_t;
 const MESSAGE_TYPE_NORMAL: MessageType = 0;
 const MESSAGE_TYPE_COLL: MessageType = 1;
@@ -311,6 +387,12 @@ pub struct CommunicationContext<T> {
 
     // for collective
     cctx: CollectiveContext,
+
+    // for rma
+    segment_allocator: SegmentAllocator,
+    rma_events: Vec<RmaEvent>,
+    atomic_domain: Option<gex_AD_t>,
+    atomic_events: Vec<AtomicEvent>,
 }
 
 type CollectiveEventId = usize;
@@ -615,6 +697,10 @@ where
             op_receiver: rx,
             op_sender: Some(tx),
             cctx: Default::default(),
+            segment_allocator: Default::default(),
+            rma_events: vec![],
+            atomic_domain: None,
+            atomic_events: vec![],
         };
 
         logging::set_global_id(context.here().as_i32());
@@ -662,6 +748,18 @@ where
             self.tctx.message_buffers.push(FxHashMap::default());
         }
         debug!("Endpoint data: {:?}", self.tctx.endpoints_data);
+        // symmetric buffers must fit in the smallest segment
+        let symmetric_len = self
+            .tctx
+            .endpoints_data
+            .iter()
+            .map(|e| e.segment_len)
+            .min()
+            .unwrap();
+        self.segment_allocator = SegmentAllocator::new(symmetric_len);
+        if !*meta_data::AM_ATOMICS {
+            self.atomic_domain = Some(gex_ad_create_u64(self.tctx.team));
+        }
         // set proper ptr
         unsafe {
             // WARN: danger!
@@ -704,6 +802,132 @@ where
         self.cctx.poll(&mut self.tctx)
     }
 
+    fn alloc(
+        &mut self,
+        size: usize,
+        align: usize,
+        notify: oneshot::Sender<Option<(usize, usize)>>,
+    ) {
+        let local_addr = self.tctx.endpoints_data[self.here().as_usize()].segment_addr as usize;
+        let allocated = self.segment_allocator.alloc(size, align);
+        let _ = notify.send(allocated.map(|offset| (offset, local_addr + offset)));
+    }
+
+    // addresses of transfers in the segment of the rank
+    fn remote_addrs(&self, rank: Rank, transfers: &[(usize, usize)]) -> Vec<*mut c_void> {
+        let endpoint = &self.tctx.endpoints_data[rank.as_usize()];
+        transfers
+            .iter()
+            .map(|&(offset, len)| {
+                assert!(
+                    offset + len <= endpoint.segment_len,
+                    "transfer out of the segment of {}",
+                    rank
+                );
+                unsafe { (endpoint.segment_addr as *mut u8).add(offset) as *mut c_void }
+            })
+            .collect()
+    }
+
+    fn put(
+        &mut self,
+        dst: Rank,
+        transfers: Transfers,
+        buffer: Vec<u8>,
+        notify: oneshot::Sender<Vec<u8>>,
+    ) {
+        let addrs = self.remote_addrs(dst, &transfers[..]);
+        let mut pos = 0;
+        let mut gex_events = vec![];
+        for (addr, (_, len)) in addrs.into_iter().zip(transfers) {
+            let src = buffer[pos..pos + len].as_ptr() as *const c_void;
+            gex_events.push(unsafe {
+                gex_rma_put_nb(self.tctx.team, dst.gex_rank(), addr, src, len as size_t)
+            });
+            pos += len;
+        }
+        self.rma_events.push(RmaEvent {
+            gex_events,
+            buffer,
+            notifier: notify,
+        });
+    }
+
+    fn get(
+        &mut self,
+        src: Rank,
+        transfers: Transfers,
+        len: usize,
+        notify: oneshot::Sender<Vec<u8>>,
+    ) {
+        let addrs = self.remote_addrs(src, &transfers[..]);
+        let mut buffer = vec![0u8; len];
+        let mut pos = 0;
+        let mut gex_events = vec![];
+        for (addr, (_, len)) in addrs.into_iter().zip(transfers) {
+            let dst = buffer[pos..pos + len].as_mut_ptr() as *mut c_void;
+            gex_events.push(unsafe {
+                gex_rma_get_nb(self.tctx.team, dst, src.gex_rank(), addr, len as size_t)
+            });
+            pos += len;
+        }
+        self.rma_events.push(RmaEvent {
+            gex_events,
+            buffer,
+            notifier: notify,
+        });
+    }
+
+    fn atomic(&mut self, dst: Rank, offset: usize, op: AtomicOp, notify: oneshot::Sender<u64>) {
+        let ad = self
+            .atomic_domain
+            .expect("gasnet atomics are disabled by AM_ATOMICS");
+        let addr = self.remote_addrs(dst, &[(offset, std::mem::size_of::<u64>())])[0];
+        let op = match op {
+            AtomicOp::FetchAdd(v) => GexAtomicOp::FetchAdd(v),
+            AtomicOp::CompareSwap(current, new) => GexAtomicOp::CompareSwap(current, new),
+            AtomicOp::Swap(v) => GexAtomicOp::Swap(v),
+            AtomicOp::Load => GexAtomicOp::Load,
+        };
+        let mut result = Box::new(0u64);
+        let gex_event = unsafe { gex_ad_op_nb_u64(ad, &mut *result, dst.gex_rank(), addr, op) };
+        self.atomic_events.push(AtomicEvent {
+            gex_event,
+            result,
+            notifier: notify,
+        });
+    }
+
+    /// return true if there is progress
+    fn poll_rma_events(&mut self) -> bool {
+        let mut progress = false;
+        let mut pending = vec![];
+        for event in self.atomic_events.drain(..) {
+            if gex_event_done(event.gex_event) {
+                // the requester might be gone
+                let _ = event.notifier.send(*event.result);
+                progress = true;
+            } else {
+                pending.push(event);
+            }
+        }
+        self.atomic_events = pending;
+        if self.rma_events.is_empty() {
+            return progress;
+        }
+        let before = self.rma_events.len();
+        let mut pending = vec![];
+        for mut event in self.rma_events.drain(..) {
+            if event.test() {
+                event.notify();
+            } else {
+                pending.push(event);
+            }
+        }
+        self.rma_events = pending;
+        progress || self.rma_events.len() != before
+    }
+
     pub fn run(&mut self) {
         use mpsc::TryRecvError::*;
         // drop sender, otherwise the channel will never be closed
@@ -715,6 +939,7 @@ where
         loop {
             gasnet_ampoll();
             progress = self.poll_collective_events();
+            progress |= self.poll_rma_events();
             match self.op_receiver.try_recv() {
                 Ok(op) => {
                     match op {
@@ -726,6 +951,19 @@ where
                             self.broadcast(root, data, len, notify)
                         }
                         NetworkOperation::AllGather(data, notify) => self.all_gather(data, notify),
+                        NetworkOperation::Alloc(size, align, notify) => {
+                            self.alloc(size, align, notify)
+                        }
+                        NetworkOperation::Free(offset) => self.segment_allocator.free(offset),
+                        NetworkOperation::Put(dst, transfers, data, notify) => {
+                            self.put(dst, transfers, data, notify)
+                        }
+                        NetworkOperation::Get(src, transfers, len, notify) => {
+                            self.get(src, transfers, len, notify)
+                        }
+                        NetworkOperation::Atomic(dst, offset, op, notify) => {
+                            self.atomic(dst, offset, op, notify)
+                        }
                     };
                     progress = true;
                 }
@@ -744,6 +982,10 @@ where
             }
         }
 
+        // every place stops after the final barrier
+        if let Some(ad) = self.atomic_domain.take() {
+            gex_ad_destroy(ad);
+        }
         info!("Shuting down network.");
     }
 
@@ -777,6 +1019,12 @@ where
         }
     }
 
+    pub(crate) fn rma_operator(&self) -> GexRmaOperator {
+        GexRmaOperator {
+            sender: self.op_sender.as_ref().unwrap().clone(),
+        }
+    }
+
     pub fn cmd_args(&self) -> &[String] {
         &self.cmd_args[..]
     }
@@ -838,6 +1086,49 @@ impl CollectiveOperator for GexCollectiveOperator {
     }
 }
 
+pub(crate) struct GexRmaOperator {
+    sender: mpsc::Sender<NetworkOperation>,
+}
+
+impl RmaOperator for GexRmaOperator {
+    fn alloc(&self, size: usize, align: usize) -> oneshot::Receiver<Option<(usize, usize)>> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(NetworkOperation::Alloc(size, align, tx))
+            .unwrap();
+        rx
+    }
+
+    fn free(&self, offset: usize) {
+        // the network might be stopped at exit
+        let _ = self.sender.send(NetworkOperation::Free(offset));
+    }
+
+    fn put(&self, dst: Rank, transfers: Transfers, bytes: Vec<u8>) -> oneshot::Receiver<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(NetworkOperation::Put(dst, transfers, bytes, tx))
+            .unwrap();
+        rx
+    }
+
+    fn get(&self, src: Rank, transfers: Transfers, len: usize) -> oneshot::Receiver<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(NetworkOperation::Get(src, transfers, len, tx))
+            .unwrap();
+        rx
+    }
+
+    fn atomic(&self, dst: Rank, offset: usize, op: AtomicOp) -> oneshot::Receiver<u64> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .send(NetworkOperation::Atomic(dst, offset, op, tx))
+            .unwrap();
+        rx
+    }
+}
+
 #[cfg(test)]
 mod test {
 
@@ -875,6 +1166,10 @@ mod test {
             op_receiver: rx,
             op_sender: Some(tx),
             cctx: Default::default(),
+            segment_allocator: SegmentAllocator::new(512),
+            rma_events: vec![],
+            atomic_domain: None,
+            atomic_events: vec![],
         }
     }
     fn set_ptr<T>(ctx: &mut CommunicationContext<T>) {
@@ -1022,4 +1317,21 @@ mod test {
         };
         _test_short_long_receiver(callback, message_payload);
     }
+
+    #[test]
+    fn test_segment_allocator() {
+        let mut a = SegmentAllocator::new(64);
+        assert_eq!(a.alloc(10, 1), Some(0));
+        assert_eq!(a.alloc(10, 8), Some(16));
+        assert_eq!(a.alloc(4, 4), Some(12));
+        assert_eq!(a.alloc(64, 1), None);
+        a.free(0);
+        // first fit
+        assert_eq!(a.alloc(8, 8), Some(0));
+        assert_eq!(a.alloc(40, 1), None);
+        assert_eq!(a.alloc(32, 32), Some(32));
+        // zero sized buffers still get their own offsets
+        assert_eq!(a.alloc(0, 1), Some(8));
+        assert_eq!(a.alloc(0, 1), Some(9));
+    }
 }
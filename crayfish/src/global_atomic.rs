@@ -0,0 +1,213 @@
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::meta_data;
+use crate::network::AtomicOp;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::remote::ReplyTo;
+use crate::rma::SymmetricBuffer;
+use crate::rma::SymmetricBufferRef;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use serde::Serialize;
+use std::ops::Deref;
+use std::sync::atomic::AtomicU64;
+
+// A global atomic is a u64 in a symmetric buffer, where only the copy at its home place is
+// used. Operations are GASNet atomics on the segment of the home place, or with AM_ATOMICS
+// set, requests applied by a handler at the home place for transports without NIC atomics.
+// The two must not be mixed, so AM_ATOMICS must be the same at every place, checked at init.
+
+/// A u64 at its home place, updated atomically from any place.
+///
+/// Must be created and freed at every place in the same order, like a collective. Use
+/// `global_ref` to send it to activities. Dropping it without `free` leaks the value.
+pub struct GlobalAtomicU64 {
+    cell: SymmetricBuffer<u64>,
+    global: GlobalAtomicU64Ref,
+}
+
+/// A reference to a global atomic, which can be sent to any place
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct GlobalAtomicU64Ref {
+    home: Place,
+    cell: SymmetricBufferRef<u64>,
+}
+
+impl crate::args::RemoteSend for GlobalAtomicU64Ref {
+    crate::impl_body! {}
+}
+
+impl GlobalAtomicU64 {
+    /// a collective, resolves when every place has created it
+    pub async fn new(home: Place, value: u64) -> Self {
+        Self::with_cell(home, SymmetricBuffer::new(1, value).await)
+    }
+
+    fn with_cell(home: Place, cell: SymmetricBuffer<u64>) -> Self {
+        let global = GlobalAtomicU64Ref {
+            home,
+            cell: cell.global_ref(),
+        };
+        GlobalAtomicU64 { cell, global }
+    }
+
+    /// free the value, a collective like `SymmetricBuffer::free`
+    pub async fn free(self) {
+        self.cell.free().await
+    }
+
+    pub fn global_ref(&self) -> GlobalAtomicU64Ref {
+        self.global
+    }
+}
+
+impl Deref for GlobalAtomicU64 {
+    type Target = GlobalAtomicU64Ref;
+
+    fn deref(&self) -> &Self::Target {
+        &self.global
+    }
+}
+
+impl GlobalAtomicU64Ref {
+    pub fn home(&self) -> Place {
+        self.home
+    }
+
+    fn local_atomic(&self) -> &AtomicU64 {
+        unsafe { &*(self.cell.local_addr() as *const AtomicU64) }
+    }
+
+    fn apply(&self, op: AtomicOp) -> BoxFuture<'static, u64> {
+        if !*meta_data::AM_ATOMICS {
+            return self.cell.atomic(self.home, 0, op);
+        }
+        if self.home == here() {
+            return futures::future::ready(op.apply(self.local_atomic())).boxed();
+        }
+        let offset = self.cell.offset();
+        remote::request(self.home, ATOMIC_FN_ID, |builder| {
+            builder.arg(offset);
+            builder.arg(op);
+        })
+        .map(|mut e| e.arg())
+        .boxed()
+    }
+
+    /// add to the value, resolves to the previous value
+    pub fn fetch_add(&self, value: u64) -> BoxFuture<'static, u64> {
+        self.apply(AtomicOp::FetchAdd(value))
+    }
+
+    /// Store new if the value is current. Resolves to the previous value, which is Ok if it was
+    /// current, like `AtomicU64::compare_exchange`
+    pub fn compare_swap(&self, current: u64, new: u64) -> BoxFuture<'static, Result<u64, u64>> {
+        self.apply(AtomicOp::CompareSwap(current, new))
+            .map(move |previous| match previous == current {
+                true => Ok(previous),
+                false => Err(previous),
+            })
+            .boxed()
+    }
+
+    /// store the value, resolves to the previous value
+    pub fn swap(&self, value: u64) -> BoxFuture<'static, u64> {
+        self.apply(AtomicOp::Swap(value))
+    }
+
+    pub fn load(&self) -> BoxFuture<'static, u64> {
+        self.apply(AtomicOp::Load)
+    }
+}
+
+const ATOMIC_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "atomic_request_handler");
+
+fn atomic_request_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let reply_to: ReplyTo = e.arg();
+    let offset: usize = e.arg();
+    let op: AtomicOp = e.arg();
+    // the same buffer has the same offset here
+    let cell: SymmetricBufferRef<u64> = SymmetricBufferRef::at_offset(offset, 1);
+    let atomic = unsafe { &*(cell.local_addr() as *const AtomicU64) };
+    let previous = op.apply(atomic);
+    remote::reply(reply_to, |builder| builder.arg(previous));
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        ATOMIC_FN_ID,
+        atomic_request_handler,
+        String::from("atomic_request_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::rma::test::TestGuard;
+    use crate::runtime;
+    use futures::executor;
+
+    #[test]
+    fn test_global_atomic() {
+        let _g = TestGuard::new();
+        let cell = executor::block_on(SymmetricBuffer::alloc(1, 5u64));
+        let a = GlobalAtomicU64::with_cell(3, cell);
+        assert_eq!(a.home(), 3);
+        assert_eq!(executor::block_on(a.fetch_add(2)), 5);
+        assert_eq!(executor::block_on(a.compare_swap(6, 9)), Err(7));
+        assert_eq!(executor::block_on(a.compare_swap(7, 9)), Ok(7));
+        assert_eq!(executor::block_on(a.swap(1)), 9);
+        assert_eq!(executor::block_on(a.global_ref().load()), 1);
+    }
+
+    #[test]
+    fn test_atomic_request_handler() {
+        let _g = TestGuard::new();
+        let _a = TestGuardForStatic::new();
+        runtime::init_task_item_channels();
+        let sent = runtime::take_task_item_receiver();
+        // serve an item sent to here like a worker
+        let dispatch = |item: Box<TaskItem>| {
+            let fn_id = item.function_id();
+            let handler = inventory::iter::<FunctionMetaData>
+                .into_iter()
+                .find(|f| f.fn_id() == fn_id)
+                .unwrap();
+            executor::block_on(handler.call(*item));
+        };
+        let cell = executor::block_on(SymmetricBuffer::alloc(1, 5u64));
+        let reply = remote::request(here(), ATOMIC_FN_ID, |builder| {
+            builder.arg(cell.offset());
+            builder.arg(AtomicOp::FetchAdd(2));
+        });
+        dispatch(sent.recv().unwrap()); // the request
+        dispatch(sent.recv().unwrap()); // the reply
+        assert_eq!(executor::block_on(reply).arg::<u64>(), 5);
+        assert_eq!(unsafe { cell.local() }, &[7]);
+    }
+
+    #[test]
+    fn test_atomic_op() {
+        let a = AtomicU64::new(1);
+        assert_eq!(AtomicOp::FetchAdd(3).apply(&a), 1);
+        assert_eq!(AtomicOp::CompareSwap(1, 8).apply(&a), 4);
+        assert_eq!(AtomicOp::CompareSwap(4, 8).apply(&a), 4);
+        assert_eq!(AtomicOp::Swap(2).apply(&a), 8);
+        assert_eq!(AtomicOp::Load.apply(&a), 2);
+    }
+}
@@ -0,0 +1,469 @@
+use crate::activity;
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::logging::*;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::remote::ReplyTo;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use tokio::sync::oneshot;
+
+// A promise and its future meet at the home place, where they are created. The value of a
+// promise set at any place is sent to the home place, and kept in the slot of the promise until
+// the future is awaited. A future awaited at another place requests the value from the home
+// place, which replies once the value is there. Either way the slot is removed once the value is
+// delivered, so the value is sent at most twice. A future dropped before its value is delivered
+// discards the value at the home place, or leaves a discarded mark there until the value
+// arrives. Only a remote future dropped while its value is on the way back leaves the mark for
+// good. Setting a promise twice drops the second
+// value, and awaiting a future twice resolves the second wait to an error, which panics in the
+// awaiting activity. Neither can be sent to several places by at_each!, since each copy would
+// be set or awaited.
+
+// the value, or why there is none
+type Delivery = Result<Vec<u8>, String>;
+
+enum Waiter {
+    Local(oneshot::Sender<Delivery>),
+    Remote(ReplyTo),
+}
+
+enum Slot {
+    Value(Vec<u8>),
+    Waiting(Waiter),
+    Discarded,
+}
+
+static NEXT_PROMISE_ID: AtomicU64 = AtomicU64::new(0);
+static SLOTS: Lazy<Mutex<FxHashMap<u64, Slot>>> = Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn deliver(waiter: Waiter, delivery: Delivery) {
+    match waiter {
+        // the awaiting task might be gone
+        Waiter::Local(tx) => drop(tx.send(delivery)),
+        Waiter::Remote(to) => remote::reply(to, |builder| builder.arg(delivery)),
+    }
+}
+
+// called by handlers, so misuse is logged instead of panicking
+fn fulfil(id: u64, value: Vec<u8>) {
+    let waiter = {
+        let mut slots = SLOTS.lock();
+        match slots.remove(&id) {
+            None => {
+                slots.insert(id, Slot::Value(value));
+                return;
+            }
+            Some(Slot::Waiting(waiter)) => waiter,
+            Some(Slot::Discarded) => return,
+            Some(slot @ Slot::Value(_)) => {
+                slots.insert(id, slot);
+                error!("promise {} is set twice, the second value is dropped", id);
+                return;
+            }
+        }
+    };
+    deliver(waiter, Ok(value));
+}
+
+fn wait(id: u64, waiter: Waiter) {
+    let value = {
+        let mut slots = SLOTS.lock();
+        match slots.remove(&id) {
+            None => {
+                slots.insert(id, Slot::Waiting(waiter));
+                return;
+            }
+            Some(Slot::Value(value)) => value,
+            // the wait of a dropped future arrives after its discard
+            Some(Slot::Discarded) => {
+                slots.insert(id, Slot::Discarded);
+                return deliver(waiter, Err(format!("future {} is dropped", id)));
+            }
+            Some(slot @ Slot::Waiting(_)) => {
+                slots.insert(id, slot);
+                let misuse = format!("future {} is awaited twice", id);
+                error!("{}", misuse);
+                return deliver(waiter, Err(misuse));
+            }
+        }
+    };
+    deliver(waiter, Ok(value));
+}
+
+fn discard(id: u64) {
+    let mut slots = SLOTS.lock();
+    // the value yet to arrive is dropped by the mark
+    if !matches!(slots.remove(&id), Some(Slot::Value(_))) {
+        slots.insert(id, Slot::Discarded);
+    }
+}
+
+/// The sending side of a global future. It can be sent to any place, and set there once.
+///
+/// Awaiting the future of a promise dropped without being set never resolves.
+pub struct GlobalPromise<T> {
+    home: Place,
+    id: u64,
+    _value: PhantomData<fn(T)>,
+}
+
+/// A future resolving to the value set to its promise. It can be sent to and awaited at any place.
+/// Dropping it before the value is delivered discards the value.
+pub struct GlobalFuture<T> {
+    home: Place,
+    id: u64,
+    // delivered, or sent to another place where it lives now
+    released: Cell<bool>,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for GlobalPromise<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GlobalPromise")
+            .field("home", &self.home)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for GlobalFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GlobalFuture")
+            .field("home", &self.home)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+// a promise or a future is used once, so it is sent as its home and id to a single place
+fn serialize_once<S: Serializer>(
+    kind: &str,
+    home: Place,
+    id: u64,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    if activity::deliveries() > 1 {
+        return Err(serde::ser::Error::custom(format!(
+            "global {} {} cannot be sent to several places",
+            kind, id
+        )));
+    }
+    (home, id).serialize(serializer)
+}
+
+impl<T> Serialize for GlobalPromise<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_once("promise", self.home, self.id, serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for GlobalPromise<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (home, id) = <(Place, u64)>::deserialize(deserializer)?;
+        Ok(GlobalPromise {
+            home,
+            id,
+            _value: PhantomData,
+        })
+    }
+}
+
+impl<T> Serialize for GlobalFuture<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ret = serialize_once("future", self.home, self.id, serializer);
+        self.released.set(ret.is_ok());
+        ret
+    }
+}
+
+impl<'de, T> Deserialize<'de> for GlobalFuture<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (home, id) = <(Place, u64)>::deserialize(deserializer)?;
+        Ok(GlobalFuture::new(home, id))
+    }
+}
+
+impl<T: RemoteSend> RemoteSend for GlobalPromise<T> {
+    crate::impl_body! {}
+}
+
+impl<T: RemoteSend> RemoteSend for GlobalFuture<T> {
+    crate::impl_body! {}
+}
+
+/// a promise and its future, with their home at this place
+pub fn global_promise<T: RemoteSend>() -> (GlobalPromise<T>, GlobalFuture<T>) {
+    let home = here();
+    let id = NEXT_PROMISE_ID.fetch_add(1, Ordering::Relaxed);
+    let promise = GlobalPromise {
+        home,
+        id,
+        _value: PhantomData,
+    };
+    (promise, GlobalFuture::new(home, id))
+}
+
+impl<T: RemoteSend> GlobalPromise<T> {
+    pub fn home(&self) -> Place {
+        self.home
+    }
+
+    /// resolve the future to the value, wherever it is awaited
+    pub fn set(self, value: T) {
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &value).expect("Failed to serialize promised value");
+        if self.home == here() {
+            fulfil(self.id, bytes);
+        } else {
+            let id = self.id;
+            remote::send(self.home, SET_FN_ID, |builder| {
+                builder.arg(id);
+                builder.arg(bytes);
+            });
+        }
+    }
+}
+
+impl<T> GlobalFuture<T> {
+    fn new(home: Place, id: u64) -> Self {
+        GlobalFuture {
+            home,
+            id,
+            released: Cell::new(false),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T> Drop for GlobalFuture<T> {
+    fn drop(&mut self) {
+        if self.released.get() {
+            return;
+        }
+        let id = self.id;
+        if self.home == here() {
+            discard(id);
+        } else {
+            remote::send(self.home, DISCARD_FN_ID, |builder| builder.arg(id));
+        }
+    }
+}
+
+impl<T: RemoteSend> GlobalFuture<T> {
+    pub fn home(&self) -> Place {
+        self.home
+    }
+
+    /// Resolves to the value once the promise is set. Panics if the future is awaited twice
+    pub fn get(self) -> BoxFuture<'static, T> {
+        let delivery = if self.home == here() {
+            let (tx, rx) = oneshot::channel();
+            wait(self.id, Waiter::Local(tx));
+            async move { rx.await.expect("slot of a global future is dropped") }.boxed()
+        } else {
+            let id = self.id;
+            remote::request(self.home, WAIT_FN_ID, |builder| builder.arg(id))
+                .map(|mut e| e.arg::<Delivery>())
+                .boxed()
+        };
+        // dropped with the returned future until the value is delivered
+        async move {
+            let delivery = delivery.await;
+            self.released.set(true);
+            match delivery {
+                Ok(bytes) => {
+                    deserialize_from(&bytes[..]).expect("Failed to deserialize promised value")
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+        .boxed()
+    }
+}
+
+const SET_FN_ID: FunctionLabel = runtime_meta::function_id(module_path!(), "promise_set_handler");
+const WAIT_FN_ID: FunctionLabel = runtime_meta::function_id(module_path!(), "future_wait_handler");
+const DISCARD_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "future_discard_handler");
+
+fn promise_set_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let id: u64 = e.arg();
+    let value: Vec<u8> = e.arg();
+    fulfil(id, value);
+    futures::future::ready(()).boxed()
+}
+
+fn future_wait_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let reply_to: ReplyTo = e.arg();
+    let id: u64 = e.arg();
+    // replied now or when the promise is set
+    wait(id, Waiter::Remote(reply_to));
+    futures::future::ready(()).boxed()
+}
+
+fn future_discard_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    discard(e.arg());
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        SET_FN_ID,
+        promise_set_handler,
+        String::from("promise_set_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        WAIT_FN_ID,
+        future_wait_handler,
+        String::from("future_wait_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        DISCARD_FN_ID,
+        future_discard_handler,
+        String::from("future_discard_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+
+    #[test]
+    fn test_set_before_get() {
+        let _a = TestGuardForStatic::new();
+        let (promise, future) = global_promise::<String>();
+        assert_eq!(promise.home(), TEST_HERE);
+        promise.set(String::from("hello"));
+        assert_eq!(executor::block_on(future.get()), "hello");
+        assert!(SLOTS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_get_before_set() {
+        let _a = TestGuardForStatic::new();
+        let (promise, future) = global_promise::<Vec<usize>>();
+        let value = future.get();
+        promise.set(vec![1, 2, 3]);
+        assert_eq!(executor::block_on(value), vec![1, 2, 3]);
+        assert!(SLOTS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_set_twice() {
+        let _a = TestGuardForStatic::new();
+        let (promise, future) = global_promise::<usize>();
+        let id = promise.id;
+        promise.set(1);
+        // a handler does not panic, the first value is kept
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &2usize).unwrap();
+        fulfil(id, bytes);
+        assert_eq!(executor::block_on(future.get()), 1);
+        assert!(SLOTS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_awaited_twice() {
+        let _a = TestGuardForStatic::new();
+        let (promise, future) = global_promise::<usize>();
+        let (tx, rx) = oneshot::channel();
+        // awaited by the future
+        wait(future.id, Waiter::Local(tx));
+        future.released.set(true);
+        // the second waiter hears an error, the first still gets the value
+        let second = GlobalFuture::<usize>::new(future.home, future.id);
+        let second = std::panic::AssertUnwindSafe(second.get());
+        assert!(std::panic::catch_unwind(|| executor::block_on(second)).is_err());
+        promise.set(3);
+        let bytes = executor::block_on(rx).unwrap().unwrap();
+        assert_eq!(deserialize_from::<_, usize>(&bytes[..]).unwrap(), 3);
+        assert!(SLOTS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_dropped_future() {
+        let _a = TestGuardForStatic::new();
+        // set, then dropped without get
+        let (promise, future) = global_promise::<usize>();
+        promise.set(1);
+        drop(future);
+        assert!(SLOTS.lock().is_empty());
+        // dropped before the promise is set
+        let (promise, future) = global_promise::<usize>();
+        drop(future);
+        promise.set(2);
+        assert!(SLOTS.lock().is_empty());
+        // dropped while waiting
+        let (promise, future) = global_promise::<usize>();
+        let mut value = future.get();
+        assert!((&mut value).now_or_never().is_none());
+        drop(value);
+        promise.set(3);
+        assert!(SLOTS.lock().is_empty());
+        // the wait of a remote future arrives after its discard
+        let (promise, future) = global_promise::<usize>();
+        let id = future.id;
+        drop(future);
+        let (tx, rx) = oneshot::channel();
+        wait(id, Waiter::Local(tx));
+        assert!(executor::block_on(rx).unwrap().is_err());
+        promise.set(4);
+        assert!(SLOTS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_sent_to_several_places() {
+        let _a = TestGuardForStatic::new();
+        let (promise, future) = global_promise::<usize>();
+        let bytes = activity::serialize_args(&promise, &[TEST_HERE]);
+        let promise: GlobalPromise<usize> = activity::deserialize_args(&bytes[..]);
+        // each copy of at_each! would be awaited
+        let several = || activity::serialize_args(&future, &[0, TEST_HERE]);
+        assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(several)).is_err());
+        promise.set(4);
+        assert_eq!(executor::block_on(future.get()), 4);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::finish::FinishMode;
 use crate::place::here;
 use crate::place::Place;
 use once_cell::sync::Lazy;
@@ -23,12 +24,16 @@ pub struct FinishId {
     place: Place,
     worker_id: WorkerId,
     local_id: LocalId,
+    mode: FinishMode, // carried by every activity, so any place knows the protocol
 }
 
 impl FinishId {
     pub fn get_place(&self) -> Place {
         self.place
     }
+    pub fn get_mode(&self) -> FinishMode {
+        self.mode
+    }
 }
 
 impl FinishId {
@@ -37,6 +42,7 @@ impl FinishId {
             place,
             worker_id,
             local_id,
+            mode: FinishMode::default(),
         }
     }
 }
@@ -140,8 +146,10 @@ fn next_activity_local_id() -> LocalId {
     })
 }
 
-pub(crate) fn new_global_finish_id() -> FinishId {
-    FinishId::new(here(), my_worker_id(), next_finish_local_id())
+pub(crate) fn new_global_finish_id(mode: FinishMode) -> FinishId {
+    let mut fid = FinishId::new(here(), my_worker_id(), next_finish_local_id());
+    fid.mode = mode;
+    fid
 }
 
 // place part of lower activity id is the place it spwan
@@ -261,7 +269,7 @@ pub(crate) mod test {
         let _a = TestGuardForStatic::new();
 
         // id get works well
-        let fid = new_global_finish_id();
+        let fid = new_global_finish_id(FinishMode::Default);
         assert_eq!(fid.place, TEST_HERE);
         let aid = new_global_activity_id(fid);
         assert_eq!(aid.get_finish_id(), fid);
@@ -287,7 +295,7 @@ pub(crate) mod test {
             let ftx = ftx.clone();
             threads.push(thread::spawn(move || {
                 for _ in 1..1025 {
-                    let fid = new_global_finish_id();
+                    let fid = new_global_finish_id(FinishMode::Default);
                     ftx.send(fid).unwrap();
                     atx.send(new_global_activity_id(fid)).unwrap();
                 }
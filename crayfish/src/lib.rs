@@ -1,22 +1,36 @@
 #[macro_use]
 mod utils;
 
+pub mod accumulator;
 pub mod activity; // TODO private
 pub mod args;
+pub mod atomic;
+pub mod clock;
+pub mod collecting;
 pub mod collective;
+pub mod context;
+pub mod dist_array;
+pub mod dist_channel;
+pub mod dist_hash_map;
+pub mod dist_lock;
 pub mod essence;
 mod executor;
 mod finish;
 mod gasnet;
+pub mod global_atomic;
+pub mod global_future;
 pub mod global_id; // TODO: private
 pub mod logging; // TODO: mark as private
 mod meta_data;
 pub mod network; // TODO: mark private
 pub mod place;
+mod remote;
+pub mod rma;
 pub mod runtime;
 pub mod runtime_meta;
 mod serialization;
 pub mod shared;
+pub mod stream;
 #[cfg(feature = "trace")]
 pub mod trace;
 
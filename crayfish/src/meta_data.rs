@@ -1,5 +1,6 @@
 extern crate once_cell;
 extern crate sys_info;
+use crate::collective;
 use crate::logging::*;
 use once_cell::sync::Lazy;
 use std::env;
@@ -92,6 +93,34 @@ pub static MAX_BUFFER_LIFETIME: Lazy<time::Duration> = Lazy::new(|| {
     }
 });
 
+/// use active messages instead of GASNet atomics, for transports without NIC atomics
+pub static AM_ATOMICS: Lazy<bool> = Lazy::new(|| match env_with_prefix("AM_ATOMICS") {
+    Ok(s) => match s.as_str() {
+        "1" | "true" => true,
+        "0" | "false" => false,
+        _ => {
+            warn!("bad AM_ATOMICS: {}, should be true or false", s);
+            false
+        }
+    },
+    Err(_) => false,
+});
+
+/// atomics by active messages and by GASNet on the same value are not atomic with each other,
+/// so AM_ATOMICS must be the same at every place. Panic if not
+pub(crate) async fn check_am_atomics() {
+    let settings = collective::all_gather(*AM_ATOMICS).await;
+    let differ: Vec<_> = (0..settings.len())
+        .filter(|place| settings[*place] != settings[0])
+        .collect();
+    if !differ.is_empty() {
+        panic!(
+            "AM_ATOMICS differs between places: {} at place 0, {} at places {:?}",
+            settings[0], !settings[0], differ
+        );
+    }
+}
+
 pub fn show_data() {
     let show_table_header = s_vec!["Variable", "Name"];
     let show_table_body = vec![
@@ -100,6 +129,7 @@ pub fn show_data() {
             "MAX_SEND_INTERVAL".to_owned(),
             format!("{:?}", *MAX_BUFFER_LIFETIME),
         ],
+        vec!["AM_ATOMICS".to_owned(), AM_ATOMICS.to_string()],
     ];
     debug!(
         "run Crayfish with:\n{}",
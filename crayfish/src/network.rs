@@ -2,6 +2,10 @@ use std::convert::TryInto;
 use crate::place::Place;
 use std::fmt;
 use futures::channel::oneshot;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 
 pub trait MessageHandler: for<'a> FnMut(Rank, &'a [u8]) {}
 impl<T> MessageHandler for T where T: for<'a> FnMut(Rank, &'a [u8]) {}
@@ -17,6 +21,49 @@ pub(crate) trait CollectiveOperator : Send + 'static{
     fn all_gather(&self, bytes:Vec<u8>) -> oneshot::Receiver<Vec<Vec<u8>>>;
 }
 
+/// (offset in the segment, length) of each contiguous part of a put or get
+pub(crate) type Transfers = Vec<(usize, usize)>;
+
+pub(crate) trait RmaOperator: Send + 'static {
+    /// offset in the segment and local address of the allocated buffer
+    fn alloc(&self, size: usize, align: usize) -> oneshot::Receiver<Option<(usize, usize)>>;
+    fn free(&self, offset: usize);
+    /// put bytes to transfers one by one, the bytes are handed back when done
+    fn put(&self, dst: Rank, transfers: Transfers, bytes: Vec<u8>) -> oneshot::Receiver<Vec<u8>>;
+    fn get(&self, src: Rank, transfers: Transfers, len: usize) -> oneshot::Receiver<Vec<u8>>;
+    /// apply op to the u64 at offset in the segment of dst, resolves to the previous value
+    fn atomic(&self, dst: Rank, offset: usize, op: AtomicOp) -> oneshot::Receiver<u64>;
+}
+
+/// an operation of a remote atomic u64
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum AtomicOp {
+    FetchAdd(u64),
+    CompareSwap(u64, u64),
+    Swap(u64),
+    Load,
+}
+
+impl crate::args::RemoteSend for AtomicOp {
+    crate::impl_body! {}
+}
+
+impl AtomicOp {
+    /// apply to a local atomic, return the previous value
+    pub(crate) fn apply(self, atomic: &AtomicU64) -> u64 {
+        match self {
+            AtomicOp::FetchAdd(v) => atomic.fetch_add(v, Ordering::SeqCst),
+            AtomicOp::CompareSwap(current, new) => {
+                match atomic.compare_exchange(current, new, Ordering::SeqCst, Ordering::SeqCst) {
+                    Ok(v) | Err(v) => v,
+                }
+            }
+            AtomicOp::Swap(v) => atomic.swap(v, Ordering::SeqCst),
+            AtomicOp::Load => atomic.load(Ordering::SeqCst),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Rank {
     rank: i32,
@@ -82,6 +82,28 @@ pub fn world_size() -> usize{
     })
 }
 
+// spawn tree of at_each!: the root relays to FANOUT subtrees of the rest places
+const SPAWN_TREE_FANOUT: usize = 2;
+
+/// places of a group for a spawn tree, in ascending order
+pub fn spawn_tree_places(group: impl IntoIterator<Item = Place>) -> Vec<Place> {
+    let mut places: Vec<Place> = group.into_iter().collect();
+    places.sort_unstable();
+    places.dedup();
+    places
+}
+
+/// subtrees under the root of a spawn tree, which is the first place. Each subtree is a
+/// contiguous range of places rooted at its first place, so results concatenate in order
+pub fn spawn_tree_children(places: &[Place]) -> Vec<Vec<Place>> {
+    let rest = &places[1.min(places.len())..];
+    if rest.is_empty() {
+        return vec![];
+    }
+    let chunk = (rest.len() - 1) / SPAWN_TREE_FANOUT + 1;
+    rest.chunks(chunk).map(|c| c.to_vec()).collect()
+}
+
 
 #[cfg(test)]
 mod test {
@@ -100,6 +122,27 @@ mod test {
         assert_eq!(pg.into_iter().collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
     }
 
+    #[test]
+    fn test_spawn_tree() {
+        let places = spawn_tree_places(vec![4, 0, 2, 1, 3, 2, 5]);
+        assert_eq!(places, vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(
+            spawn_tree_children(&places),
+            vec![vec![1, 2, 3], vec![4, 5]]
+        );
+        // every place is reached exactly once
+        let mut reached = vec![];
+        let mut trees = vec![places];
+        while let Some(tree) = trees.pop() {
+            reached.push(tree[0]);
+            trees.extend(spawn_tree_children(&tree));
+        }
+        reached.sort_unstable();
+        assert_eq!(reached, vec![0, 1, 2, 3, 4, 5]);
+        assert!(spawn_tree_children(&[7]).is_empty());
+        assert!(spawn_tree_children(&[]).is_empty());
+    }
+
     #[test]
     fn test_here() {
         use crate::global_id::test::TEST_HERE;
@@ -0,0 +1,112 @@
+use crate::activity::ActivityId;
+use crate::activity::FunctionLabel;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemBuilder;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::logging::*;
+use crate::place::here;
+use crate::place::Place;
+use crate::runtime::ApgasContext;
+use crate::runtime::ConcreteContext;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use tokio::sync::oneshot;
+
+// Requests served by a handler at another place, for runtime data structures that live at
+// their owner places, and one-way messages to such handlers. Requests, replies and messages are
+// not activities, so they are neither counted by finish nor carry a context. The handler of a
+// request must reply exactly once, and must not panic, since the requester has nobody else to
+// hear from. Handlers report errors in their replies instead.
+
+/// where to send the reply of a request, the first argument of a request item
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct ReplyTo {
+    place: Place,
+    request_id: u64,
+}
+
+impl RemoteSend for ReplyTo {
+    crate::impl_body! {}
+}
+
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+static PENDING_REPLIES: Lazy<Mutex<FxHashMap<u64, oneshot::Sender<TaskItemExtracter>>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+/// send a one-way message to the handler fn_id at dst, with the arguments written by build
+pub(crate) fn send<F>(dst: Place, fn_id: FunctionLabel, build: F)
+where
+    F: FnOnce(&mut TaskItemBuilder),
+{
+    let mut builder = TaskItemBuilder::new(fn_id, dst, ActivityId::zero());
+    build(&mut builder);
+    ConcreteContext::send(builder.build_box());
+}
+
+/// send a request to the handler fn_id at dst, resolves to the extracter of the reply
+pub(crate) fn request<F>(
+    dst: Place,
+    fn_id: FunctionLabel,
+    build: F,
+) -> BoxFuture<'static, TaskItemExtracter>
+where
+    F: FnOnce(&mut TaskItemBuilder),
+{
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    let (tx, rx) = oneshot::channel();
+    PENDING_REPLIES.lock().insert(request_id, tx);
+    let mut builder = TaskItemBuilder::new(fn_id, dst, ActivityId::zero());
+    builder.arg(ReplyTo {
+        place: here(),
+        request_id,
+    });
+    build(&mut builder);
+    ConcreteContext::send(builder.build_box());
+    async move { rx.await.expect("reply of a remote request is dropped") }.boxed()
+}
+
+/// send the reply of a request, with the arguments written by build
+pub(crate) fn reply<F>(to: ReplyTo, build: F)
+where
+    F: FnOnce(&mut TaskItemBuilder),
+{
+    let mut builder = TaskItemBuilder::new(REPLY_FN_ID, to.place, ActivityId::zero());
+    builder.arg(to.request_id);
+    build(&mut builder);
+    ConcreteContext::send(builder.build_box());
+}
+
+const REPLY_FN_ID: FunctionLabel = runtime_meta::function_id(module_path!(), "reply_handler");
+
+fn reply_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let request_id: u64 = e.arg();
+    match PENDING_REPLIES.lock().remove(&request_id) {
+        // the requester might be gone
+        Some(tx) => drop(tx.send(e)),
+        None => warn!("dropped a reply of unknown request {}", request_id),
+    }
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        REPLY_FN_ID,
+        reply_handler,
+        String::from("reply_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
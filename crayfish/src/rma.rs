@@ -0,0 +1,549 @@
+use crate::collective;
+use crate::logging::*;
+use crate::network::AtomicOp;
+use crate::network::Rank;
+use crate::network::RmaOperator;
+use crate::network::Transfers;
+use crate::place;
+use crate::place::Place;
+pub use bytemuck::Pod;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use parking_lot::const_mutex;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use serde::Serialize;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::ops::Deref;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+// One-sided data movement on symmetric buffers allocated from the GASNet segment. A buffer has
+// the same offset in the segment of every place, so a put or get only needs the place and the
+// index at the remote side. Transfers are plain bytes, without activities or serialization, so
+// elements are Pod: any bytes from a remote place are a valid value.
+
+static RMA_OPERATOR: Mutex<Option<Box<dyn RmaOperator>>> = const_mutex(None);
+
+// address of the segment of this place
+static LOCAL_SEGMENT: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn set_rma(rma: Box<dyn RmaOperator>) {
+    let mut h = RMA_OPERATOR.lock();
+    if cfg!(test) {
+        assert!(h.is_none());
+    }
+    *h = Some(rma);
+}
+
+pub(crate) fn take_rma() -> Box<dyn RmaOperator> {
+    RMA_OPERATOR.lock().take().unwrap()
+}
+
+fn with_rma<F, R>(f: F) -> R
+where
+    F: FnOnce(&dyn RmaOperator) -> R,
+{
+    let h = RMA_OPERATOR.lock();
+    f(h.as_ref().expect("rma is not available").as_ref())
+}
+
+fn from_bytes<T: Pod>(bytes: &[u8]) -> Vec<T> {
+    debug_assert_eq!(bytes.len() % size_of::<T>(), 0);
+    bytes
+        .chunks_exact(size_of::<T>())
+        .map(bytemuck::pod_read_unaligned)
+        .collect()
+}
+
+/// Remote indices of a strided transfer: `count` blocks of `block` elements, the first at
+/// `start` and each `stride` elements after the previous
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Strided {
+    pub start: usize,
+    pub block: usize,
+    pub stride: usize,
+    pub count: usize,
+}
+
+impl Strided {
+    /// number of elements transferred
+    pub fn len(&self) -> usize {
+        self.block * self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn end(&self) -> usize {
+        match self.count {
+            0 => self.start,
+            _ => self.start + (self.count - 1) * self.stride + self.block,
+        }
+    }
+}
+
+/// A buffer of len elements of T at every place, allocated from the GASNet segment. T is not
+/// zero-sized.
+///
+/// Must be created and freed at every place in the same order, like a collective. Use
+/// `global_ref` to send the buffer to activities. Dropping it without `free` leaks the buffer,
+/// since other places might still put or get from it.
+pub struct SymmetricBuffer<T> {
+    global: SymmetricBufferRef<T>,
+}
+
+/// A reference to a symmetric buffer, which can be sent to any place
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct SymmetricBufferRef<T> {
+    offset: usize,
+    len: usize,
+    _mark: PhantomData<fn(T)>,
+}
+
+impl<T: Pod + Send> SymmetricBuffer<T> {
+    /// allocate the buffer with all elements at this place set to init. A collective: resolves
+    /// when every place has allocated it at the same offset
+    pub async fn new(len: usize, init: T) -> Self {
+        let buffer = Self::alloc(len, init).await;
+        let layout = buffer.layout();
+        check_symmetric(layout, collective::all_gather(layout).await);
+        buffer
+    }
+
+    pub(crate) async fn alloc(len: usize, init: T) -> Self {
+        assert_ne!(size_of::<T>(), 0, "symmetric buffer of a zero-sized type");
+        let size = len
+            .checked_mul(size_of::<T>())
+            .unwrap_or_else(|| panic!("symmetric buffer of {} elements overflows", len));
+        let allocated = with_rma(|rma| rma.alloc(size, std::mem::align_of::<T>()));
+        let (offset, local_addr) = allocated
+            .await
+            .unwrap()
+            .unwrap_or_else(|| panic!("no space for a symmetric buffer of {} bytes", size));
+        LOCAL_SEGMENT.store(local_addr - offset, Ordering::Relaxed);
+        let buffer = SymmetricBuffer {
+            global: SymmetricBufferRef {
+                offset,
+                len,
+                _mark: PhantomData,
+            },
+        };
+        // the segment is not initialized, so write it without reading
+        let base: *mut T = buffer.global.local_addr();
+        for i in 0..len {
+            unsafe { base.add(i).write(init) };
+        }
+        buffer
+    }
+
+    pub fn global_ref(&self) -> SymmetricBufferRef<T> {
+        self.global
+    }
+
+    /// free the buffer. A collective: frees it when every place is done with it, so other
+    /// places must have finished their puts and gets before calling it
+    pub async fn free(self) {
+        collective::barrier().await;
+        // the network might be stopped at exit
+        if let Some(rma) = RMA_OPERATOR.lock().as_ref() {
+            rma.free(self.global.offset);
+        }
+        std::mem::forget(self);
+    }
+
+    fn layout(&self) -> Layout {
+        (self.global.offset, self.global.len, size_of::<T>())
+    }
+
+    /// the buffer of this place
+    ///
+    /// # Safety
+    /// Puts from other places must not write the buffer at the same time
+    pub unsafe fn local_mut(&mut self) -> &mut [T] {
+        std::slice::from_raw_parts_mut(self.global.local_addr(), self.global.len)
+    }
+}
+
+impl<T> Deref for SymmetricBuffer<T> {
+    type Target = SymmetricBufferRef<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.global
+    }
+}
+
+impl<T> Drop for SymmetricBuffer<T> {
+    fn drop(&mut self) {
+        // freeing it here could hand the offset to a new buffer while other places still
+        // put to it
+        if RMA_OPERATOR.lock().is_some() {
+            warn!(
+                "leak symmetric buffer at {}, free it instead",
+                self.global.offset
+            );
+        }
+    }
+}
+
+// offset, length and element size of a symmetric buffer
+type Layout = (usize, usize, usize);
+
+// layouts of a new symmetric buffer at each place, indexed by place
+fn check_symmetric(layout: Layout, layouts: Vec<Layout>) {
+    let diverged: Vec<_> = layouts
+        .iter()
+        .enumerate()
+        .filter(|(_, l)| **l != layout)
+        .map(|(place, (offset, len, size))| {
+            format!("place {}: {} of {}x{} bytes", place, offset, len, size)
+        })
+        .collect();
+    assert!(
+        diverged.is_empty(),
+        "symmetric buffer diverges from {} of {}x{} bytes at place {}: {}. Are symmetric buffers \
+         created and freed in the same order at every place?",
+        layout.0,
+        layout.1,
+        layout.2,
+        place::here(),
+        diverged.join(", ")
+    );
+}
+
+impl<T> Clone for SymmetricBufferRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for SymmetricBufferRef<T> {}
+
+impl<T> fmt::Debug for SymmetricBufferRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SymmetricBufferRef")
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<T: 'static> crate::args::RemoteSend for SymmetricBufferRef<T> {
+    crate::impl_body! {}
+}
+
+impl<T: Pod + Send> SymmetricBufferRef<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// the buffer of len elements at offset in the segment
+    pub(crate) fn at_offset(offset: usize, len: usize) -> Self {
+        SymmetricBufferRef {
+            offset,
+            len,
+            _mark: PhantomData,
+        }
+    }
+
+    pub(crate) fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub(crate) fn local_addr(&self) -> *mut T {
+        (LOCAL_SEGMENT.load(Ordering::Relaxed) + self.offset) as *mut T
+    }
+
+    fn byte_offset(&self, index: usize) -> usize {
+        self.offset + index * size_of::<T>()
+    }
+
+    fn check_range(&self, end: usize) {
+        assert!(
+            end <= self.len,
+            "range end {} out of a buffer of length {}",
+            end,
+            self.len
+        );
+    }
+
+    fn contiguous(&self, start: usize, len: usize) -> Transfers {
+        self.check_range(start + len);
+        vec![(self.byte_offset(start), len * size_of::<T>())]
+    }
+
+    fn strided(&self, strided: Strided) -> Transfers {
+        self.check_range(strided.end());
+        (0..strided.count)
+            .map(|i| {
+                let start = strided.start + i * strided.stride;
+                (self.byte_offset(start), strided.block * size_of::<T>())
+            })
+            .collect()
+    }
+
+    fn put_transfers(
+        &self,
+        place: Place,
+        transfers: Transfers,
+        values: &[T],
+    ) -> BoxFuture<'static, ()> {
+        let bytes = bytemuck::cast_slice(values).to_vec();
+        let done = with_rma(|rma| rma.put(Rank::from_place(place), transfers, bytes));
+        done.map(|r| {
+            r.unwrap();
+        })
+        .boxed()
+    }
+
+    fn get_transfers(
+        &self,
+        place: Place,
+        transfers: Transfers,
+        len: usize,
+    ) -> BoxFuture<'static, Vec<T>> {
+        let done =
+            with_rma(|rma| rma.get(Rank::from_place(place), transfers, len * size_of::<T>()));
+        done.map(|r| from_bytes(&r.unwrap()[..])).boxed()
+    }
+
+    /// apply op to the element at index of the buffer at place, resolves to the previous value
+    pub(crate) fn atomic(
+        &self,
+        place: Place,
+        index: usize,
+        op: AtomicOp,
+    ) -> BoxFuture<'static, u64> {
+        assert_eq!(size_of::<T>(), size_of::<u64>());
+        self.check_range(index + 1);
+        let offset = self.byte_offset(index);
+        let done = with_rma(|rma| rma.atomic(Rank::from_place(place), offset, op));
+        done.map(|r| r.unwrap()).boxed()
+    }
+
+    /// the buffer of this place
+    ///
+    /// # Safety
+    /// Puts from other places must not write the buffer at the same time
+    pub unsafe fn local(&self) -> &[T] {
+        std::slice::from_raw_parts(self.local_addr(), self.len)
+    }
+
+    /// write the element at index of the buffer at place, resolves when it is written
+    pub fn put(&self, place: Place, index: usize, value: T) -> BoxFuture<'static, ()> {
+        self.put_bulk(place, index, &[value])
+    }
+
+    /// write values to the buffer at place from index start
+    pub fn put_bulk(&self, place: Place, start: usize, values: &[T]) -> BoxFuture<'static, ()> {
+        let transfers = self.contiguous(start, values.len());
+        self.put_transfers(place, transfers, values)
+    }
+
+    /// write values to the strided indices of the buffer at place, in order
+    pub fn put_strided(
+        &self,
+        place: Place,
+        strided: Strided,
+        values: &[T],
+    ) -> BoxFuture<'static, ()> {
+        assert_eq!(strided.len(), values.len(), "strided length mismatch");
+        let transfers = self.strided(strided);
+        self.put_transfers(place, transfers, values)
+    }
+
+    /// read the element at index of the buffer at place
+    pub fn get(&self, place: Place, index: usize) -> BoxFuture<'static, T> {
+        self.get_bulk(place, index, 1).map(|v| v[0]).boxed()
+    }
+
+    /// read len elements of the buffer at place from index start
+    pub fn get_bulk(&self, place: Place, start: usize, len: usize) -> BoxFuture<'static, Vec<T>> {
+        let transfers = self.contiguous(start, len);
+        self.get_transfers(place, transfers, len)
+    }
+
+    /// read the strided indices of the buffer at place, in order
+    pub fn get_strided(&self, place: Place, strided: Strided) -> BoxFuture<'static, Vec<T>> {
+        let transfers = self.strided(strided);
+        self.get_transfers(place, transfers, strided.len())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use futures::channel::oneshot;
+    use futures::executor;
+    use once_cell::sync::Lazy;
+    use parking_lot::MutexGuard;
+    use std::sync::atomic::AtomicU64;
+
+    const SEGMENT_LEN: usize = 1024;
+
+    pub(crate) static TEST_LOCK: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
+
+    // a single place, whose segment is a boxed array
+    struct MockRma {
+        segment: Box<[u64; SEGMENT_LEN / 8]>, // aligned for atomics
+        next: Mutex<usize>,
+    }
+
+    impl MockRma {
+        fn base(&self) -> *mut u8 {
+            self.segment.as_ptr() as *mut u8
+        }
+    }
+
+    impl RmaOperator for MockRma {
+        fn alloc(&self, size: usize, align: usize) -> oneshot::Receiver<Option<(usize, usize)>> {
+            let (tx, rx) = oneshot::channel();
+            let mut next = self.next.lock();
+            let offset = *next + (align - *next % align) % align;
+            *next = offset + size;
+            tx.send(Some((offset, self.base() as usize + offset)))
+                .unwrap();
+            rx
+        }
+        fn free(&self, _offset: usize) {}
+        fn put(
+            &self,
+            _dst: Rank,
+            transfers: Transfers,
+            bytes: Vec<u8>,
+        ) -> oneshot::Receiver<Vec<u8>> {
+            let (tx, rx) = oneshot::channel();
+            let mut pos = 0;
+            for (offset, len) in transfers {
+                unsafe { std::ptr::copy(bytes[pos..].as_ptr(), self.base().add(offset), len) };
+                pos += len;
+            }
+            tx.send(bytes).unwrap();
+            rx
+        }
+        fn get(&self, _src: Rank, transfers: Transfers, len: usize) -> oneshot::Receiver<Vec<u8>> {
+            let (tx, rx) = oneshot::channel();
+            let mut bytes = vec![0u8; len];
+            let mut pos = 0;
+            for (offset, len) in transfers {
+                unsafe { std::ptr::copy(self.base().add(offset), bytes[pos..].as_mut_ptr(), len) };
+                pos += len;
+            }
+            tx.send(bytes).unwrap();
+            rx
+        }
+        fn atomic(&self, _dst: Rank, offset: usize, op: AtomicOp) -> oneshot::Receiver<u64> {
+            let (tx, rx) = oneshot::channel();
+            let atomic = unsafe { &*(self.base().add(offset) as *const AtomicU64) };
+            tx.send(op.apply(atomic)).unwrap();
+            rx
+        }
+    }
+
+    pub(crate) struct TestGuard<'a> {
+        _guard: MutexGuard<'a, bool>,
+    }
+
+    impl<'a> TestGuard<'a> {
+        pub(crate) fn new() -> Self {
+            let guard = TestGuard {
+                _guard: TEST_LOCK.lock(),
+            };
+            set_rma(Box::new(MockRma {
+                segment: Box::new([0; SEGMENT_LEN / 8]),
+                next: Mutex::new(0),
+            }));
+            guard
+        }
+    }
+
+    impl<'a> Drop for TestGuard<'a> {
+        fn drop(&mut self) {
+            take_rma();
+        }
+    }
+
+    #[test]
+    fn test_bulk() {
+        let _g = TestGuard::new();
+        let mut a = executor::block_on(SymmetricBuffer::alloc(8, 1u8));
+        let b = executor::block_on(SymmetricBuffer::alloc(4, 0.5f64));
+        assert_eq!(b.offset % 8, 0);
+        executor::block_on(b.put(0, 3, 2.5));
+        assert_eq!(executor::block_on(b.get(0, 3)), 2.5);
+        executor::block_on(a.put_bulk(0, 2, &[7, 8, 9]));
+        assert_eq!(executor::block_on(a.get_bulk(0, 1, 5)), vec![1, 7, 8, 9, 1]);
+        unsafe {
+            a.local_mut()[0] = 3;
+            assert_eq!(a.local(), &[3, 1, 7, 8, 9, 1, 1, 1]);
+            assert_eq!(b.local(), &[0.5, 0.5, 0.5, 2.5]);
+        }
+    }
+
+    #[test]
+    fn test_strided() {
+        let _g = TestGuard::new();
+        let a = executor::block_on(SymmetricBuffer::alloc(10, 0u32));
+        let strided = Strided {
+            start: 1,
+            block: 2,
+            stride: 4,
+            count: 3,
+        };
+        assert_eq!(strided.len(), 6);
+        assert_eq!(strided.end(), 11);
+        let strided = Strided {
+            count: 2,
+            ..strided
+        };
+        executor::block_on(a.put_strided(0, strided, &[1, 2, 3, 4]));
+        unsafe { assert_eq!(a.local(), &[0, 1, 2, 0, 0, 3, 4, 0, 0, 0]) };
+        assert_eq!(
+            executor::block_on(a.get_strided(0, strided)),
+            vec![1, 2, 3, 4]
+        );
+        let out_of_range = std::panic::catch_unwind(|| {
+            a.get_strided(
+                0,
+                Strided {
+                    count: 3,
+                    ..strided
+                },
+            )
+        });
+        assert!(out_of_range.is_err());
+    }
+
+    #[test]
+    fn test_check_symmetric() {
+        let _s = crate::global_id::test::TestGuardForStatic::new();
+        let agreed = vec![(64, 4, 8); 4];
+        check_symmetric((64, 4, 8), agreed.clone());
+        let mut other_offset = agreed.clone();
+        other_offset[2].0 = 96;
+        assert!(std::panic::catch_unwind(|| check_symmetric((64, 4, 8), other_offset)).is_err());
+        let mut other_len = agreed;
+        other_len[1].1 = 5;
+        assert!(std::panic::catch_unwind(|| check_symmetric((64, 4, 8), other_len)).is_err());
+        let huge = std::panic::catch_unwind(|| {
+            executor::block_on(SymmetricBuffer::alloc(usize::MAX, 0u64))
+        });
+        assert!(huge.is_err());
+    }
+
+    #[test]
+    fn test_zero_sized() {
+        let _g = TestGuard::new();
+        let zst =
+            std::panic::catch_unwind(|| executor::block_on(SymmetricBuffer::alloc(4, [0u8; 0])));
+        assert!(zst.is_err());
+    }
+}
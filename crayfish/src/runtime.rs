@@ -1,13 +1,27 @@
 use crate::activity::AbstractSquashBuffer;
 use crate::activity::AbstractSquashBufferFactory;
+use crate::activity::ActivityFrame;
 use crate::activity::ActivityId;
+use crate::activity::FunctionLabel;
+use crate::activity::PanicPayload;
+pub use crate::activity::RemotePanic;
 use crate::activity::StaticSquashBufferFactory;
 use crate::activity::TaskItem;
 use crate::activity::TaskItemBuilder;
 use crate::activity::TaskItemExtracter;
 use crate::args::RemoteSend;
+use crate::collecting;
+use crate::context::TaskContext;
+use crate::finish;
 use crate::finish::CallingTree;
+use crate::finish::CountingFinish;
+pub use crate::finish::DeadPlaceError;
+pub use crate::finish::FinishError;
 use crate::finish::FinishId;
+pub use crate::finish::FinishMode;
+pub use crate::finish::FinishTry;
+pub use crate::finish::FromFinishResidual;
+pub use crate::finish::LostActivity;
 use crate::global_id;
 use crate::global_id::ActivityIdLower;
 use crate::logging::*;
@@ -17,12 +31,16 @@ use crate::network::Rank;
 use crate::place;
 use crayfish_trace_macros::profiling_start_internal;
 use crayfish_trace_macros::profiling_stop_internal;
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use once_cell::sync::Lazy;
 use rustc_hash::FxHashMap;
-use std::any::Any;
 use std::cell::Cell;
 use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::mpsc::channel;
@@ -30,10 +48,13 @@ use std::sync::mpsc::Receiver;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
 use std::time;
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
 extern crate futures;
 extern crate once_cell;
@@ -111,10 +132,21 @@ pub(crate) fn take_worker_task_receiver() -> UnboundedReceiver<Box<TaskItem>> {
     WORKER_TASK_QUEUE.lock().unwrap().1.take().unwrap()
 }
 
+/// items sent by this thread, for tests of handlers without an execution hub
+#[cfg(test)]
+pub(crate) fn take_task_item_receiver() -> Receiver<Box<TaskItem>> {
+    let wid = global_id::my_worker_id();
+    TASK_ITEM_CHANNELS.lock().unwrap()[wid as usize].1.take().unwrap()
+}
+
+// outstanding activities of a finish, updated by the execution hub
+type FinishProgress = Arc<AtomicUsize>;
+
 #[derive(Debug)]
 enum WaitItem {
     One(ActivityId),
-    All(ConcreteContext),
+    All(ConcreteContext, Option<FinishProgress>),
+    PlaceFailed(place::Place), // nothing is sent back
 }
 type WaitRequest = (WaitItem, oneshot::Sender<Box<TaskItem>>);
 
@@ -159,29 +191,62 @@ fn get_wait_request_sender_ref() -> &'static Sender<Box<WaitRequest>> {
 pub trait ApgasContext: Send {
     fn inherit(finish_id: FinishId) -> Self;
     fn new_frame() -> Self;
+    fn finish_id(&self) -> FinishId;
     fn spawned(self) -> Vec<ActivityId>;
     fn spawn(&mut self) -> ActivityId;
+    /// spawn an activity running at the place
+    fn spawn_at(&mut self, dst: place::Place) -> ActivityId;
+    /// places of the spawned activities, only tracked by resilient finishes
+    fn destinations(&mut self) -> Vec<place::Place>;
+    /// the nearest collecting finish of the activity, see `collecting::scope`
+    fn collecting(&self) -> Option<FinishId>;
     fn send(item: Box<TaskItem>);
 }
 
 #[derive(Debug)]
 pub struct ConcreteContext {
     sub_activities: Vec<ActivityId>,
+    destinations: Vec<place::Place>,
     finish_id: FinishId,
+    is_frame: bool, // context of the finish block itself
+    collecting: Option<FinishId>,
+}
+
+impl ConcreteContext {
+    pub fn new_frame_with_mode(mode: FinishMode) -> Self {
+        ConcreteContext {
+            sub_activities: vec![],
+            destinations: vec![],
+            finish_id: global_id::new_global_finish_id(mode),
+            is_frame: true,
+            collecting: None,
+        }
+    }
+
+    /// context of an activity executing with the task context
+    pub fn for_activity(finish_id: FinishId, task_context: &TaskContext) -> Self {
+        ConcreteContext {
+            collecting: collecting::nearest_in(task_context),
+            ..Self::inherit(finish_id)
+        }
+    }
 }
 
 impl ApgasContext for ConcreteContext {
     fn inherit(finish_id: FinishId) -> Self {
         ConcreteContext {
             sub_activities: vec![],
+            destinations: vec![],
             finish_id,
+            is_frame: false,
+            collecting: None,
         }
     }
     fn new_frame() -> Self {
-        ConcreteContext {
-            sub_activities: vec![],
-            finish_id: global_id::new_global_finish_id(),
-        }
+        Self::new_frame_with_mode(FinishMode::Default)
+    }
+    fn finish_id(&self) -> FinishId {
+        self.finish_id
     }
     fn spawned(self) -> Vec<ActivityId> {
         self.sub_activities
@@ -189,16 +254,40 @@ impl ApgasContext for ConcreteContext {
 
     fn spawn(&mut self) -> ActivityId {
         let aid = global_id::new_global_activity_id(self.finish_id);
-        self.sub_activities.push(aid);
+        if self.finish_id.get_mode().is_tree() {
+            self.sub_activities.push(aid);
+        } else {
+            // other modes count activities instead of building a calling tree
+            finish::count_spawn(self.finish_id, self.is_frame);
+        }
+        aid
+    }
+
+    fn spawn_at(&mut self, dst: place::Place) -> ActivityId {
+        if !self.finish_id.get_mode().is_tree() {
+            finish::check_spawn(self.finish_id, self.is_frame, dst);
+        }
+        let aid = self.spawn();
+        if self.finish_id.get_mode() == FinishMode::Resilient {
+            self.destinations.push(dst);
+        }
         aid
     }
 
+    fn destinations(&mut self) -> Vec<place::Place> {
+        std::mem::take(&mut self.destinations)
+    }
+
+    fn collecting(&self) -> Option<FinishId> {
+        self.collecting
+    }
+
     fn send(item: Box<TaskItem>) {
         get_task_item_sender_ref().send(item).unwrap();
     }
 }
 
-pub async fn wait_single<T: RemoteSend>(wait_this: ActivityId) -> T {
+pub async fn try_wait_single<T: RemoteSend>(wait_this: ActivityId) -> Result<T, RemotePanic> {
     // TODO dup code
     let (tx, rx) = oneshot::channel::<Box<TaskItem>>();
     get_wait_request_sender_ref()
@@ -206,19 +295,184 @@ pub async fn wait_single<T: RemoteSend>(wait_this: ActivityId) -> T {
         .unwrap();
     let item = rx.await.unwrap();
     let mut ex = TaskItemExtracter::new(*item);
-    let ret = ex.ret::<T>();
-    ret.unwrap() // assert no panic here TODO: deal with panic payload
+    let frame = ActivityFrame::new(ex.fn_id(), wait_this.get_spawned_place());
+    ex.ret::<T>()
+        .map_err(|payload| RemotePanic::new(payload, vec![frame]))
 }
 
-pub async fn wait_all(ctx: ConcreteContext) {
+pub async fn wait_single<T: RemoteSend>(wait_this: ActivityId) -> T {
+    match try_wait_single::<T>(wait_this).await {
+        Ok(ret) => ret,
+        Err(e) => panic!("{}", e), // re-panic at the waiting activity
+    }
+}
+
+/// wait an activity spawned by the local fast path
+pub async fn try_wait_local<T>(
+    fn_id: FunctionLabel,
+    wait_this: ActivityId,
+    handle: JoinHandle<Result<T, PanicPayload>>,
+) -> Result<T, RemotePanic> {
+    let result = handle.await.expect("local activity is cancelled");
+    result.map_err(|payload| {
+        let frame = ActivityFrame::new(fn_id, wait_this.get_spawned_place());
+        RemotePanic::new(payload, vec![frame])
+    })
+}
+
+pub async fn wait_local<T>(
+    fn_id: FunctionLabel,
+    wait_this: ActivityId,
+    handle: JoinHandle<Result<T, PanicPayload>>,
+) -> T {
+    match try_wait_local(fn_id, wait_this, handle).await {
+        Ok(ret) => ret,
+        Err(e) => panic!("{}", e), // re-panic at the waiting activity
+    }
+}
+
+// the wait request is sent before the returned future is polled
+fn request_wait_all(
+    ctx: ConcreteContext,
+    progress: Option<FinishProgress>,
+) -> BoxFuture<'static, Result<(), FinishError>> {
+    if ctx.finish_id.get_mode() == FinishMode::Local {
+        return async move {
+            match finish::wait_local_finish(ctx.finish_id).await {
+                None => Ok(()),
+                Some(remote_panic) => Err(remote_panic.into()),
+            }
+        }
+        .boxed();
+    }
     let (tx, rx) = oneshot::channel::<Box<TaskItem>>();
     get_wait_request_sender_ref()
-        .send(Box::new((WaitItem::All(ctx), tx)))
+        .send(Box::new((WaitItem::All(ctx, progress), tx)))
         .unwrap();
-    let item = rx.await.unwrap();
-    let mut ex = TaskItemExtracter::new(*item);
-    let ret = ex.ret_panic();
-    ret.unwrap() // assert no panic here TODO: deal with panic payload
+    async move {
+        let item = rx.await.unwrap();
+        let mut ex = TaskItemExtracter::new(*item);
+        // the calling tree sends back the panic as a return value
+        match ex.ret::<Option<RemotePanic>>() {
+            Ok(None) => (),
+            Ok(Some(remote_panic)) => return Err(remote_panic.into()),
+            Err(payload) => return Err(RemotePanic::new(payload, vec![]).into()),
+        }
+        // and the lost activities of a resilient finish after it
+        match ex.arg::<Option<DeadPlaceError>>() {
+            None => Ok(()),
+            Some(dead_place) => Err(dead_place.into()),
+        }
+    }
+    .boxed()
+}
+
+// only a resilient finish fails for dead places, which is re-panicked by the others
+fn panic_of(e: FinishError) -> RemotePanic {
+    match e {
+        FinishError::Panicked(remote_panic) => remote_panic,
+        FinishError::DeadPlace(dead_place) => panic!("{}", dead_place),
+    }
+}
+
+pub async fn try_wait_all(ctx: ConcreteContext) -> Result<(), RemotePanic> {
+    request_wait_all(ctx, None).await.map_err(panic_of)
+}
+
+/// like `try_wait_all`, but also returns the activities lost with failed places, for a
+/// finish in the resilient mode, see `try_finish!`
+pub async fn try_wait_resilient(ctx: ConcreteContext) -> Result<(), FinishError> {
+    request_wait_all(ctx, None).await
+}
+
+/// Tell the execution hub of this place that the place has failed. Resilient finishes at this
+/// place stop waiting for activities at the failed place, and complete with a `DeadPlaceError`.
+///
+/// A manual hook: the transport does not detect failed places, so whoever does, e.g. a
+/// heartbeat of the application or the job launcher, calls it at every surviving place
+pub fn notify_place_failure(dead: place::Place) {
+    assert_ne!(
+        dead,
+        place::here(),
+        "a place can not be notified of its own failure"
+    );
+    let (tx, _) = oneshot::channel::<Box<TaskItem>>();
+    get_wait_request_sender_ref()
+        .send(Box::new((WaitItem::PlaceFailed(dead), tx)))
+        .unwrap();
+}
+
+pub async fn wait_all(ctx: ConcreteContext) {
+    if let Err(e) = request_wait_all(ctx, None).await {
+        panic!("{}", e); // re-panic at the finish owner
+    }
+}
+
+/// A finish waited in the background, see `finish_async!`. Resolves to the value of the
+/// finish block once all activities under the finish are done
+pub struct FinishHandle<T> {
+    finish_id: FinishId,
+    progress: FinishProgress,
+    wait: BoxFuture<'static, Result<(), FinishError>>,
+    value: Option<T>,
+}
+
+impl<T> FinishHandle<T> {
+    pub fn new(ctx: ConcreteContext, value: T) -> Self {
+        let finish_id = ctx.finish_id;
+        let outstanding = if finish_id.get_mode().is_tree() {
+            ctx.sub_activities.len()
+        } else {
+            finish::place_outstanding(finish_id)
+        };
+        let progress = Arc::new(AtomicUsize::new(outstanding));
+        FinishHandle {
+            finish_id,
+            progress: progress.clone(),
+            wait: request_wait_all(ctx, Some(progress)),
+            value: Some(value),
+        }
+    }
+
+    /// activities known by the finish place to be spawned but not done
+    pub fn outstanding(&self) -> usize {
+        match self.finish_id.get_mode() {
+            FinishMode::Local => finish::place_outstanding(self.finish_id),
+            _ => self.progress.load(Ordering::Relaxed),
+        }
+    }
+
+    /// like awaiting the handle, but returns the panic of an activity instead of re-panic
+    pub async fn try_join(self) -> Result<T, RemotePanic> {
+        self.try_join_resilient().await.map_err(panic_of)
+    }
+
+    /// like `try_join`, but also returns the activities lost with failed places, for a finish
+    /// in the resilient mode. Survivors can recover from the error, e.g. by redoing the work
+    /// of the lost activities at other places.
+    ///
+    /// Spawns are reported to the finish when the spawning activity is done, so the finish does
+    /// not know the activities spawned by lost activities at surviving places. Such orphans
+    /// might still run when the error returns, so redone work must tolerate them
+    pub async fn try_join_resilient(mut self) -> Result<T, FinishError> {
+        (&mut self.wait).await?;
+        Ok(self.value.take().unwrap())
+    }
+}
+
+// the value is never pinned
+impl<T> Unpin for FinishHandle<T> {}
+
+impl<T> Future for FinishHandle<T> {
+    type Output = T;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        match self.wait.poll_unpin(cx) {
+            Poll::Ready(Ok(())) => Poll::Ready(self.value.take().expect("polled after done")),
+            Poll::Ready(Err(e)) => panic!("{}", e), // re-panic at the finish owner
+            Poll::Pending => Poll::Pending,
+        }
+    }
 }
 
 pub(crate) trait AbstractDistributor: Send + 'static {
@@ -339,6 +593,9 @@ where
     wait_request_receivers: Vec<Option<Receiver<Box<WaitRequest>>>>,
     return_item_sender: FxHashMap<FinishId, oneshot::Sender<Box<TaskItem>>>,
     calling_trees: FxHashMap<FinishId, CallingTree>,
+    finish_progress: FxHashMap<FinishId, FinishProgress>,
+    counting_finishes: FxHashMap<FinishId, CountingFinish>,
+    dead_places: Vec<place::Place>, // notified, for resilient finishes
     #[allow(clippy::vec_box)] // I think store a pointer is faster
     free_items: FxHashMap<FinishId, Vec<Box<TaskItem>>>, // all return value got
     single_wait_free_items: FxHashMap<ActivityIdLower, Box<TaskItem>>, // all return value got
@@ -371,6 +628,9 @@ where
             wait_request_receivers: vec![],
             return_item_sender: FxHashMap::default(),
             calling_trees: FxHashMap::default(),
+            finish_progress: FxHashMap::default(),
+            counting_finishes: FxHashMap::default(),
+            dead_places: vec![],
             free_items: FxHashMap::default(),
             single_wait: FxHashMap::default(),
             single_wait_free_items: FxHashMap::default(),
@@ -399,11 +659,12 @@ where
                 let activity_id = item.activity_id();
                 let finish_id = activity_id.get_finish_id();
                 let mut tree_all_done = false;
+                let mut counting_all_done = false;
                 if item.is_waited() {
                     trace!("waited single {:?}", item);
                     // waited by a single wait
                     if let Some(sender) = self.single_wait.remove(&activity_id.get_lower()) {
-                        sender.send(item).unwrap();
+                        let _ = sender.send(item); // the waiter may be dropped
                     } else {
                         // not yet waited, go to free item
                         self.single_wait_free_items
@@ -416,6 +677,16 @@ where
                     if tree.all_done() {
                         tree_all_done = true; // use another flag to pass borrow checker
                     }
+                    if let Some(progress) = self.finish_progress.get(&finish_id) {
+                        progress.store(tree.outstanding(), Ordering::Relaxed);
+                    }
+                } else if let Some(counting) = self.counting_finishes.get_mut(&finish_id) {
+                    trace!("waited counting {:?}", item);
+                    counting.activity_done(*item);
+                    counting_all_done = counting.all_done();
+                    if let Some(progress) = self.finish_progress.get(&finish_id) {
+                        progress.store(counting.outstanding(), Ordering::Relaxed);
+                    }
                 } else {
                     trace!("waited free {:?}", item);
                     // not waited, go to free items
@@ -426,10 +697,18 @@ where
                     }
                 }
                 // clean up and send back
+                if tree_all_done || counting_all_done {
+                    self.finish_progress.remove(&finish_id);
+                }
                 if tree_all_done {
                     let tree = self.calling_trees.remove(&finish_id).unwrap();
                     let sender = self.return_item_sender.remove(&finish_id).unwrap();
-                    Self::calling_tree_complete_send_return(tree, sender);
+                    Self::finish_complete_send_return(tree.completion(), sender);
+                }
+                if counting_all_done {
+                    let counting = self.counting_finishes.remove(&finish_id).unwrap();
+                    let sender = self.return_item_sender.remove(&finish_id).unwrap();
+                    Self::finish_complete_send_return(counting.completion(), sender);
                 }
             } else {
                 // is request
@@ -442,18 +721,46 @@ where
         }
     }
 
-    fn calling_tree_complete_send_return(
-        tree: CallingTree,
+    fn finish_complete_send_return(
+        error: Option<FinishError>,
         sender: oneshot::Sender<Box<TaskItem>>,
     ) {
+        let (panic, dead_place) = match error {
+            None => (None, None),
+            Some(FinishError::Panicked(e)) => (Some(e), None),
+            Some(FinishError::DeadPlace(e)) => (None, Some(e)),
+        };
         let mut b = TaskItemBuilder::new(0, 0, ActivityId::zero());
-        match tree.panic_backtrace() {
-            None => b.ret(Ok(())), // build an empty ret
-            Some(panic_payload) => b.ret(std::thread::Result::<()>::Err(
-                Box::new(panic_payload) as Box<dyn Any + Send + 'static>
-            )),
+        b.ret_result::<Option<RemotePanic>>(Ok(panic));
+        b.arg::<Option<DeadPlaceError>>(dead_place);
+        // the handle of the finish may be dropped, e.g. losing a race with a timer
+        let _ = sender.send(Box::new(b.build()));
+    }
+
+    fn handle_place_failure(&mut self, dead: place::Place) {
+        if self.dead_places.contains(&dead) {
+            return;
+        }
+        self.dead_places.push(dead);
+        let mut done = vec![];
+        for (finish_id, tree) in self.calling_trees.iter_mut() {
+            if finish_id.get_mode() != FinishMode::Resilient {
+                continue; // waits as before
+            }
+            tree.place_failed(dead);
+            if let Some(progress) = self.finish_progress.get(finish_id) {
+                progress.store(tree.outstanding(), Ordering::Relaxed);
+            }
+            if tree.all_done() {
+                done.push(*finish_id);
+            }
+        }
+        for finish_id in done {
+            self.finish_progress.remove(&finish_id);
+            let tree = self.calling_trees.remove(&finish_id).unwrap();
+            let sender = self.return_item_sender.remove(&finish_id).unwrap();
+            Self::finish_complete_send_return(tree.completion(), sender);
         }
-        sender.send(Box::new(b.build())).unwrap();
     }
 
     fn handle_wait_request(&mut self, wr: WaitRequest) {
@@ -469,19 +776,61 @@ where
                     self.single_wait.insert(aid.get_lower(), w_sender);
                 }
             }
-            WaitItem::All(ctx) => {
+            WaitItem::PlaceFailed(dead) => {
+                trace!("got failure of place {}", dead);
+                self.handle_place_failure(dead);
+            }
+            WaitItem::All(ctx, progress) if !ctx.finish_id.get_mode().is_tree() => {
+                trace!("got counting request :{:?}", ctx);
+                let finish_id = ctx.finish_id;
+                let mut counting = CountingFinish::new(finish_id);
+                if let Some(task_items) = self.free_items.remove(&finish_id) {
+                    for task_item in task_items {
+                        counting.activity_done(*task_item);
+                    }
+                }
+                if let Some(progress) = progress.as_ref() {
+                    progress.store(counting.outstanding(), Ordering::Relaxed);
+                }
+                if counting.all_done() {
+                    Self::finish_complete_send_return(counting.completion(), w_sender);
+                } else {
+                    if let Some(progress) = progress {
+                        self.finish_progress.insert(finish_id, progress);
+                    }
+                    self.counting_finishes.insert(finish_id, counting);
+                    self.return_item_sender.insert(finish_id, w_sender);
+                }
+            }
+            WaitItem::All(ctx, progress) => {
                 trace!("got all request :{:?}", ctx);
                 let finish_id = ctx.finish_id;
-                let mut new_tree = CallingTree::new(ctx.sub_activities);
+                let mut new_tree = if finish_id.get_mode() == FinishMode::Resilient {
+                    let mut ctx = ctx;
+                    let destinations = ctx.destinations();
+                    let mut tree = CallingTree::new_resilient(ctx.sub_activities, destinations);
+                    for dead in self.dead_places.iter() {
+                        tree.place_failed(*dead);
+                    }
+                    tree
+                } else {
+                    CallingTree::new(ctx.sub_activities)
+                };
                 // if some free item already exist
                 if let Some(task_items) = self.free_items.remove(&finish_id) {
                     for task_item in task_items {
                         new_tree.activity_done(*task_item);
                     }
                 }
+                if let Some(progress) = progress.as_ref() {
+                    progress.store(new_tree.outstanding(), Ordering::Relaxed);
+                }
                 if new_tree.all_done() {
-                    Self::calling_tree_complete_send_return(new_tree, w_sender);
+                    Self::finish_complete_send_return(new_tree.completion(), w_sender);
                 } else {
+                    if let Some(progress) = progress {
+                        self.finish_progress.insert(finish_id, progress);
+                    }
                     self.calling_trees.insert(finish_id, new_tree);
                     self.return_item_sender.insert(finish_id, w_sender);
                 }
@@ -574,6 +923,7 @@ mod test {
     use super::*;
     use crate::activity::test::TestGuardForStatic as ATestGuard;
     use crate::activity::test::A;
+    use crate::collecting::PartialReduction;
     use crate::global_id::test::TestGuardForStatic as GTestGuard;
     use crate::global_id::*;
     use futures::executor;
@@ -747,6 +1097,276 @@ mod test {
         ConcreteContext::send(builder.build_box());
     }
 
+    fn counting_wait_all(mode: FinishMode) {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let mut ctx = ConcreteContext::new_frame_with_mode(mode);
+        let aids: Vec<_> = (0..3).map(|_| ctx.spawn()).collect();
+        assert!(ctx.sub_activities.is_empty());
+        let finish_id = ctx.finish_id;
+        let can_ret = ShouldNotReturnUntil::new(move || executor::block_on(wait_all(ctx)));
+        thread::sleep(time::Duration::from_millis(1));
+        finish::count_done(aids[0], 0, None, None);
+        if mode != FinishMode::Here {
+            // a nested activity outlives its parent
+            let mut nested_ctx = ConcreteContext::inherit(finish_id);
+            let nested = nested_ctx.spawn();
+            finish::count_done(aids[1], 0, None, None);
+            thread::sleep(time::Duration::from_millis(1));
+            finish::count_done(nested, 0, None, None);
+        } else {
+            finish::count_done(aids[1], 0, None, None);
+        }
+        thread::sleep(time::Duration::from_millis(1));
+        can_ret.can_return_now();
+        finish::count_done(aids[2], 0, None, None);
+    }
+
+    #[test]
+    fn test_wait_all_spmd() {
+        counting_wait_all(FinishMode::Spmd);
+    }
+
+    #[test]
+    fn test_wait_all_here() {
+        counting_wait_all(FinishMode::Here);
+    }
+
+    #[test]
+    fn test_wait_all_local() {
+        counting_wait_all(FinishMode::Local);
+    }
+
+    #[test]
+    fn test_counting_spawn_destination() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let here = place::here();
+        let other = here + 1;
+        let spawn_at = |ctx: &mut ConcreteContext, dst| {
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| ctx.spawn_at(dst)))
+        };
+        let mut local = ConcreteContext::new_frame_with_mode(FinishMode::Local);
+        assert!(spawn_at(&mut local, other).is_err());
+        let aid = spawn_at(&mut local, here).unwrap();
+        finish::count_done(aid, 0, None, None);
+        executor::block_on(wait_all(local));
+
+        // the block of a spmd finish spawns anywhere, but nested activities only at their place
+        let mut spmd = ConcreteContext::new_frame_with_mode(FinishMode::Spmd);
+        let aid = spawn_at(&mut spmd, other).unwrap();
+        let mut nested = ConcreteContext::inherit(spmd.finish_id);
+        assert!(spawn_at(&mut nested, other).is_err());
+        let nested_aid = spawn_at(&mut nested, here).unwrap();
+        finish::count_done(aid, 0, None, None);
+        finish::count_done(nested_aid, 0, None, None);
+        executor::block_on(wait_all(spmd));
+    }
+
+    #[test]
+    fn test_try_wait_all_counting_panic() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        for mode in [FinishMode::Spmd, FinishMode::Here, FinishMode::Local] {
+            let mut ctx = ConcreteContext::new_frame_with_mode(mode);
+            let aids: Vec<_> = (0..2).map(|_| ctx.spawn()).collect();
+            let payload = crate::activity::cast_panic_payload(Box::new(String::from("boom")));
+            finish::count_done(aids[0], 42, Some(payload), None);
+            finish::count_done(aids[1], 0, None, None);
+            let e = executor::block_on(try_wait_all(ctx)).unwrap_err();
+            assert_eq!(e.message(), "boom");
+            assert_eq!(e.activity().unwrap().fn_id(), 42);
+        }
+    }
+
+    #[test]
+    fn test_wait_all_resilient() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let here = place::here();
+        let dead = here + 1;
+        let mut ctx = ConcreteContext::new_frame_with_mode(FinishMode::Resilient);
+        let survivor = ctx.spawn_at(here);
+        let lost = ctx.spawn_at(dead);
+        let handle = FinishHandle::new(ctx, ());
+        send_1_local(survivor, vec![]);
+        notify_place_failure(dead);
+        match executor::block_on(handle.try_join_resilient()) {
+            Err(FinishError::DeadPlace(e)) => {
+                assert_eq!(e.places(), &[dead]);
+                assert_eq!(e.lost().len(), 1);
+                assert_eq!(e.lost()[0].id(), lost);
+                assert_eq!(e.lost()[0].place(), dead);
+                assert!(e.panic().is_none());
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        // the failure is known before the finish waits
+        let mut ctx = ConcreteContext::new_frame_with_mode(FinishMode::Resilient);
+        ctx.spawn_at(dead);
+        let e = executor::block_on(try_wait_resilient(ctx)).unwrap_err();
+        assert!(matches!(e, FinishError::DeadPlace(_)));
+
+        // a finish of the default mode is not resilient
+        let mut ctx = ConcreteContext::new_frame();
+        let aid = ctx.spawn_at(dead);
+        assert!(ctx.destinations.is_empty());
+        let can_ret = ShouldNotReturnUntil::new(move || executor::block_on(wait_all(ctx)));
+        thread::sleep(time::Duration::from_millis(1));
+        can_ret.can_return_now();
+        send_1_local(aid, vec![]);
+    }
+
+    #[test]
+    fn test_resilient_orphans() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let here = place::here();
+        let dead = here + 1;
+        // like try_finish!(mode = "resilient", { .. })
+        let mut ctx = ConcreteContext::new_frame_with_mode(FinishMode::Resilient);
+        let finish_id = ctx.finish_id;
+        let lost = ctx.spawn_at(dead);
+        // spawned here by the lost activity, which never reports it
+        let orphan = ConcreteContext::inherit(finish_id).spawn_at(here);
+        let finished = request_wait_all(ctx, None);
+        // by whoever detects the failure
+        notify_place_failure(dead);
+        match executor::block_on(finished) {
+            Err(FinishError::DeadPlace(e)) => {
+                assert_eq!(
+                    e.lost().iter().map(|l| l.id()).collect::<Vec<_>>(),
+                    vec![lost]
+                );
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        // the orphan is done after the finish completes
+        send_1_local(orphan, vec![]);
+    }
+
+    #[test]
+    fn test_wait_all_collecting() {
+        use crate::collecting;
+        use crate::collecting::Sum;
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let mut ctx = ConcreteContext::new_frame();
+        let collecting = collecting::start::<Sum<usize>>(&ctx);
+        collecting::offer(ctx.finish_id(), Sum(1usize));
+        let aids: Vec<_> = (0..2).map(|_| ctx.spawn()).collect();
+        for (i, aid) in aids.into_iter().enumerate() {
+            // a return item with a partial reduction from a remote place
+            let mut builder = TaskItemBuilder::new(0, place::here(), aid);
+            builder.ret(thread::Result::<()>::Ok(()));
+            let partial = PartialReduction::new(ctx.finish_id(), &Sum(10usize << i));
+            builder.collected(vec![partial]);
+            ConcreteContext::send(builder.build_box());
+        }
+        executor::block_on(wait_all(ctx));
+        assert_eq!(collecting.result(), Sum(31));
+    }
+
+    #[test]
+    fn test_wait_all_collecting_mismatch() {
+        use crate::collecting;
+        use crate::collecting::Max;
+        use crate::collecting::Sum;
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let mut ctx = ConcreteContext::new_frame();
+        let collecting = collecting::start::<Sum<usize>>(&ctx);
+        let aid = ctx.spawn();
+        // a place reducing with another reducer
+        let mut builder = TaskItemBuilder::new(0, place::here(), aid);
+        builder.ret(thread::Result::<()>::Ok(()));
+        builder.collected(vec![PartialReduction::new(
+            ctx.finish_id(),
+            &Max(Some(1usize)),
+        )]);
+        ConcreteContext::send(builder.build_box());
+        let e = executor::block_on(try_wait_all(ctx)).unwrap_err();
+        assert!(e.payload().message().contains("Max"));
+        assert_eq!(collecting.result(), Sum(0));
+    }
+
+    fn send_done(aid: ActivityId) {
+        let mut builder = TaskItemBuilder::new(0, place::here(), aid);
+        builder.ret(thread::Result::<()>::Ok(()));
+        ConcreteContext::send(builder.build_box());
+    }
+
+    fn wait_outstanding<T>(handle: &FinishHandle<T>, outstanding: usize) {
+        while handle.outstanding() != outstanding {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_finish_handle() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let mut ctx = ConcreteContext::new_frame();
+        let aids: Vec<_> = (0..2).map(|_| ctx.spawn()).collect();
+        let handle = FinishHandle::new(ctx, 42usize);
+        assert_eq!(handle.outstanding(), 2);
+        send_done(aids[0]);
+        wait_outstanding(&handle, 1);
+        send_done(aids[1]);
+        assert_eq!(executor::block_on(handle), 42);
+    }
+
+    #[test]
+    fn test_finish_handle_counting_race() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let mut slow = ConcreteContext::new_frame_with_mode(FinishMode::Spmd);
+        let mut fast = ConcreteContext::new_frame();
+        let slow_aid = slow.spawn();
+        let fast_aid = fast.spawn();
+        let slow = FinishHandle::new(slow, "slow");
+        let fast = FinishHandle::new(fast, "fast");
+        assert_eq!(slow.outstanding(), 1);
+        send_done(fast_aid);
+        let (first, slow) = match executor::block_on(futures::future::select(slow, fast)) {
+            futures::future::Either::Right((first, slow)) => (first, slow),
+            _ => panic!("the slow finish has outstanding activities"),
+        };
+        assert_eq!(first, "fast");
+        assert_eq!(slow.outstanding(), 1);
+        finish::count_done(slow_aid, 0, None, None);
+        assert_eq!(executor::block_on(slow.try_join()).unwrap(), "slow");
+    }
+
+    #[test]
+    fn test_finish_handle_dropped() {
+        use futures::FutureExt;
+        let _e = ExecutorHubSetUp::new_with_fake();
+        // a handle losing a race is dropped before its activities are done
+        let mut ctx = ConcreteContext::new_frame();
+        let aid = ctx.spawn();
+        let mut handle = FinishHandle::new(ctx, ());
+        assert!((&mut handle).now_or_never().is_none());
+        drop(handle);
+        send_done(aid);
+        let mut local = ConcreteContext::new_frame_with_mode(FinishMode::Local);
+        let local_aid = local.spawn_at(place::here());
+        let mut handle = FinishHandle::new(local, ());
+        assert!((&mut handle).now_or_never().is_none());
+        drop(handle);
+        finish::count_done(local_aid, 0, None, None);
+        // the hub keeps serving other finishes
+        let mut ctx = ConcreteContext::new_frame();
+        let aid = ctx.spawn();
+        let handle = FinishHandle::new(ctx, 42usize);
+        send_done(aid);
+        assert_eq!(executor::block_on(handle), 42);
+    }
+
+    #[test]
+    fn test_finish_here_nested_spawn() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let ctx = ConcreteContext::new_frame_with_mode(FinishMode::Here);
+        use std::panic::{self, AssertUnwindSafe};
+        // catch manually to avoid poison the lock
+        let ret = panic::catch_unwind(AssertUnwindSafe(move || {
+            ConcreteContext::inherit(ctx.finish_id).spawn();
+        }));
+        assert!(ret.is_err());
+    }
+
     #[test]
     fn test_single_wait_local_send_after_wait() {
         let _e = ExecutorHubSetUp::new_with_fake();
@@ -821,6 +1441,39 @@ mod test {
         assert!(ret.is_err());
     }
 
+    #[test]
+    fn test_try_single_wait_local_panic() {
+        let _e = ExecutorHubSetUp::new_with_fake();
+        let mut ctx = ConcreteContext::new_frame();
+        std::panic::set_hook(Box::new(|_| {})); // silence backtrace
+        let new_aid = ctx.spawn();
+        let f = try_wait_single::<usize>(new_aid);
+        send_2_local_panic(new_aid, vec![]);
+        let ret = executor::block_on(f);
+        let _ = std::panic::take_hook();
+        assert_eq!(ret.unwrap_err().message(), "I panic!");
+        executor::block_on(wait_all(ctx));
+    }
+
+    #[test]
+    fn test_wait_local() {
+        use crate::activity::cast_panic_payload;
+        let rt = crate::executor::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let aid = ActivityId::zero();
+            let handle = crate::spawn(async { Ok::<usize, PanicPayload>(123) });
+            assert_eq!(wait_local(7, aid, handle).await, 123);
+
+            let payload = cast_panic_payload(Box::new("I panic!"));
+            let handle = crate::spawn(async move { Err::<usize, _>(payload) });
+            let e = try_wait_local(7, aid, handle).await.unwrap_err();
+            assert_eq!(e.message(), "I panic!");
+            assert_eq!(e.activity().unwrap().fn_id(), 7);
+        });
+    }
+
     fn activity_tree(
         ctx: &mut ConcreteContext,
         current_depth: usize,
@@ -5,7 +5,9 @@ use crate::activity::HelperMap;
 use crate::activity::SquashTypeHelper;
 use crate::activity::TaskItem;
 use crate::args::RemoteSend;
+use crate::collective;
 use crate::logging;
+use crate::place::Place;
 use futures::future::BoxFuture;
 use once_cell::sync::Lazy;
 use rustc_hash::FxHashMap;
@@ -46,6 +48,26 @@ impl FunctionMetaData {
         }
     }
 
+    pub fn fn_id(&self) -> FunctionLabel {
+        self.fn_id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.fn_name
+    }
+
+    pub fn file(&self) -> &str {
+        &self.file
+    }
+
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn module_path(&self) -> &str {
+        &self.mod_path
+    }
+
     pub fn call(&self, item: TaskItem) -> BoxFuture<'static, ()> {
         (self.fn_ptr)(item)
     }
@@ -53,6 +75,28 @@ impl FunctionMetaData {
 
 inventory::collect!(FunctionMetaData);
 
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+const fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    let mut hash = hash;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+/// The id of an activity function. It only depends on where and how the function is
+/// defined, so the same source always gets the same id, whichever binary it is built into
+pub const fn function_id(module_path: &str, signature: &str) -> FunctionLabel {
+    let hash = fnv1a(FNV_OFFSET_BASIS, module_path.as_bytes());
+    let hash = fnv1a(hash, b"::");
+    fnv1a(hash, signature.as_bytes())
+}
+
 type FuncMetaTable = FxHashMap<FunctionLabel, FunctionMetaData>;
 static STATIC_FUNC_META_TABLE: Lazy<Mutex<FuncMetaTable>> =
     Lazy::new(|| Mutex::new(FuncMetaTable::default()));
@@ -90,6 +134,64 @@ pub(crate) fn init_func_table() {
     );
 }
 
+// id, name, module path
+type FuncTableEntry = (FunctionLabel, String, String);
+
+fn func_table_entries(table: &FuncMetaTable) -> Vec<FuncTableEntry> {
+    let mut entries: Vec<_> = table
+        .values()
+        .map(|f| (f.fn_id, f.fn_name.clone(), f.mod_path.clone()))
+        .collect();
+    entries.sort();
+    entries
+}
+
+fn func_table_digest(entries: &[FuncTableEntry]) -> u64 {
+    let mut hash = FNV_OFFSET_BASIS;
+    for (fn_id, name, mod_path) in entries {
+        hash = fnv1a(hash, &fn_id.to_le_bytes());
+        hash = fnv1a(hash, name.as_bytes());
+        hash = fnv1a(hash, mod_path.as_bytes());
+    }
+    hash
+}
+
+// compare the function table of every place to the one of place 0
+fn func_table_diff(tables: &[Vec<FuncTableEntry>]) -> String {
+    let mut diff = String::from(
+        "Function tables differ between places. Are all places running the same binary?",
+    );
+    let base = &tables[0];
+    for (place, table) in tables.iter().enumerate().skip(1) {
+        let missing: Vec<_> = base.iter().filter(|e| !table.contains(e)).collect();
+        let extra: Vec<_> = table.iter().filter(|e| !base.contains(e)).collect();
+        if missing.is_empty() && extra.is_empty() {
+            continue;
+        }
+        diff.push_str(&format!("\nplace {} vs place 0:", place as Place));
+        for (sign, entries) in [("-", missing), ("+", extra)].iter() {
+            for (fn_id, name, mod_path) in entries.iter() {
+                diff.push_str(&format!("\n  {} {}::{} ({})", sign, mod_path, name, fn_id));
+            }
+        }
+    }
+    diff
+}
+
+/// all places must register the same activities, otherwise a fn_id received might be
+/// resolved to a wrong function. Panic if not
+pub(crate) async fn check_func_table() {
+    let entries = func_table_entries(&STATIC_FUNC_META_TABLE.lock().unwrap());
+    let digest = func_table_digest(&entries[..]);
+    let digests = collective::all_gather(digest).await;
+    if digests.iter().all(|d| *d == digest) {
+        return;
+    }
+    // every place sees the mismatch, so all of them join the second gather
+    let tables = collective::all_gather(entries).await;
+    panic!("{}", func_table_diff(&tables[..]));
+}
+
 pub(crate) fn get_func_table() -> &'static FuncMetaTable {
     thread_local! {
         static FUNC_META_TABLE: Cell<Option<FuncMetaTable>> = Cell::new(None);
@@ -131,6 +233,43 @@ pub(crate) fn init_helpers() {
     set_helpers(helpers);
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONST_ID: FunctionLabel = function_id("foo::bar", "async fn baz(a : i32) -> i32");
+
+    #[test]
+    fn test_function_id() {
+        assert_eq!(
+            CONST_ID,
+            function_id("foo::bar", "async fn baz(a : i32) -> i32")
+        );
+        assert_ne!(
+            CONST_ID,
+            function_id("foo::qux", "async fn baz(a : i32) -> i32")
+        );
+        assert_ne!(
+            CONST_ID,
+            function_id("foo::bar", "async fn baz(a : i64) -> i32")
+        );
+    }
+
+    #[test]
+    fn test_func_table_diff() {
+        let entry = |id: FunctionLabel, name: &str| (id, String::from(name), String::from("m"));
+        let table0 = vec![entry(1, "a"), entry(2, "b")];
+        let table1 = vec![entry(1, "a"), entry(3, "c")];
+        let digest0 = func_table_digest(&table0[..]);
+        assert_eq!(digest0, func_table_digest(&table0.clone()[..]));
+        assert_ne!(digest0, func_table_digest(&table1[..]));
+
+        let diff = func_table_diff(&[table0.clone(), table0.clone(), table1]);
+        assert!(!diff.contains("place 1 vs place 0"));
+        assert!(diff.contains("place 2 vs place 0:\n  - m::b (2)\n  + m::c (3)"));
+    }
+}
+
 // This mod is trying to do something like "check types at compiling time"
 // Dark Magic: Just register all type of squashable, and manually create dyn trait at runtime
 // If rust support specialization someday, will remove this.
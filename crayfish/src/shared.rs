@@ -1,4 +1,8 @@
+use crate::activity;
 use crate::args::RemoteSend;
+use crate::collective;
+use crate::place;
+use crate::place::Place;
 use once_cell::sync::Lazy;
 use parking_lot::const_rwlock;
 use parking_lot::RwLock;
@@ -6,27 +10,162 @@ use rustc_hash::FxHashMap;
 use serde::Deserialize;
 use serde::Serialize;
 use std::any::Any;
+use std::fmt;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 type HandleID = usize;
 
+// handles created by new_everywhere are numbered apart from those by new, so the collective ones
+// agree at every place however many local ones each place creates
+const COLLECTIVE_ID_BASE: HandleID = !(HandleID::MAX >> 1);
+
+fn is_collective(id: HandleID) -> bool {
+    id >= COLLECTIVE_ID_BASE
+}
+
 #[derive(Default)]
 struct ClipBoard {
     next_id: HandleID,
+    next_collective_id: HandleID,
     records: FxHashMap<HandleID, Box<dyn Any + Send + Sync>>,
+    // of collective handles alive at this place, or not yet created here but already received
+    weak_counters: FxHashMap<HandleID, Arc<WeakCounter>>,
+}
+
+impl ClipBoard {
+    fn created(&self, id: HandleID) -> bool {
+        id - COLLECTIVE_ID_BASE < self.next_collective_id
+    }
 }
 
 static CLIP_BOARD: Lazy<RwLock<ClipBoard>> = Lazy::new(|| const_rwlock(ClipBoard::default()));
 
-// it is fine to clone. The first to drop will clean the record in clipboard
-#[derive(Clone)]
-pub struct PlaceLocal<T: ?Sized> {
+// References to a collective handle from weak handles at this place, for destroy to wait for.
+// Only collective handles can be destroyed, so weak handles of local ones are not counted. A
+// weak handle is sent by serializing it, so it is on the way from sent until received. Bytes
+// deserialized at many places, e.g. arguments of at_each, are sent once per place.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct WeakCount {
+    live: u64,
+    sent: u64,
+    received: u64,
+}
+
+impl RemoteSend for WeakCount {
+    crate::impl_body! {}
+}
+
+// shared by the weak handles of a collective handle at this place, so cloning and dropping them
+// takes no lock
+#[derive(Debug, Default)]
+struct WeakCounter {
+    live: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl WeakCounter {
+    fn count(&self) -> WeakCount {
+        WeakCount {
+            live: self.live.load(Ordering::SeqCst),
+            sent: self.sent.load(Ordering::SeqCst),
+            received: self.received.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// the counter of a handle, none if not counted or already dropped at this place
+fn weak_counter(id: HandleID) -> Option<Arc<WeakCounter>> {
+    if !is_collective(id) {
+        return None;
+    }
+    let h = CLIP_BOARD.read();
+    if let Some(counter) = h.weak_counters.get(&id) {
+        return Some(counter.clone());
+    }
+    if h.created(id) {
+        return None;
+    }
+    drop(h);
+    // received before created here, e.g. from a place that has returned from new_everywhere
+    let mut h = CLIP_BOARD.write();
+    if h.created(id) && !h.weak_counters.contains_key(&id) {
+        return None;
+    }
+    Some(h.weak_counters.entry(id).or_default().clone())
+}
+
+// no place references the handle, except the PlaceLocal destroying it at each place
+fn quiescent(counts: &[WeakCount]) -> bool {
+    let sum = |f: fn(&WeakCount) -> u64| counts.iter().map(f).sum::<u64>();
+    sum(|c| c.live) == 0 && sum(|c| c.sent) == sum(|c| c.received)
+}
+
+/// Why a weak handle cannot be upgraded
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum UpgradeError {
+    /// the value is dropped, or never created at this place
+    Missing { id: usize },
+    /// the value is not of the expected type
+    WrongType { id: usize, expected: &'static str },
+}
+
+impl fmt::Display for UpgradeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpgradeError::Missing { id } => {
+                write!(
+                    f,
+                    "place local {} is missing at place {}",
+                    id,
+                    place::here()
+                )
+            }
+            UpgradeError::WrongType { id, expected } => write!(
+                f,
+                "place local {} at place {} is not of type {}",
+                id,
+                place::here(),
+                expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for UpgradeError {}
+
+// the record is removed when the last clone of a PlaceLocal drops
+struct Handle<T: ?Sized> {
     id: HandleID,
     value: Arc<T>,
 }
 
+impl<T: ?Sized> Drop for Handle<T> {
+    fn drop(&mut self) {
+        let mut h = CLIP_BOARD.write();
+        h.records.remove(&self.id);
+        h.weak_counters.remove(&self.id);
+    }
+}
+
+/// A value at this place, which can be reached by weak handles sent to this place. Clones share
+/// the value, which is reachable until the last clone drops.
+pub struct PlaceLocal<T: ?Sized> {
+    handle: Arc<Handle<T>>,
+}
+
+impl<T: ?Sized> Clone for PlaceLocal<T> {
+    fn clone(&self) -> Self {
+        PlaceLocal {
+            handle: self.handle.clone(),
+        }
+    }
+}
+
 // it is reasonable to be 'static since T might live arbitrarily long.
 // T must be Sync and Send since accessed in different threads.
 impl<T> PlaceLocal<T>
@@ -36,18 +175,90 @@ where
     pub fn new(val: T) -> Self {
         let mut h = CLIP_BOARD.write();
         let id = h.next_id;
-        let pl = PlaceLocal {
-            id,
-            value: Arc::new(val),
-        };
-        let record = Box::new(pl.value.clone());
-        h.records.insert(pl.id, record);
         h.next_id += 1;
+        Self::insert(&mut h, id, val)
+    }
+
+    fn insert(h: &mut ClipBoard, id: HandleID, val: T) -> Self {
+        let value = Arc::new(val);
+        h.records.insert(id, Box::new(value.clone()));
+        if is_collective(id) {
+            h.weak_counters.entry(id).or_default();
+        }
+        PlaceLocal {
+            handle: Arc::new(Handle { id, value }),
+        }
+    }
+
+    /// Construct a value by init at every place under the same handle, so that its weak handle
+    /// can be upgraded at any place. A collective: every place must call it in the same order.
+    /// Panic if places disagree on the handle
+    pub async fn new_everywhere<F>(init: F) -> Self
+    where
+        F: FnOnce() -> T,
+    {
+        let pl = Self::new_collective(init());
+        let handles = collective::all_gather((pl.id(), String::from(std::any::type_name::<T>())));
+        check_agreed(pl.id(), handles.await);
         pl
     }
 
+    pub(crate) fn new_collective(val: T) -> Self {
+        let mut h = CLIP_BOARD.write();
+        let id = COLLECTIVE_ID_BASE + h.next_collective_id;
+        h.next_collective_id += 1;
+        Self::insert(&mut h, id, val)
+    }
+
     pub fn downgrade(&self) -> PlaceLocalWeak<T> {
-        PlaceLocalWeak::<T>::new(self.id)
+        PlaceLocalWeak::<T>::new(self.id())
+    }
+
+    /// a reference to the value that can be sent to other places, see `GlobalRef`
+    pub fn global_ref(&self) -> GlobalRef<T> {
+        GlobalRef {
+            home: place::here(),
+            weak: self.downgrade(),
+        }
+    }
+
+    // references at this place other than this PlaceLocal, the record and weak handles
+    fn strong_references(&self) -> u64 {
+        let clones = Arc::strong_count(&self.handle) - 1;
+        let upgraded = Arc::strong_count(&self.handle.value) - 2; // by the handle and the record
+        (clones + upgraded) as u64
+    }
+
+    /// Remove the value at every place once no place references it: no weak handles, neither
+    /// kept nor on the way in activities, no upgraded values and no other clones. A collective:
+    /// every place must call it in the same order, outside finish blocks. Panic if the handle
+    /// is not by `new_everywhere`
+    pub async fn destroy(self) {
+        let id = self.id();
+        assert!(
+            is_collective(id),
+            "place local {} is destroyed, but not created by new_everywhere",
+            id
+        );
+        let counter = weak_counter(id).expect("counter of a live handle");
+        loop {
+            let mut local = counter.count();
+            local.live += self.strong_references();
+            // every place gets the same counts, so all stop at the same round
+            if quiescent(&collective::all_gather(local).await[..]) {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        let mut h = CLIP_BOARD.write();
+        h.records.remove(&id);
+        h.weak_counters.remove(&id);
+    }
+}
+
+impl<T: ?Sized> PlaceLocal<T> {
+    fn id(&self) -> HandleID {
+        self.handle.id
     }
 }
 
@@ -55,36 +266,115 @@ impl<T: ?Sized> Deref for PlaceLocal<T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &self.value
+        &self.handle.value
     }
 }
 
-impl<T> Drop for PlaceLocal<T>
-where
-    T: ?Sized,
-{
-    fn drop(&mut self) {
-        let mut h = CLIP_BOARD.write();
-        h.records.remove(&self.id);
-    }
+// handles of a new_everywhere at each place, indexed by place
+fn check_agreed(id: HandleID, handles: Vec<(HandleID, String)>) {
+    let (_, type_name) = &handles[place::here() as usize];
+    let diverged: Vec<_> = handles
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| h.0 != id || &h.1 != type_name)
+        .map(|(place, (id, type_name))| {
+            format!(
+                "place {}: {} of {}",
+                place,
+                id - COLLECTIVE_ID_BASE,
+                type_name
+            )
+        })
+        .collect();
+    assert!(
+        diverged.is_empty(),
+        "place local handles diverge from {} of {} at place {}: {}. Is new_everywhere called in \
+         the same order at every place?",
+        id - COLLECTIVE_ID_BASE,
+        type_name,
+        place::here(),
+        diverged.join(", ")
+    );
 }
 
-#[derive(Default, Serialize, Deserialize)]
+/// A handle to a place-local value, which can be sent to other places and upgraded to the value
+/// of the same handle there
 pub struct PlaceLocalWeak<T: ?Sized> {
     id: HandleID,
+    counter: Option<Arc<WeakCounter>>,
     _mark: PhantomData<T>,
 }
 
-impl<T> Clone for PlaceLocalWeak<T>
-where
-    T: ?Sized,
-{
-    fn clone(&self) -> Self {
+impl<T: ?Sized> PlaceLocalWeak<T> {
+    fn new(id: HandleID) -> Self {
+        Self::with_counter(id, weak_counter(id))
+    }
+
+    fn with_counter(id: HandleID, counter: Option<Arc<WeakCounter>>) -> Self {
+        if let Some(counter) = counter.as_ref() {
+            counter.live.fetch_add(1, Ordering::SeqCst);
+        }
         PlaceLocalWeak {
-            id: self.id,
+            id,
+            counter,
             _mark: PhantomData,
         }
     }
+
+    /// the handle, the same at every place for handles by `new_everywhere`
+    pub(crate) fn id(&self) -> usize {
+        self.id
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for PlaceLocalWeak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PlaceLocalWeak")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+impl<T: ?Sized> Default for PlaceLocalWeak<T> {
+    fn default() -> Self {
+        Self::new(HandleID::default())
+    }
+}
+
+impl<T: ?Sized> Clone for PlaceLocalWeak<T> {
+    fn clone(&self) -> Self {
+        Self::with_counter(self.id, self.counter.clone())
+    }
+}
+
+impl<T: ?Sized> Drop for PlaceLocalWeak<T> {
+    fn drop(&mut self) {
+        if let Some(counter) = self.counter.as_ref() {
+            counter.live.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl<T: ?Sized> Serialize for PlaceLocalWeak<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if let Some(counter) = self.counter.as_ref() {
+            // counted as sent to each place of at_each!
+            let deliveries = activity::deliveries() as u64;
+            counter.sent.fetch_add(deliveries, Ordering::SeqCst);
+        }
+        self.id.serialize(serializer)
+    }
+}
+
+impl<'de, T: ?Sized> Deserialize<'de> for PlaceLocalWeak<T> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let id = HandleID::deserialize(deserializer)?;
+        let weak = Self::new(id);
+        if let Some(counter) = weak.counter.as_ref() {
+            counter.received.fetch_add(1, Ordering::SeqCst);
+        }
+        Ok(weak)
+    }
 }
 
 // TODO remove that, when https://github.com/jaxonwang/rust-apgas/issues/18 solved
@@ -93,18 +383,79 @@ impl<T: ?Sized + Send + 'static> RemoteSend for PlaceLocalWeak<T> {
 }
 
 impl<T: 'static> PlaceLocalWeak<T> {
-    fn new(id: HandleID) -> Self {
-        PlaceLocalWeak {
-            id,
-            _mark: PhantomData,
+    pub fn upgrade(&self) -> Result<Arc<T>, UpgradeError> {
+        let h = CLIP_BOARD.read();
+        let record = h
+            .records
+            .get(&self.id)
+            .ok_or(UpgradeError::Missing { id: self.id })?;
+        match record.downcast_ref::<Arc<T>>() {
+            Some(value) => Ok(value.clone()),
+            None => Err(UpgradeError::WrongType {
+                id: self.id,
+                expected: std::any::type_name::<T>(),
+            }),
         }
     }
+}
 
-    pub fn upgrade(&self) -> Option<Arc<T>> {
-        let h = CLIP_BOARD.read();
-        h.records
-            .get(&self.id)
-            .map(|b| b.downcast_ref::<Arc<T>>().unwrap().clone())
+/// A reference to a place-local value that remembers its home place. It can be sent anywhere,
+/// but only dereferenced at home. Use `at_home!` to run code on the value at its home
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub struct GlobalRef<T: ?Sized> {
+    home: Place,
+    weak: PlaceLocalWeak<T>,
+}
+
+impl<T: ?Sized> Clone for GlobalRef<T> {
+    fn clone(&self) -> Self {
+        GlobalRef {
+            home: self.home,
+            weak: self.weak.clone(),
+        }
+    }
+}
+
+impl<T: ?Sized> fmt::Debug for GlobalRef<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GlobalRef")
+            .field("home", &self.home)
+            .field("id", &self.weak.id)
+            .finish()
+    }
+}
+
+impl<T: ?Sized + Send + 'static> RemoteSend for GlobalRef<T> {
+    crate::impl_body! {}
+}
+
+impl<T: 'static> GlobalRef<T> {
+    pub fn home(&self) -> Place {
+        self.home
+    }
+
+    pub fn is_home(&self) -> bool {
+        self.home == place::here()
+    }
+
+    /// the value if it is still alive. Panic if not at home
+    pub fn upgrade(&self) -> Result<Arc<T>, UpgradeError> {
+        assert!(
+            self.is_home(),
+            "{:?} is dereferenced at place {}, not at its home",
+            self,
+            place::here()
+        );
+        self.weak.upgrade()
+    }
+
+    /// the value. Panic if not at home or the value is dropped
+    pub fn get(&self) -> Arc<T> {
+        match self.upgrade() {
+            Ok(value) => value,
+            Err(e) => panic!("the value of {:?} is unavailable: {}", self, e),
+        }
     }
 }
 
@@ -123,8 +474,8 @@ mod test {
             let threads: Vec<_> = weak_ptrs
                 .map(|ptr| {
                     thread::spawn(move || match ptr.upgrade() {
-                        Some(p) => assert_eq!(*p, value),
-                        None => (),
+                        Ok(p) => assert_eq!(*p, value),
+                        Err(_) => (),
                     })
                 })
                 .collect();
@@ -134,6 +485,153 @@ mod test {
             // now pl is destroyed. return ptrs should be invalid.
         };
         ptrs.into_iter()
-            .for_each(|p| assert!(p.upgrade().is_none()));
+            .for_each(|p| assert!(p.upgrade().is_err()));
+    }
+
+    #[test]
+    pub fn test_new_collective() {
+        use crate::global_id::test::TestGuardForStatic;
+        use std::panic;
+        let _a = TestGuardForStatic::new();
+        let a = PlaceLocal::new_collective(1usize);
+        // local handles do not disturb collective ones
+        let _local = PlaceLocal::new(2usize);
+        let b = PlaceLocal::new_collective(String::from("b"));
+        assert_eq!(b.id(), a.id() + 1);
+        assert_eq!(*b.downgrade().upgrade().unwrap(), "b");
+
+        let name = |s: &str| String::from(s);
+        let agreed = vec![(b.id(), name("alloc::string::String")); 8];
+        check_agreed(b.id(), agreed.clone());
+        let mut other_id = agreed.clone();
+        other_id[3].0 = a.id();
+        assert!(panic::catch_unwind(|| check_agreed(b.id(), other_id)).is_err());
+        let mut other_type = agreed;
+        other_type[0].1 = name("usize");
+        assert!(panic::catch_unwind(|| check_agreed(b.id(), other_type)).is_err());
+    }
+
+    #[test]
+    pub fn test_place_local_clones() {
+        let pl = PlaceLocal::new(1u32);
+        let weak = pl.downgrade();
+        let cloned = pl.clone();
+        // the value is alive until the last clone drops
+        drop(pl);
+        assert_eq!(*weak.upgrade().unwrap(), 1);
+        drop(cloned);
+        let id = weak.id;
+        assert_eq!(weak.upgrade(), Err(UpgradeError::Missing { id }));
+    }
+
+    #[test]
+    pub fn test_upgrade_wrong_type() {
+        let pl = PlaceLocal::new(1u32);
+        let weak = PlaceLocalWeak::<String>::new(pl.id());
+        let error = weak.upgrade().unwrap_err();
+        assert_eq!(
+            error,
+            UpgradeError::WrongType {
+                id: pl.id(),
+                expected: "alloc::string::String"
+            }
+        );
+        assert!(error
+            .to_string()
+            .contains("is not of type alloc::string::String"));
+    }
+
+    #[test]
+    pub fn test_weak_count() {
+        use crate::serialization::deserialize_from;
+        use crate::serialization::serialize_into;
+        let pl = PlaceLocal::new_collective(String::from("counted"));
+        let counter = weak_counter(pl.id()).unwrap();
+        let counts = || counter.count();
+        let weak = pl.downgrade();
+        let upgraded = weak.upgrade().unwrap();
+        assert_eq!(pl.strong_references(), 1);
+        drop(upgraded);
+        assert_eq!(pl.strong_references(), 0);
+
+        // on the way to another place
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &weak).unwrap();
+        drop(weak);
+        let on_the_way = counts();
+        assert_eq!(
+            (on_the_way.live, on_the_way.sent, on_the_way.received),
+            (0, 1, 0)
+        );
+        assert!(!quiescent(&[on_the_way, WeakCount::default()]));
+        // received there
+        let received: PlaceLocalWeak<String> = deserialize_from(&bytes[..]).unwrap();
+        assert!(!quiescent(&[counts()]));
+        drop(received);
+        assert!(quiescent(&[counts()]));
+
+        // bytes for two places, like arguments of at_each
+        let weak = pl.downgrade();
+        let bytes = activity::serialize_args(&weak, &[0, 1]);
+        drop(weak);
+        let there: PlaceLocalWeak<String> = deserialize_from(&bytes[..]).unwrap();
+        drop(there);
+        assert!(!quiescent(&[counts()]));
+        let there: PlaceLocalWeak<String> = deserialize_from(&bytes[..]).unwrap();
+        drop(there);
+        assert!(quiescent(&[counts()]));
+    }
+
+    #[test]
+    pub fn test_weak_counter_freed() {
+        use crate::serialization::deserialize_from;
+        use crate::serialization::serialize_into;
+        use futures::executor;
+        use std::panic;
+        // weak handles of local handles are not counted, and those cannot be destroyed
+        let local = PlaceLocal::new(1u32);
+        assert!(local.downgrade().counter.is_none());
+        assert!(panic::catch_unwind(|| executor::block_on(local.destroy())).is_err());
+
+        let pl = PlaceLocal::new_collective(1u32);
+        let id = pl.id();
+        let weak = pl.downgrade();
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &weak).unwrap();
+        drop(pl);
+        assert!(!CLIP_BOARD.read().weak_counters.contains_key(&id));
+        // received after dropped here
+        let late: PlaceLocalWeak<u32> = deserialize_from(&bytes[..]).unwrap();
+        assert!(late.counter.is_none());
+        assert!(!CLIP_BOARD.read().weak_counters.contains_key(&id));
+        drop(weak);
+    }
+
+    #[test]
+    pub fn test_global_ref() {
+        use crate::global_id::test::TestGuardForStatic;
+        use crate::global_id::test::TEST_HERE;
+        use crate::serialization::deserialize_from;
+        use crate::serialization::serialize_into;
+        use std::panic;
+        let _a = TestGuardForStatic::new();
+        let pl = PlaceLocal::new(String::from("home"));
+        let gref = pl.global_ref();
+        assert_eq!(gref.home(), TEST_HERE);
+        // a reference survives a trip to another place
+        let mut bytes = vec![];
+        serialize_into(&mut bytes, &gref).unwrap();
+        let gref: GlobalRef<String> = deserialize_from(&bytes[..]).unwrap();
+        assert_eq!(*gref.get(), "home");
+
+        let away = GlobalRef::<String> {
+            home: TEST_HERE + 1,
+            weak: gref.weak.clone(),
+        };
+        assert!(!away.is_home());
+        assert!(panic::catch_unwind(|| away.upgrade()).is_err());
+
+        drop(pl);
+        assert!(gref.upgrade().is_err());
     }
 }
@@ -0,0 +1,467 @@
+use crate::activity::cast_panic_payload;
+use crate::activity::FunctionLabel;
+use crate::activity::RemotePanic;
+use crate::activity::TaskItem;
+use crate::activity::TaskItemExtracter;
+use crate::args::RemoteSend;
+use crate::place::here;
+use crate::place::Place;
+use crate::remote;
+use crate::runtime_meta;
+use crate::runtime_meta::inventory;
+use crate::runtime_meta::FunctionMetaData;
+use crate::serialization::deserialize_from;
+use crate::serialization::serialize_into;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::Stream;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use tokio::sync::mpsc;
+use tokio::sync::Notify;
+
+// A stream activity sends its items to the consumer place in batches, as task items going
+// through the squash buffers like any other message. The producer only takes items from the
+// stream while it holds credits, which the consumer returns as items are consumed, so at most a
+// window of items is in flight or buffered at the consumer. Dropping the consumer cancels the
+// producer, which stops taking items once it sees the cancel and ends the activity. Messages
+// might be reordered on the way, so batches are numbered and put back in order by the consumer,
+// and the end carries the number of batches before it.
+
+/// The number of items a stream producer can send ahead of the consumer
+pub const STREAM_WINDOW: usize = 1024;
+const MAX_BATCH: usize = STREAM_WINDOW / 4;
+
+type StreamId = (Place, u64); // consumer and id at consumer
+
+#[derive(Serialize, Deserialize)]
+enum Message {
+    Items(u64, Vec<u8>),           // the sequence number and a serialized batch
+    End(u64, Option<RemotePanic>), // after the number of batches
+}
+
+impl RemoteSend for Message {
+    crate::impl_body! {}
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+enum Control {
+    Credit(usize),
+    Cancel,
+}
+
+impl RemoteSend for Control {
+    crate::impl_body! {}
+}
+
+#[derive(Default)]
+struct FlowState {
+    credits: usize,
+    cancelled: bool,
+}
+
+#[derive(Default)]
+struct Flow {
+    state: Mutex<FlowState>,
+    notify: Notify,
+}
+
+impl Flow {
+    fn apply(&self, control: Control) {
+        {
+            let mut state = self.state.lock();
+            match control {
+                Control::Credit(n) => state.credits += n,
+                Control::Cancel => state.cancelled = true,
+            }
+        }
+        self.notify.notify_one();
+    }
+
+    // waits for credits and takes at most max of them, None if cancelled
+    async fn acquire(&self, max: usize) -> Option<usize> {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock();
+                if state.cancelled {
+                    return None;
+                }
+                if state.credits > 0 {
+                    let n = std::cmp::min(state.credits, max);
+                    state.credits -= n;
+                    return Some(n);
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+static NEXT_STREAM_ID: AtomicU64 = AtomicU64::new(0);
+static CONSUMERS: Lazy<Mutex<FxHashMap<u64, mpsc::UnboundedSender<Message>>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+// A control message can come before the producer starts. A cancel coming after the producer
+// ends leaves an entry behind, which is small and only happens if the consumer is dropped
+// while the end of the stream is in flight
+static PRODUCERS: Lazy<Mutex<FxHashMap<StreamId, Arc<Flow>>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+fn flow(id: StreamId) -> Arc<Flow> {
+    PRODUCERS.lock().entry(id).or_default().clone()
+}
+
+fn send_control(id: StreamId, producer: Place, control: Control) {
+    if producer == here() {
+        flow(id).apply(control);
+    } else {
+        remote::send(producer, CONTROL_FN_ID, |builder| {
+            builder.arg(id);
+            builder.arg(control);
+        });
+    }
+}
+
+fn send_message(id: StreamId, message: Message) {
+    if id.0 == here() {
+        receive(id.1, message);
+    } else {
+        remote::send(id.0, MESSAGE_FN_ID, |builder| {
+            builder.arg(id.1);
+            builder.arg(message);
+        });
+    }
+}
+
+fn receive(id: u64, message: Message) {
+    // the consumer might be gone
+    if let Some(tx) = CONSUMERS.lock().get(&id) {
+        let _ = tx.send(message);
+    }
+}
+
+/// The producing end of a remote stream, sent to the stream activity
+#[derive(Serialize, Deserialize)]
+pub struct StreamSink<T> {
+    id: StreamId,
+    window: usize,
+    _item: PhantomData<fn(T)>,
+}
+
+impl<T: RemoteSend> RemoteSend for StreamSink<T> {
+    crate::impl_body! {}
+}
+
+/// The consuming end of a remote stream, resolved by `at_stream!`. Panics if the stream
+/// panics at the producer place, like `at!`. Dropping it cancels the producer.
+pub struct RemoteStream<T> {
+    id: StreamId,
+    producer: Place,
+    rx: mpsc::UnboundedReceiver<Message>,
+    buffer: VecDeque<T>,
+    consumed: usize, // since credits were last returned
+    next_batch: u64,
+    pending: FxHashMap<u64, Vec<u8>>, // batches arriving before the next one
+    end: Option<(u64, Option<RemotePanic>)>,
+    ended: bool,
+}
+
+// never pinned structurally
+impl<T> Unpin for RemoteStream<T> {}
+
+/// a remote stream produced at the producer place, sinking into the consumer here
+pub fn channel<T: RemoteSend>(producer: Place) -> (StreamSink<T>, RemoteStream<T>) {
+    let id = (here(), NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed));
+    let (tx, rx) = mpsc::unbounded_channel();
+    CONSUMERS.lock().insert(id.1, tx);
+    let sink = StreamSink {
+        id,
+        window: STREAM_WINDOW,
+        _item: PhantomData,
+    };
+    let stream = RemoteStream {
+        id,
+        producer,
+        rx,
+        buffer: VecDeque::new(),
+        consumed: 0,
+        next_batch: 0,
+        pending: FxHashMap::default(),
+        end: None,
+        ended: false,
+    };
+    (sink, stream)
+}
+
+enum Outcome {
+    Ended,
+    Panicked(Box<dyn std::any::Any + Send + 'static>),
+    Cancelled,
+}
+
+impl<T: RemoteSend> StreamSink<T> {
+    /// Send the items of the stream to the consumer, until the stream ends or the consumer is
+    /// dropped. A panic of the stream is sent to the consumer and then resumed.
+    pub async fn forward<S>(self, stream: S)
+    where
+        S: Stream<Item = T> + Send,
+    {
+        let flow = flow(self.id);
+        flow.apply(Control::Credit(self.window));
+        let mut stream = Box::pin(AssertUnwindSafe(stream).catch_unwind());
+        let mut batches = 0;
+        let outcome = loop {
+            let credits = match flow.acquire(MAX_BATCH).await {
+                Some(credits) => credits,
+                None => break Outcome::Cancelled,
+            };
+            // the first item of a batch is awaited, the others only taken while ready
+            let mut batch = vec![];
+            let mut end = match stream.next().await {
+                Some(Ok(item)) => {
+                    batch.push(item);
+                    None
+                }
+                Some(Err(payload)) => Some(Outcome::Panicked(payload)),
+                None => Some(Outcome::Ended),
+            };
+            while end.is_none() && batch.len() < credits {
+                match stream.next().now_or_never() {
+                    Some(Some(Ok(item))) => batch.push(item),
+                    Some(Some(Err(payload))) => end = Some(Outcome::Panicked(payload)),
+                    Some(None) => end = Some(Outcome::Ended),
+                    None => break,
+                }
+            }
+            if credits > batch.len() {
+                flow.apply(Control::Credit(credits - batch.len()));
+            }
+            if !batch.is_empty() {
+                let mut bytes = vec![];
+                serialize_into(&mut bytes, &batch).expect("Failed to serialize stream items");
+                send_message(self.id, Message::Items(batches, bytes));
+                batches += 1;
+            }
+            if let Some(end) = end {
+                break end;
+            }
+        };
+        PRODUCERS.lock().remove(&self.id);
+        match outcome {
+            Outcome::Ended => send_message(self.id, Message::End(batches, None)),
+            Outcome::Panicked(payload) => {
+                let payload = cast_panic_payload(payload);
+                let message = String::from(payload.message());
+                send_message(
+                    self.id,
+                    Message::End(batches, Some(RemotePanic::new(payload, vec![]))),
+                );
+                std::panic::resume_unwind(Box::new(message));
+            }
+            Outcome::Cancelled => (),
+        }
+    }
+}
+
+impl<T: RemoteSend> RemoteStream<T> {
+    pub fn producer(&self) -> Place {
+        self.producer
+    }
+
+    fn consume(&mut self) {
+        self.consumed += 1;
+        if self.consumed >= STREAM_WINDOW / 2 {
+            send_control(self.id, self.producer, Control::Credit(self.consumed));
+            self.consumed = 0;
+        }
+    }
+}
+
+impl<T: RemoteSend> Stream for RemoteStream<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.buffer.pop_front() {
+                this.consume();
+                return Poll::Ready(Some(item));
+            }
+            if this.ended {
+                return Poll::Ready(None);
+            }
+            if let Some(bytes) = this.pending.remove(&this.next_batch) {
+                let batch: Vec<T> =
+                    deserialize_from(&bytes[..]).expect("Failed to deserialize stream items");
+                this.buffer.extend(batch);
+                this.next_batch += 1;
+                continue;
+            }
+            // all batches before the end have arrived
+            if matches!(this.end, Some((batches, _)) if batches == this.next_batch) {
+                this.ended = true;
+                if let Some((_, Some(e))) = this.end.take() {
+                    panic!("{}", e); // re-panic at the consumer
+                }
+                continue;
+            }
+            match futures::ready!(this.rx.poll_recv(cx)) {
+                Some(Message::Items(seq, bytes)) => {
+                    this.pending.insert(seq, bytes);
+                }
+                Some(Message::End(batches, panic)) => this.end = Some((batches, panic)),
+                None => this.ended = true,
+            }
+        }
+    }
+}
+
+impl<T> Drop for RemoteStream<T> {
+    fn drop(&mut self) {
+        CONSUMERS.lock().remove(&self.id.1);
+        if !self.ended {
+            send_control(self.id, self.producer, Control::Cancel);
+        }
+    }
+}
+
+const MESSAGE_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "stream_message_handler");
+const CONTROL_FN_ID: FunctionLabel =
+    runtime_meta::function_id(module_path!(), "stream_control_handler");
+
+fn stream_message_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let id: u64 = e.arg();
+    let message: Message = e.arg();
+    receive(id, message);
+    futures::future::ready(()).boxed()
+}
+
+fn stream_control_handler(item: TaskItem) -> BoxFuture<'static, ()> {
+    let mut e = TaskItemExtracter::new(item);
+    let id: StreamId = e.arg();
+    let control: Control = e.arg();
+    flow(id).apply(control);
+    futures::future::ready(()).boxed()
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        MESSAGE_FN_ID,
+        stream_message_handler,
+        String::from("stream_message_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+inventory::submit! {
+    FunctionMetaData::new(
+        CONTROL_FN_ID,
+        stream_control_handler,
+        String::from("stream_control_handler"),
+        String::from(file!()),
+        line!(),
+        String::from(module_path!())
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::global_id::test::TestGuardForStatic;
+    use crate::global_id::test::TEST_HERE;
+    use futures::executor;
+
+    #[test]
+    fn test_local_stream() {
+        let _a = TestGuardForStatic::new();
+        let (sink, stream) = channel::<usize>(TEST_HERE);
+        assert_eq!(stream.producer(), TEST_HERE);
+        // more items than a window, so credits must come back
+        let n = STREAM_WINDOW * 3 + 7;
+        let producer = sink.forward(futures::stream::iter(0..n));
+        let (_, items) =
+            executor::block_on(futures::future::join(producer, stream.collect::<Vec<_>>()));
+        assert_eq!(items, (0..n).collect::<Vec<_>>());
+        assert!(CONSUMERS.lock().is_empty());
+        assert!(PRODUCERS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_reordered_messages() {
+        let _a = TestGuardForStatic::new();
+        let (_sink, stream) = channel::<usize>(TEST_HERE);
+        let batch = |items: &[usize]| {
+            let mut bytes = vec![];
+            serialize_into(&mut bytes, &items.to_vec()).unwrap();
+            bytes
+        };
+        // the end and the batches overtake each other
+        receive(stream.id.1, Message::End(3, None));
+        receive(stream.id.1, Message::Items(2, batch(&[5])));
+        receive(stream.id.1, Message::Items(0, batch(&[0, 1, 2])));
+        receive(stream.id.1, Message::Items(1, batch(&[3, 4])));
+        let items = executor::block_on(stream.collect::<Vec<_>>());
+        assert_eq!(items, (0..6).collect::<Vec<_>>());
+        assert!(CONSUMERS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let _a = TestGuardForStatic::new();
+        let (sink, stream) = channel::<usize>(TEST_HERE);
+        // never ends unless cancelled
+        let producer = sink.forward(futures::stream::iter(0..));
+        let consumer = async move {
+            let mut stream = stream;
+            let mut items = vec![];
+            while items.len() < 10 {
+                items.push(stream.next().await.unwrap());
+            }
+            items
+        };
+        let (_, items) = executor::block_on(futures::future::join(producer, consumer));
+        assert_eq!(items, (0..10).collect::<Vec<_>>());
+        assert!(CONSUMERS.lock().is_empty());
+        assert!(PRODUCERS.lock().is_empty());
+    }
+
+    #[test]
+    fn test_panic() {
+        let _a = TestGuardForStatic::new();
+        let (sink, mut stream) = channel::<usize>(TEST_HERE);
+        let failing = futures::stream::iter(0..3).map(|i| match i {
+            2 => panic!("stream failed"),
+            i => i,
+        });
+        let producer = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            executor::block_on(sink.forward(failing))
+        }));
+        assert!(producer.is_err());
+        assert_eq!(executor::block_on(stream.next()), Some(0));
+        assert_eq!(executor::block_on(stream.next()), Some(1));
+        let consumer =
+            std::panic::catch_unwind(AssertUnwindSafe(|| executor::block_on(stream.next())));
+        let message = consumer.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.contains("stream failed"));
+        drop(stream);
+        assert!(CONSUMERS.lock().is_empty());
+        assert!(PRODUCERS.lock().is_empty());
+    }
+}
@@ -568,3 +568,22 @@ gex_Event_t gex_Coll_BroadcastNB_Wrap(gex_TM_t tm, gex_Rank_t root,
 int gasnet_AMPoll_Wrap (){
     return gasnet_AMPoll(); 
 }
+
+// atomics
+extern gex_DT_t gex_dt_u64() { return GEX_DT_U64; }
+extern gex_OP_t gex_op_fadd() { return GEX_OP_FADD; }
+extern gex_OP_t gex_op_fcas() { return GEX_OP_FCAS; }
+extern gex_OP_t gex_op_swap() { return GEX_OP_SWAP; }
+extern gex_OP_t gex_op_get() { return GEX_OP_GET; }
+void gex_AD_Create_Wrap(gex_AD_t *ad_p, gex_TM_t tm, gex_DT_t dt, gex_OP_t ops,
+                        gex_Flags_t flags) {
+  gex_AD_Create(ad_p, tm, dt, ops, flags);
+}
+void gex_AD_Destroy_Wrap(gex_AD_t ad) { gex_AD_Destroy(ad); }
+gex_Event_t gex_AD_OpNB_U64_Wrap(gex_AD_t ad, uint64_t *result_p,
+                                 gex_Rank_t tgt_rank, void *tgt_addr,
+                                 gex_OP_t opcode, uint64_t operand1,
+                                 uint64_t operand2, gex_Flags_t flags) {
+  return gex_AD_OpNB_U64(ad, result_p, tgt_rank, tgt_addr, opcode, operand1,
+                         operand2, flags);
+}
@@ -1,6 +1,7 @@
 #define GASNET_SEQ
 #include <gasnetex.h>
 #include <gasnet_coll.h>
+#include <gasnet_ratomic.h>
 gex_Flags_t gex_flag_uses_gasnet1();
 gex_Rank_t gex_rank_invalid();
 gex_Event_t gex_event_invalid();
@@ -304,3 +305,17 @@ gex_Event_t gex_Coll_BroadcastNB_Wrap(gex_TM_t tm, gex_Rank_t root, void *dst,
                                       const void *src, size_t nbytes,
                                       gex_Flags_t flags);
 int gasnet_AMPoll_Wrap ();
+
+// atomics
+gex_DT_t gex_dt_u64();
+gex_OP_t gex_op_fadd();
+gex_OP_t gex_op_fcas();
+gex_OP_t gex_op_swap();
+gex_OP_t gex_op_get();
+void gex_AD_Create_Wrap(gex_AD_t *ad_p, gex_TM_t tm, gex_DT_t dt, gex_OP_t ops,
+                        gex_Flags_t flags);
+void gex_AD_Destroy_Wrap(gex_AD_t ad);
+gex_Event_t gex_AD_OpNB_U64_Wrap(gex_AD_t ad, uint64_t *result_p,
+                                 gex_Rank_t tgt_rank, void *tgt_addr,
+                                 gex_OP_t opcode, uint64_t operand1,
+                                 uint64_t operand2, gex_Flags_t flags);
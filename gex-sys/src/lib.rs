@@ -601,6 +601,68 @@ pub unsafe fn gex_rma_putblocking(
     gex_RMA_PutBlocking_Wrap(tm, rank, dest_addr, source_addr, nbytes, 0);
 }
 
+/// the source can be reused once the returned event is done
+pub unsafe fn gex_rma_put_nb(
+    tm: gex_TM_t,
+    rank: gex_Rank_t,
+    dest_addr: *mut ::std::os::raw::c_void,
+    source_addr: *const ::std::os::raw::c_void,
+    nbytes: size_t,
+) -> gex_Event_t {
+    gex_RMA_PutNB_Wrap(tm, rank, dest_addr, source_addr, nbytes, gex_event_defer(), 0)
+}
+
+pub unsafe fn gex_rma_get_nb(
+    tm: gex_TM_t,
+    dest_addr: *mut ::std::os::raw::c_void,
+    rank: gex_Rank_t,
+    source_addr: *mut ::std::os::raw::c_void,
+    nbytes: size_t,
+) -> gex_Event_t {
+    gex_RMA_GetNB_Wrap(tm, dest_addr, rank, source_addr, nbytes, 0)
+}
+
+/// operations of a gex atomic
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GexAtomicOp {
+    FetchAdd(u64),
+    CompareSwap(u64, u64),
+    Swap(u64),
+    Load,
+}
+
+/// a collective, the domain supports all of GexAtomicOp on u64
+pub fn gex_ad_create_u64(tm: gex_TM_t) -> gex_AD_t {
+    let mut ad = uninit::<gex_AD_t>();
+    unsafe {
+        let ops = gex_op_fadd() | gex_op_fcas() | gex_op_swap() | gex_op_get();
+        gex_AD_Create_Wrap(ad.as_mut_ptr(), tm, gex_dt_u64(), ops, 0);
+        ad.assume_init()
+    }
+}
+
+/// a collective
+pub fn gex_ad_destroy(ad: gex_AD_t) {
+    unsafe { gex_AD_Destroy_Wrap(ad) }
+}
+
+/// the previous value is written to result once the returned event is done
+pub unsafe fn gex_ad_op_nb_u64(
+    ad: gex_AD_t,
+    result: *mut u64,
+    rank: gex_Rank_t,
+    addr: *mut ::std::os::raw::c_void,
+    op: GexAtomicOp,
+) -> gex_Event_t {
+    let (opcode, operand1, operand2) = match op {
+        GexAtomicOp::FetchAdd(v) => (gex_op_fadd(), v, 0),
+        GexAtomicOp::CompareSwap(current, new) => (gex_op_fcas(), current, new),
+        GexAtomicOp::Swap(v) => (gex_op_swap(), v, 0),
+        GexAtomicOp::Load => (gex_op_get(), 0, 0),
+    };
+    gex_AD_OpNB_U64_Wrap(ad, result, rank, addr, opcode, operand1, operand2, 0)
+}
+
 pub fn gasnet_ampoll() {
     unsafe {
         gasnet_AMPoll_Wrap();